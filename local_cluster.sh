@@ -0,0 +1,52 @@
+#!/bin/bash
+# Runs a two instance cluster on localhost, opens a tunnel on the second instance,
+# and checks a request to the first one gets proxied through to it.
+#
+# Uses ports 8000 (the local service), 8080/8081, 5000/5001 and 6000/6001.
+set -e
+
+cargo build --bin neutun_server --bin neutun
+BIN="$PWD/target/debug"
+DIR=$(mktemp -d)
+PIDS=()
+trap 'kill "${PIDS[@]}" 2>/dev/null; rm -rf "$DIR"' EXIT
+
+mkdir -p "$DIR/www" "$DIR/home/.neutun"
+echo "hello from the tunnel" > "$DIR/www/index.txt"
+(cd "$DIR/www" && exec python3 -m http.server 8000 >/dev/null 2>&1) &
+PIDS+=($!)
+
+export ALLOWED_HOSTS=localhost MASTER_API_KEY=cluster-test
+export MASTER_SIG_KEY=$(printf '5%.0s' $(seq 64))
+export CLUSTER_PEERS=127.0.0.1:6000,127.0.0.1:6001
+PORT=8080 CTRL_PORT=5000 NET_PORT=6000 "$BIN/neutun_server" >"$DIR/a.log" 2>&1 &
+PIDS+=($!)
+PORT=8081 CTRL_PORT=5001 NET_PORT=6001 "$BIN/neutun_server" >"$DIR/b.log" 2>&1 &
+PIDS+=($!)
+sleep 1
+
+# the client connects to the second instance
+cat > "$DIR/home/.neutun/config.json" <<EOF
+{"host":"localhost","ctrl_host":"localhost","ctrl_port":5001,"tls":false,"port":8000,"key":"cluster-test"}
+EOF
+HOME="$DIR/home" "$BIN/neutun" -p 8000 -s cluster >"$DIR/client.log" 2>&1 &
+PIDS+=($!)
+sleep 2
+
+status=0
+for port in 8081 8080; do
+    body=$(curl -s -m 5 -H "Host: cluster.localhost" "http://127.0.0.1:$port/index.txt" || true)
+    if [ "$body" = "hello from the tunnel" ]; then
+        echo "ok: instance on $port serves the tunnel"
+    else
+        echo "FAILED: instance on $port answered: $body"
+        status=1
+    fi
+done
+
+if [ $status -ne 0 ]; then
+    echo "--- instance a"; cat "$DIR/a.log"
+    echo "--- instance b"; cat "$DIR/b.log"
+    echo "--- client"; cat "$DIR/client.log"
+fi
+exit $status
@@ -37,7 +37,14 @@ impl CliInterface {
         }
     }
 
-    pub fn did_connect(&self, sub_domain: &str, full_hostname: &str, taken_domains: &str) {
+    pub fn did_connect(
+        &self,
+        sub_domain: &str,
+        full_hostname: &str,
+        taken_domains: &str,
+        reserved: bool,
+        http2: bool,
+    ) {
         self.spinner.finish_with_message(format!(
             "{}",
             "Success! Remote tunnel is now open.\n".green()
@@ -91,6 +98,28 @@ impl CliInterface {
         if let Some(notice) = self.get_sub_domain_notice(sub_domain) {
             eprintln!("\n{}: {}\n", ">>> Notice".yellow(), notice);
         }
+
+        if reserved {
+            eprintln!(
+                "\n{}: {} is reserved for your key, even while you're disconnected.\n",
+                ">>> Reserved".green(),
+                full_hostname
+            );
+        } else if self.config.reserve {
+            eprintln!(
+                "\n{}: {}\n",
+                ">>> Notice".yellow(),
+                "The server did not reserve this sub-domain for your key.".yellow()
+            );
+        }
+
+        if self.config.http2 && !http2 {
+            eprintln!(
+                "\n{}: {}\n",
+                ">>> Notice".yellow(),
+                "The server can't forward HTTP/2, requests will reach you over HTTP/1.1.".yellow()
+            );
+        }
     }
 }
 
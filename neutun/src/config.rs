@@ -1,7 +1,7 @@
 use std::net::{SocketAddr, ToSocketAddrs};
 
 use super::*;
-use clap::{Parser, Subcommand};
+use clap::{Parser, Subcommand, ValueEnum};
 
 pub(crate) const DEFAULT_HOST: &str = "neutun.dev";
 #[allow(dead_code)]
@@ -48,6 +48,10 @@ pub struct Opts {
     #[arg(short = 't', long = "use-tls")]
     pub use_tls: bool,
 
+    /// Pass TLS connections through to the local service still encrypted, routed by their SNI host
+    #[arg(long = "tls-passthrough", conflicts_with_all = ["use_tls", "tcp"], group = "stream_tunnel")]
+    pub tls_passthrough: bool,
+
     /// Sets the port to forward incoming tunnel traffic to on the target host
     #[arg(short = 'p', long = "port")]
     pub port: Option<u16>,
@@ -60,6 +64,82 @@ pub struct Opts {
     #[arg(short = 'w', long = "wildcard")]
     pub wildcard: bool,
 
+    /// Tunnel raw TCP connections (i.e. postgres, ssh) on a server allocated public port
+    #[arg(long = "tcp", conflicts_with = "wildcard", group = "stream_tunnel")]
+    pub tcp: bool,
+
+    /// Ask the server for a specific public port for a TCP tunnel
+    #[arg(long = "remote-port", requires = "tcp")]
+    pub remote_port: Option<u16>,
+
+    /// Keep the sub-domain reserved for your key after you disconnect
+    #[arg(long = "reserve")]
+    pub reserve: bool,
+
+    /// Forward requests to the local service over HTTP/2 (i.e. gRPC), negotiated with ALPN when using TLS
+    #[arg(long = "http2", conflicts_with_all = ["tls_passthrough", "tcp"])]
+    pub http2: bool,
+
+    /// Don't let the server add X-Forwarded-For, X-Forwarded-Proto, X-Forwarded-Host and Forwarded headers
+    #[arg(long = "no-forwarded-headers")]
+    pub no_forwarded_headers: bool,
+
+    /// Start every connection to the local service with a PROXY protocol header naming the remote client (TCP and TLS passthrough tunnels)
+    #[arg(long = "proxy-protocol", value_name = "VERSION", requires = "stream_tunnel")]
+    pub proxy_protocol: Option<ProxyProtocolVersion>,
+
+    /// Require HTTP basic auth with these credentials for requests to reach you (repeatable)
+    #[arg(long = "basic-auth", value_name = "USER:PASSWORD", conflicts_with = "stream_tunnel")]
+    pub basic_auth: Vec<String>,
+
+    /// Require an `Authorization: Bearer` token for requests to reach you (repeatable)
+    #[arg(long = "bearer-token", value_name = "TOKEN", conflicts_with = "stream_tunnel")]
+    pub bearer_tokens: Vec<String>,
+
+    /// Only let these IP addresses or CIDR ranges (i.e. 203.0.113.0/24) connect (repeatable)
+    #[arg(long = "allow-ip", value_name = "IP")]
+    pub allow_ips: Vec<String>,
+
+    /// Only let requests with these HTTP methods (i.e. GET) through (repeatable)
+    #[arg(long = "allow-method", value_name = "METHOD", conflicts_with = "stream_tunnel")]
+    pub allow_methods: Vec<String>,
+
+    /// Answer requests beyond this many a second with 429 Too Many Requests
+    #[arg(long = "max-requests-per-sec", value_name = "N", value_parser = clap::value_parser!(u32).range(1..), conflicts_with = "stream_tunnel")]
+    pub max_requests: Option<u32>,
+
+    /// Turn away new connections beyond this many a second
+    #[arg(long = "max-connections-per-sec", value_name = "N", value_parser = clap::value_parser!(u32).range(1..))]
+    pub max_connections: Option<u32>,
+
+    /// Like --max-requests-per-sec, for each remote IP address on its own
+    #[arg(long = "max-ip-requests-per-sec", value_name = "N", value_parser = clap::value_parser!(u32).range(1..), conflicts_with = "stream_tunnel")]
+    pub max_ip_requests: Option<u32>,
+
+    /// Like --max-connections-per-sec, for each remote IP address on its own
+    #[arg(long = "max-ip-connections-per-sec", value_name = "N", value_parser = clap::value_parser!(u32).range(1..))]
+    pub max_ip_connections: Option<u32>,
+
+    /// Also route a host of your own (i.e. staging.example.com) to the tunnel, once its DNS proves you own it
+    #[arg(long = "custom-domain", value_name = "HOST", conflicts_with_all = ["wildcard", "tcp"])]
+    pub custom_domain: Option<String>,
+
+    /// Share the sub-domain with other tunnels of the same key, taking turns with new requests and connections
+    #[arg(long = "group", conflicts_with_all = ["wildcard", "tcp"])]
+    pub group: bool,
+
+    /// How a --group spreads new requests and connections over its tunnels
+    #[arg(long = "balance", value_name = "STRATEGY", requires = "group")]
+    pub balance: Option<BalanceStrategy>,
+
+    /// Wait as a hot standby while another tunnel of the same key has the sub-domain, and take over once it disconnects
+    #[arg(long = "standby", conflicts_with_all = ["wildcard", "tcp", "group"])]
+    pub standby: bool,
+
+    /// Only take the requests under this path (i.e. /api), sharing the sub-domain with the tunnels of the same key on other paths
+    #[arg(long = "path-prefix", value_name = "PATH", conflicts_with_all = ["wildcard", "stream_tunnel"])]
+    pub path_prefix: Option<String>,
+
     /// Run as a background daemon
     #[arg(short = 'D', long = "daemon")]
     pub daemon: bool,
@@ -69,6 +149,29 @@ pub struct Opts {
     pub verbose: bool,
 }
 
+/// Which PROXY protocol header to send to the local service
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum ProxyProtocolVersion {
+    V1,
+    V2,
+}
+
+/// How a load balanced group picks the tunnel for a new stream
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum BalanceStrategy {
+    RoundRobin,
+    LeastStreams,
+}
+
+impl From<BalanceStrategy> for Balance {
+    fn from(strategy: BalanceStrategy) -> Self {
+        match strategy {
+            BalanceStrategy::RoundRobin => Balance::RoundRobin,
+            BalanceStrategy::LeastStreams => Balance::LeastStreams,
+        }
+    }
+}
+
 #[derive(Debug, Subcommand)]
 pub enum SubCommand {
     /// Manage configuration settings
@@ -195,6 +298,18 @@ pub struct Config {
     pub dashboard_port: u16,
     pub verbose: bool,
     pub wildcard: bool,
+    pub kind: TunnelKind,
+    pub remote_port: Option<u16>,
+    pub reserve: bool,
+    pub http2: bool,
+    pub forwarded_headers: bool,
+    pub proxy_protocol: Option<ProxyProtocolVersion>,
+    pub policy: EdgePolicy,
+    pub rate_limits: RateLimits,
+    pub custom_domain: Option<String>,
+    pub group: Option<Balance>,
+    pub standby: bool,
+    pub path_prefix: Option<String>,
 }
 
 impl Config {
@@ -285,6 +400,16 @@ impl Config {
 
         let use_tls = opts.use_tls || session.map(|s| s.use_tls).unwrap_or(false);
         let wildcard = opts.wildcard || session.map(|s| s.wildcard).unwrap_or(false);
+        let kind = if opts.tcp {
+            TunnelKind::Tcp
+        } else if opts.tls_passthrough {
+            TunnelKind::Tls
+        } else {
+            session.map(|s| s.kind()).unwrap_or_default()
+        };
+        let remote_port = opts
+            .remote_port
+            .or_else(|| session.and_then(|s| s.remote_port));
 
         let dashboard_port = opts
             .dashboard_port
@@ -307,23 +432,57 @@ impl Config {
             control_tls_off: tls_off,
             first_run: true,
             wildcard,
+            kind,
+            remote_port,
+            reserve: opts.reserve,
+            http2: opts.http2,
+            forwarded_headers: !opts.no_forwarded_headers,
+            proxy_protocol: opts.proxy_protocol,
+            policy: EdgePolicy {
+                basic_auth: opts.basic_auth.clone(),
+                bearer_tokens: opts.bearer_tokens.clone(),
+                allow_ips: opts.allow_ips.clone(),
+                allow_methods: opts.allow_methods.clone(),
+            },
+            rate_limits: RateLimits {
+                requests: opts.max_requests,
+                connections: opts.max_connections,
+                requests_per_ip: opts.max_ip_requests,
+                connections_per_ip: opts.max_ip_connections,
+            },
+            custom_domain: opts.custom_domain.clone(),
+            group: opts
+                .group
+                .then(|| opts.balance.map(Balance::from).unwrap_or_default()),
+            standby: opts.standby,
+            path_prefix: opts
+                .path_prefix
+                .as_deref()
+                .map(|path| format!("/{}", path.trim_matches('/')))
+                .filter(|path| path != "/"),
         })
     }
 
     pub fn activation_url(&self, full_hostname: &str) -> String {
         format!(
             "{}://{}",
-            if self.control_tls_off {
-                "http"
-            } else {
-                "https"
+            match self.kind {
+                TunnelKind::Tcp => "tcp",
+                TunnelKind::Tls => "https",
+                TunnelKind::Http if self.control_tls_off => "http",
+                TunnelKind::Http => "https",
             },
             full_hostname
         )
     }
 
     pub fn forward_url(&self) -> String {
-        let scheme = if self.use_tls { "https" } else { "http" };
+        let scheme = match self.kind {
+            TunnelKind::Tcp => "tcp",
+            TunnelKind::Tls => "tls",
+            TunnelKind::Http if self.use_tls => "https",
+            TunnelKind::Http => "http",
+        };
         format!("{}://{}:{}", &scheme, &self.local_host, &self.local_port)
     }
 
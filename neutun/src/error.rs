@@ -17,6 +17,9 @@ pub enum Error {
     #[error("Cannot use this sub-domain, it is already taken.")]
     SubDomainInUse,
 
+    #[error("The server only supports protocol versions {0} to {1}, please upgrade neutun.")]
+    IncompatibleVersion(u32, u32),
+
     #[error("{0}")]
     ServerError(String),
 
@@ -28,4 +31,22 @@ pub enum Error {
 
     #[error("The server timed out sending us something.")]
     Timeout,
+
+    #[error("The server can't enforce --basic-auth, --bearer-token, --allow-ip or --allow-method, please upgrade it or connect without them.")]
+    EdgePolicyUnsupported,
+
+    #[error("The server can't enforce the --max-*-per-sec rate limits, please upgrade it or connect without them.")]
+    RateLimitsUnsupported,
+
+    #[error("The server can't route a --custom-domain, please upgrade it or connect without one.")]
+    CustomDomainsUnsupported,
+
+    #[error("The server can't share a sub-domain with a --group, please upgrade it or connect without one.")]
+    GroupsUnsupported,
+
+    #[error("The server can't keep a --standby tunnel, please upgrade it or connect without --standby.")]
+    StandbyUnsupported,
+
+    #[error("The server can't route a --path-prefix, please upgrade it or connect without one.")]
+    PathPrefixesUnsupported,
 }
@@ -1,10 +1,11 @@
 use colored::Colorize;
+use neutun_lib::{Notice, StreamMetadata};
 
 pub fn connect_failed() {
     eprintln!("{}", "CONNECTION REFUSED".red())
 }
 
-pub fn log(request: &httparse::Request, response: &httparse::Response) {
+pub fn log(request: &httparse::Request, response: &httparse::Response, metadata: &StreamMetadata) {
     let out = match response.code {
         Some(code @ 200..=299) => format!("{}", code).green(),
         Some(code) => format!("{}", code).red(),
@@ -16,5 +17,66 @@ pub fn log(request: &httparse::Request, response: &httparse::Response) {
 
     eprint!("{}", out);
 
-    eprintln!("\t\t{}\t{}", method.to_uppercase().yellow(), path.blue());
+    eprintln!(
+        "\t\t{}\t{}\t{}",
+        method.to_uppercase().yellow(),
+        path.blue(),
+        describe(metadata).dimmed()
+    );
+}
+
+/// A new tcp or tls passthrough connection
+pub fn log_connection(metadata: &StreamMetadata) {
+    eprintln!("{}\t{}", "CONNECTION".green(), describe(metadata).dimmed());
+}
+
+/// The server is holding back our traffic, or about to send it
+pub fn notice(notice: &Notice) {
+    match notice {
+        Notice::Throttled { bytes_per_sec } => eprintln!(
+            "{}\t{}",
+            "THROTTLED".yellow(),
+            format!("traffic is capped at {} bytes/s for your key", bytes_per_sec).dimmed()
+        ),
+        Notice::QuotaExceeded { quota_bytes } => eprintln!(
+            "{}\t{}",
+            "QUOTA EXCEEDED".red(),
+            format!(
+                "your key used up its {} byte transfer quota for the month, traffic is refused until the next one",
+                quota_bytes
+            )
+            .dimmed()
+        ),
+        Notice::StandingBy => eprintln!(
+            "{}\t{}",
+            "STANDBY".yellow(),
+            "another tunnel has this host, we'll take over once it disconnects".dimmed()
+        ),
+        Notice::Promoted => eprintln!(
+            "{}\t{}",
+            "PROMOTED".green(),
+            "the other tunnel disconnected, traffic comes to us now".dimmed()
+        ),
+        Notice::Unknown => {}
+    }
+}
+
+/// Where a stream came from, i.e. `203.0.113.7:51234 -> app.neutun.dev:443 (tls)`.
+/// Empty for servers that don't send any metadata.
+fn describe(metadata: &StreamMetadata) -> String {
+    let mut out = String::new();
+    if let Some(peer_addr) = metadata.peer_addr {
+        out.push_str(&format!("{} -> ", peer_addr));
+    }
+    if !metadata.host.is_empty() {
+        out.push_str(&metadata.host);
+        // http hosts may already name the port
+        if let Some(port) = metadata.public_port.filter(|_| !metadata.host.contains(':')) {
+            out.push_str(&format!(":{}", port));
+        }
+    }
+    if metadata.tls {
+        out.push_str(" (tls)");
+    }
+    out
 }
@@ -25,6 +25,7 @@ pub struct Request {
     started: chrono::NaiveDateTime,
     completed: chrono::NaiveDateTime,
     entire_request: Vec<u8>,
+    metadata: StreamMetadata,
 }
 
 impl Request {
@@ -36,6 +37,14 @@ impl Request {
             format!("{}s", duration.num_seconds())
         }
     }
+
+    /// The remote client the request came from, if the server told us
+    pub fn remote_addr(&self) -> String {
+        self.metadata
+            .peer_addr
+            .map(|addr| addr.ip().to_string())
+            .unwrap_or_default()
+    }
 }
 
 lazy_static::lazy_static! {
@@ -97,12 +106,23 @@ pub struct IntrospectChannels {
     pub response: UnboundedSender<Vec<u8>>,
 }
 
-pub fn introspect_stream() -> IntrospectChannels {
+pub fn introspect_stream(metadata: StreamMetadata) -> IntrospectChannels {
     let id = Uuid::new_v4();
     let (request_tx, request_rx) = unbounded::<Vec<u8>>();
     let (response_tx, response_rx) = unbounded::<Vec<u8>>();
 
-    tokio::spawn(async move { collect_stream(id, request_rx, response_rx).await });
+    tokio::spawn(async move { collect_stream(id, metadata, request_rx, response_rx).await });
+
+    IntrospectChannels {
+        request: request_tx,
+        response: response_tx,
+    }
+}
+
+/// Channels that go nowhere, for streams we don't want to record
+pub fn discard_stream() -> IntrospectChannels {
+    let (request_tx, _) = unbounded::<Vec<u8>>();
+    let (response_tx, _) = unbounded::<Vec<u8>>();
 
     IntrospectChannels {
         request: request_tx,
@@ -112,6 +132,7 @@ pub fn introspect_stream() -> IntrospectChannels {
 
 async fn collect_stream(
     id: Uuid,
+    metadata: StreamMetadata,
     mut request_rx: UnboundedReceiver<Vec<u8>>,
     mut response_rx: UnboundedReceiver<Vec<u8>>,
 ) {
@@ -150,7 +171,7 @@ async fn collect_stream(
     };
     let response_data = collected_response.as_slice()[parts_len..].to_vec();
 
-    console_log::log(&request, &response);
+    console_log::log(&request, &response, &metadata);
 
     let stored_request = Request {
         id: id.to_string(),
@@ -183,6 +204,7 @@ async fn collect_stream(
         completed: chrono::Local::now().naive_local(),
         is_replay: false,
         entire_request: collected_request,
+        metadata,
     };
 
     REQUESTS
@@ -280,7 +302,7 @@ async fn replay_request(
         None => return Err(warp::reject::not_found()),
     };
 
-    let (tx, rx) = unbounded::<ControlPacket>();
+    let (tx, rx) = futures::channel::mpsc::channel::<ControlPacket>(1);
     tokio::spawn(async move {
         // keep the rx alive
         let mut rx = rx;
@@ -289,7 +311,15 @@ async fn replay_request(
         }
     });
 
-    let tx = local::setup_new_stream(config, tx, StreamId::generate()).await;
+    let tx = local::setup_new_stream(
+        config,
+        tx,
+        StreamId::generate(),
+        request.metadata.clone(),
+        false,
+        false,
+    )
+    .await;
 
     // send the data to the stream
     if let Some(mut tx) = tx {
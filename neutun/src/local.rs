@@ -1,5 +1,5 @@
 use super::*;
-use futures::channel::mpsc::{unbounded, UnboundedReceiver, UnboundedSender};
+use futures::channel::mpsc::{channel, Receiver, Sender, UnboundedSender};
 use futures::{SinkExt, StreamExt};
 
 use tokio::io::{split, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
@@ -19,12 +19,15 @@ impl<T: AsyncRead + AsyncWrite + Unpin + Send> AnyTcpStream for T {}
 /// Establish a new local stream and start processing messages to it
 pub async fn setup_new_stream(
     config: Config,
-    mut tunnel_tx: UnboundedSender<ControlPacket>,
+    mut tunnel_tx: Sender<ControlPacket>,
     stream_id: StreamId,
-) -> Option<UnboundedSender<StreamMessage>> {
+    metadata: StreamMetadata,
+    flow_control: bool,
+    request_streams: bool,
+) -> Option<Sender<StreamMessage>> {
     info!("setting up local stream: {}", &stream_id.to_string());
 
-    let local_tcp = match TcpStream::connect(config.local_addr).await {
+    let mut local_tcp = match TcpStream::connect(config.local_addr).await {
         Ok(s) => s,
         Err(e) => {
             error!("failed to connect to local service: {}", e);
@@ -34,6 +37,24 @@ pub async fn setup_new_stream(
         }
     };
 
+    // tell the local service who the connection is really from, before anything else
+    if let Some(version) = config.proxy_protocol {
+        let header = ProxyHeader {
+            addresses: metadata.peer_addr.zip(local_tcp.peer_addr().ok()),
+            tls: false,
+        };
+        let header = match version {
+            ProxyProtocolVersion::V1 => header.to_v1(),
+            ProxyProtocolVersion::V2 => header.to_v2(),
+        };
+
+        if let Err(e) = local_tcp.write_all(&header).await {
+            error!("failed to send proxy protocol header to local service: {}", e);
+            let _ = tunnel_tx.send(ControlPacket::Refused(stream_id)).await;
+            return None;
+        }
+    }
+
     let local_tcp: Box<dyn AnyTcpStream> = if config.use_tls {
         let dnsname = config.local_host;
         let root_store = tokio_rustls::rustls::RootCertStore::from_iter(
@@ -41,11 +62,14 @@ pub async fn setup_new_stream(
                 .iter()
                 .cloned(),
         );
-        let config = ClientConfig::builder()
+        let mut tls_config = ClientConfig::builder()
             .with_root_certificates(root_store)
             .with_no_client_auth();
+        if config.http2 {
+            tls_config.alpn_protocols = vec![b"h2".to_vec()];
+        }
 
-        let config = TlsConnector::from(Arc::new(config));
+        let config = TlsConnector::from(Arc::new(tls_config));
 
         let dnsname = match ServerName::try_from(dnsname) {
             Ok(name) => name,
@@ -70,28 +94,59 @@ pub async fn setup_new_stream(
         Box::new(local_tcp)
     };
 
+    // raw tcp, encrypted tls and binary http/2 streams aren't readable http, there's nothing to introspect
     let IntrospectChannels {
         request: introspect_request,
         response: introspect_response,
-    } = introspect_stream();
+    } = if config.kind != TunnelKind::Http {
+        introspect::log_connection(&metadata);
+        introspect::discard_stream()
+    } else if config.http2 {
+        introspect::discard_stream()
+    } else {
+        introspect_stream(metadata)
+    };
 
     let (stream, sink) = split(local_tcp);
 
+    let window = SendWindow::new(flow_control);
+
     // Read local tcp bytes, send them tunnel
     let stream_id_clone = stream_id.clone();
+    let tunnel_tx_clone = tunnel_tx.clone();
+    let window_clone = window.clone();
     tokio::spawn(async move {
-        process_local_tcp(stream, tunnel_tx, stream_id_clone, introspect_response).await;
+        process_local_tcp(
+            stream,
+            tunnel_tx_clone,
+            stream_id_clone,
+            window_clone,
+            request_streams,
+            introspect_response,
+        )
+        .await;
     });
 
     // Forward remote packets to local tcp
-    let (tx, rx) = unbounded();
-    ACTIVE_STREAMS
-        .write()
-        .unwrap()
-        .insert(stream_id.clone(), tx.clone());
+    let (tx, rx) = channel(STREAM_QUEUE_SIZE);
+    ACTIVE_STREAMS.write().unwrap().insert(
+        stream_id.clone(),
+        ActiveStream {
+            tx: tx.clone(),
+            window,
+        },
+    );
 
     tokio::spawn(async move {
-        forward_to_local_tcp(sink, rx, introspect_request).await;
+        forward_to_local_tcp(
+            sink,
+            rx,
+            tunnel_tx,
+            stream_id,
+            ReceiveWindow::new(flow_control),
+            introspect_request,
+        )
+        .await;
     });
 
     Some(tx)
@@ -99,8 +154,10 @@ pub async fn setup_new_stream(
 
 pub async fn process_local_tcp<T>(
     mut stream: ReadHalf<T>,
-    mut tunnel: UnboundedSender<ControlPacket>,
+    mut tunnel: Sender<ControlPacket>,
     stream_id: StreamId,
+    window: SendWindow,
+    request_streams: bool,
     mut introspect: UnboundedSender<Vec<u8>>,
 ) where
     T: AnyTcpStream,
@@ -116,6 +173,10 @@ pub async fn process_local_tcp<T>(
         if n == 0 {
             info!("done reading from client stream");
             ACTIVE_STREAMS.write().unwrap().remove(&stream_id);
+            // let the server finish the public side too
+            if request_streams {
+                let _ = tunnel.send(ControlPacket::End(stream_id)).await;
+            }
             return;
         }
 
@@ -125,6 +186,12 @@ pub async fn process_local_tcp<T>(
             std::str::from_utf8(&data).unwrap_or("<non utf8>")
         );
 
+        // wait until the server is ready for more
+        if !window.reserve(n).await {
+            warn!("stream window closed");
+            return;
+        }
+
         let packet = ControlPacket::Data(stream_id.clone(), data.clone());
         tunnel
             .send(packet)
@@ -137,7 +204,10 @@ pub async fn process_local_tcp<T>(
 
 async fn forward_to_local_tcp<T>(
     mut sink: WriteHalf<T>,
-    mut queue: UnboundedReceiver<StreamMessage>,
+    mut queue: Receiver<StreamMessage>,
+    mut tunnel: Sender<ControlPacket>,
+    stream_id: StreamId,
+    mut window: ReceiveWindow,
     mut introspect: UnboundedSender<Vec<u8>>,
 ) where
     T: AnyTcpStream,
@@ -159,6 +229,13 @@ async fn forward_to_local_tcp<T>(
             .expect("failed to write packet data to local tcp socket");
         debug!("wrote to local service: {:?}", data.len());
 
+        // let the server know it can send more
+        if let Some(credit) = window.consume(data.len()) {
+            let _ = tunnel
+                .send(ControlPacket::WindowUpdate(stream_id.clone(), credit))
+                .await;
+        }
+
         let _ = introspect.send(data).await;
     }
 }
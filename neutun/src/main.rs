@@ -1,4 +1,4 @@
-use futures::channel::mpsc::{unbounded, UnboundedSender};
+use futures::channel::mpsc::{channel, unbounded, Sender, UnboundedSender};
 use futures::{SinkExt, StreamExt};
 
 use tokio::net::TcpStream;
@@ -33,11 +33,23 @@ use futures::future::Either;
 use std::time::Duration;
 use tokio::sync::Mutex;
 
-pub type ActiveStreams = Arc<RwLock<HashMap<StreamId, UnboundedSender<StreamMessage>>>>;
+pub type ActiveStreams = Arc<RwLock<HashMap<StreamId, ActiveStream>>>;
+
+/// How many control packets may queue up on their way to the server
+const TUNNEL_QUEUE_SIZE: usize = 256;
 
 lazy_static::lazy_static! {
     pub static ref ACTIVE_STREAMS:ActiveStreams = Arc::new(RwLock::new(HashMap::new()));
     pub static ref RECONNECT_TOKEN: Arc<Mutex<Option<ReconnectToken>>> = Arc::new(Mutex::new(None));
+    pub static ref TCP_PORT: Arc<Mutex<Option<u16>>> = Arc::new(Mutex::new(None));
+    /// metadata of http streams that have been initialized, but have no local connection until their first data
+    pub static ref PENDING_STREAMS: Arc<RwLock<HashMap<StreamId, StreamMetadata>>> = Arc::new(RwLock::new(HashMap::new()));
+}
+
+#[derive(Debug, Clone)]
+pub struct ActiveStream {
+    pub tx: Sender<StreamMessage>,
+    pub window: SendWindow,
 }
 
 #[derive(Debug, Clone)]
@@ -177,6 +189,18 @@ fn build_config_from_interactive(
         control_tls_off: tls_off,
         first_run: true,
         wildcard: params.wildcard,
+        kind: TunnelKind::Http,
+        remote_port: None,
+        reserve: false,
+        http2: false,
+        forwarded_headers: true,
+        proxy_protocol: None,
+        policy: EdgePolicy::default(),
+        rate_limits: RateLimits::default(),
+        custom_domain: None,
+        group: None,
+        standby: false,
+        path_prefix: None,
     }
 }
 
@@ -446,6 +470,8 @@ async fn run_wormhole(
         websocket,
         sub_domain,
         hostname,
+        capabilities,
+        reserved,
     } = connect_to_wormhole(&config).await?;
 
     // Fetch taken domains for display
@@ -455,7 +481,13 @@ async fn run_wormhole(
         .map(|v| v.join(", "))
         .unwrap_or_default();
 
-    interface.did_connect(&sub_domain, &hostname, &taken_domains);
+    interface.did_connect(
+        &sub_domain,
+        &hostname,
+        &taken_domains,
+        reserved,
+        capabilities.contains(&Capability::Http2),
+    );
 
     // Save last session after successful connection (used by `neutun saves add`)
     {
@@ -476,6 +508,9 @@ async fn run_wormhole(
             } else {
                 Some(config.dashboard_port)
             },
+            tcp: config.kind == TunnelKind::Tcp,
+            remote_port: config.remote_port,
+            tls_passthrough: config.kind == TunnelKind::Tls,
         };
         crate::saved_config::save_last_session(&session);
     }
@@ -484,10 +519,11 @@ async fn run_wormhole(
     let (mut ws_sink, mut ws_stream) = websocket.split();
 
     // tunnel channel
-    let (tunnel_tx, mut tunnel_rx) = unbounded::<ControlPacket>();
+    let (tunnel_tx, mut tunnel_rx) = channel::<ControlPacket>(TUNNEL_QUEUE_SIZE);
 
     // continuously write to websocket tunnel
     let mut restart = restart_tx.clone();
+    let negotiated = capabilities.clone();
     tokio::spawn(async move {
         loop {
             let packet = match tunnel_rx.next().await {
@@ -499,6 +535,12 @@ async fn run_wormhole(
                 }
             };
 
+            // an older server would choke on a packet it doesn't know
+            if let Some(capability) = packet.capability().filter(|c| !negotiated.contains(c)) {
+                warn!("not sending {} the server doesn't support ({:?})", packet.packet_type(), capability);
+                continue;
+            }
+
             if let Err(e) = ws_sink.send(Message::binary(packet.serialize())).await {
                 warn!("failed to write message to tunnel websocket: {:?}", e);
                 let _ = restart.send(Some(Error::WebSocketError(e))).await;
@@ -519,6 +561,7 @@ async fn run_wormhole(
             Some(Ok(message)) => {
                 let packet = process_control_flow_message(
                     config.clone(),
+                    &capabilities,
                     tunnel_tx.clone(),
                     message.into_data().to_vec(),
                 )
@@ -545,6 +588,8 @@ struct Wormhole {
     websocket: WebSocketStream<MaybeTlsStream<TcpStream>>,
     sub_domain: String,
     hostname: String,
+    capabilities: Vec<Capability>,
+    reserved: bool,
 }
 
 async fn connect_to_wormhole(config: &Config) -> Result<Wormhole, Error> {
@@ -571,7 +616,7 @@ async fn connect_to_wormhole(config: &Config) -> Result<Wormhole, Error> {
     .await?;
 
     // send our Client Hello message
-    let client_hello = match config.secret_key.clone() {
+    let mut client_hello = match config.secret_key.clone() {
         Some(secret_key) => ClientHello::generate(
             config.sub_domain.clone(),
             config.domain.clone(),
@@ -593,6 +638,21 @@ async fn connect_to_wormhole(config: &Config) -> Result<Wormhole, Error> {
         }
     };
 
+    client_hello.kind = config.kind;
+    client_hello.reserve = config.reserve;
+    client_hello.http2 = config.http2;
+    client_hello.forwarded_headers = config.forwarded_headers;
+    client_hello.policy = config.policy.clone();
+    client_hello.rate_limits = config.rate_limits;
+    client_hello.custom_domain = config.custom_domain.clone();
+    client_hello.group = config.group;
+    client_hello.standby = config.standby;
+    client_hello.path_prefix = config.path_prefix.clone();
+    if config.kind == TunnelKind::Tcp {
+        // keep the port we were given last time across reconnects
+        client_hello.tcp_port = config.remote_port.or(*TCP_PORT.lock().await);
+    }
+
     info!("connecting to wormhole...");
 
     let hello = serde_json::to_vec(&client_hello).unwrap();
@@ -612,14 +672,67 @@ async fn connect_to_wormhole(config: &Config) -> Result<Wormhole, Error> {
         Error::ServerReplyInvalid
     })?;
 
-    let (sub_domain, hostname) = match server_hello {
+    let (sub_domain, hostname, capabilities, reserved) = match server_hello {
         ServerHello::Success {
             sub_domain,
             client_id,
             hostname,
+            protocol_version,
+            capabilities,
+            tcp_port,
+            reserved,
         } => {
             info!("Server accepted our connection. I am client_{}", client_id);
-            (sub_domain, hostname)
+            info!(
+                "negotiated protocol v{} with capabilities {:?}",
+                protocol_version, capabilities
+            );
+            // an older server would let everyone through
+            if !config.policy.is_empty() && !capabilities.contains(&Capability::EdgePolicy) {
+                return Err(Error::EdgePolicyUnsupported);
+            }
+            if !config.rate_limits.is_empty() && !capabilities.contains(&Capability::RateLimits) {
+                return Err(Error::RateLimitsUnsupported);
+            }
+            // an older server would refuse the other members of the group
+            if config.group.is_some() && !capabilities.contains(&Capability::Groups) {
+                return Err(Error::GroupsUnsupported);
+            }
+            if config.standby && !capabilities.contains(&Capability::Standby) {
+                return Err(Error::StandbyUnsupported);
+            }
+            // an older server would never route it to us
+            let hostname = match &config.custom_domain {
+                Some(_) if !capabilities.contains(&Capability::CustomDomains) => {
+                    return Err(Error::CustomDomainsUnsupported);
+                }
+                Some(custom_domain) => custom_domain.to_lowercase(),
+                None => hostname,
+            };
+
+            // an older server would send us every request on the host
+            let hostname = match &config.path_prefix {
+                Some(_) if !capabilities.contains(&Capability::PathPrefixes) => {
+                    return Err(Error::PathPrefixesUnsupported);
+                }
+                Some(path_prefix) => format!("{}{}", hostname, path_prefix),
+                None => hostname,
+            };
+
+            let hostname = match tcp_port {
+                Some(port) => {
+                    TCP_PORT.lock().await.replace(port);
+                    format!("{}:{}", hostname, port)
+                }
+                None => hostname,
+            };
+            (sub_domain, hostname, capabilities, reserved)
+        }
+        ServerHello::IncompatibleVersion {
+            min_version,
+            max_version,
+        } => {
+            return Err(Error::IncompatibleVersion(min_version, max_version));
         }
         ServerHello::AuthFailed => {
             return Err(Error::AuthenticationFailed);
@@ -637,19 +750,90 @@ async fn connect_to_wormhole(config: &Config) -> Result<Wormhole, Error> {
         websocket,
         sub_domain,
         hostname,
+        capabilities,
+        reserved,
     })
 }
 
+enum Forwarded {
+    Queued,
+    /// the stream's queue was full, or its local connection gone
+    Reset,
+    NoStream,
+}
+
+/// Hand data to a local stream without waiting on it: every stream shares the tunnel's reader,
+/// so one slow local service mustn't hold up the others, or our pings
+fn forward_to_stream(stream_id: &StreamId, data: Vec<u8>) -> Forwarded {
+    let mut streams = ACTIVE_STREAMS.write().unwrap();
+    // always send with the same sender, every clone of it gets a slot of its own past the queue size
+    let queued = match streams.get_mut(stream_id) {
+        Some(stream) => stream.tx.try_send(StreamMessage::Data(data)).is_ok(),
+        None => return Forwarded::NoStream,
+    };
+    if queued {
+        return Forwarded::Queued;
+    }
+
+    // the local side writes out what it has queued already, and then closes
+    if let Some(mut stream) = streams.remove(stream_id) {
+        stream.tx.close_channel();
+        stream.window.close();
+    }
+    Forwarded::Reset
+}
+
+/// Hand data to a local stream, waiting until it has room: all a server without flow control
+/// understands is being slowed down, as before
+async fn wait_for_stream(stream_id: &StreamId, data: Vec<u8>) -> Forwarded {
+    let tx = ACTIVE_STREAMS.read().unwrap().get(stream_id).map(|stream| stream.tx.clone());
+    match tx {
+        Some(mut tx) => match tx.send(StreamMessage::Data(data)).await {
+            Ok(()) => Forwarded::Queued,
+            Err(_) => Forwarded::Reset,
+        },
+        None => Forwarded::NoStream,
+    }
+}
+
 async fn process_control_flow_message(
     config: Config,
-    mut tunnel_tx: UnboundedSender<ControlPacket>,
+    capabilities: &[Capability],
+    mut tunnel_tx: Sender<ControlPacket>,
     payload: Vec<u8>,
 ) -> Result<ControlPacket, Box<dyn std::error::Error>> {
     let control_packet = ControlPacket::deserialize(&payload)?;
+    let flow_control = capabilities.contains(&Capability::FlowControl);
+    let request_streams = capabilities.contains(&Capability::RequestStreams);
 
     match &control_packet {
-        ControlPacket::Init(stream_id) => {
-            info!("stream[{:?}] -> init", stream_id.to_string());
+        ControlPacket::Init(stream_id, metadata) => {
+            info!("stream[{:?}] -> init {:?}", stream_id.to_string(), metadata);
+
+            // older servers don't tell us anything about the connection
+            let metadata = metadata.clone().unwrap_or_default();
+
+            // server-first protocols (i.e. ssh, mysql) need the local connection before any data arrives
+            if config.kind != TunnelKind::Http {
+                if local::setup_new_stream(
+                    config.clone(),
+                    tunnel_tx.clone(),
+                    stream_id.clone(),
+                    metadata,
+                    flow_control,
+                    request_streams,
+                )
+                .await
+                .is_none()
+                {
+                    error!("failed to open local tunnel")
+                }
+            } else {
+                PENDING_STREAMS
+                    .write()
+                    .unwrap()
+                    .insert(stream_id.clone(), metadata);
+            }
         }
         ControlPacket::Ping(reconnect_token) => {
             log::info!("got ping. reconnect_token={}", reconnect_token.is_some());
@@ -665,11 +849,19 @@ async fn process_control_flow_message(
             let stream_id = stream_id.clone();
 
             info!("got end stream [{:?}]", &stream_id);
+            PENDING_STREAMS.write().unwrap().remove(&stream_id);
+
+            // a request stream only ends once its response is through, otherwise give the local service a moment to finish
+            let linger = if config.kind == TunnelKind::Http && request_streams {
+                Duration::ZERO
+            } else {
+                Duration::from_secs(5)
+            };
 
             tokio::spawn(async move {
                 let stream = ACTIVE_STREAMS.read().unwrap().get(&stream_id).cloned();
-                if let Some(mut tx) = stream {
-                    tokio::time::sleep(Duration::from_secs(5)).await;
+                if let Some(ActiveStream { mut tx, .. }) = stream {
+                    tokio::time::sleep(linger).await;
                     let _ = tx.send(StreamMessage::Close).await.map_err(|e| {
                         error!("failed to send stream close: {:?}", e);
                     });
@@ -684,30 +876,128 @@ async fn process_control_flow_message(
                 data.len()
             );
 
-            if !ACTIVE_STREAMS.read().unwrap().contains_key(&stream_id) {
-                if local::setup_new_stream(config.clone(), tunnel_tx.clone(), stream_id.clone())
-                    .await
-                    .is_none()
+            // tcp and tls streams only ever get their local connection on init
+            if config.kind == TunnelKind::Http && !ACTIVE_STREAMS.read().unwrap().contains_key(stream_id) {
+                let metadata = PENDING_STREAMS
+                    .write()
+                    .unwrap()
+                    .remove(stream_id)
+                    .unwrap_or_default();
+                if local::setup_new_stream(
+                    config.clone(),
+                    tunnel_tx.clone(),
+                    stream_id.clone(),
+                    metadata,
+                    flow_control,
+                    request_streams,
+                )
+                .await
+                .is_none()
                 {
                     error!("failed to open local tunnel")
                 }
             }
 
-            // find the right stream
-            let active_stream = ACTIVE_STREAMS.read().unwrap().get(&stream_id).cloned();
-
-            // forward data to it
-            if let Some(mut tx) = active_stream {
-                tx.send(StreamMessage::Data(data.clone())).await?;
-                info!("forwarded to local tcp ({})", stream_id.to_string());
+            let forwarded = if flow_control {
+                forward_to_stream(stream_id, data.clone())
             } else {
-                error!("got data but no stream to send it to.");
-                let _ = tunnel_tx
-                    .send(ControlPacket::Refused(stream_id.clone()))
-                    .await?;
+                wait_for_stream(stream_id, data.clone()).await
+            };
+            match forwarded {
+                Forwarded::Queued => info!("forwarded to local tcp ({})", stream_id.to_string()),
+                Forwarded::Reset => {
+                    error!("local stream can't keep up, resetting it ({})", stream_id.to_string());
+                    let _ = tunnel_tx.send(ControlPacket::End(stream_id.clone())).await?;
+                }
+                Forwarded::NoStream => {
+                    error!("got data but no stream to send it to.");
+                    let _ = tunnel_tx
+                        .send(ControlPacket::Refused(stream_id.clone()))
+                        .await?;
+                }
+            }
+        }
+        ControlPacket::Notice(notice) => {
+            warn!("server notice: {:?}", notice);
+            introspect::notice(notice);
+        }
+        ControlPacket::WindowUpdate(stream_id, credit) => {
+            debug!("stream[{:?}] -> window update: {}", stream_id.to_string(), credit);
+
+            if let Some(stream) = ACTIVE_STREAMS.read().unwrap().get(stream_id) {
+                stream.window.grant(*credit);
             }
         }
     };
 
     Ok(control_packet.clone())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::channel::mpsc::Receiver;
+
+    fn open_stream() -> (StreamId, Receiver<StreamMessage>) {
+        let stream_id = StreamId::generate();
+        let (tx, rx) = channel(STREAM_QUEUE_SIZE);
+        let window = SendWindow::new(true);
+        ACTIVE_STREAMS
+            .write()
+            .unwrap()
+            .insert(stream_id.clone(), ActiveStream { tx, window });
+        (stream_id, rx)
+    }
+
+    #[tokio::test]
+    async fn stuck_stream_does_not_block_the_others() {
+        // nobody ever reads the first stream, like a local service that stopped reading
+        let (stuck, _stuck_rx) = open_stream();
+        let (live, mut live_rx) = open_stream();
+        let reader = tokio::spawn(async move {
+            let mut received = 0;
+            while let Some(StreamMessage::Data(data)) = live_rx.next().await {
+                received += data.len();
+            }
+            received
+        });
+
+        // twice a window for both streams, in the server's chunks
+        let chunks = 2 * INITIAL_STREAM_WINDOW as usize / STREAM_CHUNK_SIZE;
+        let mut reset = false;
+        for _ in 0..chunks {
+            match forward_to_stream(&stuck, vec![0; STREAM_CHUNK_SIZE]) {
+                Forwarded::Reset => reset = true,
+                Forwarded::Queued => assert!(!reset),
+                Forwarded::NoStream => assert!(reset),
+            }
+            assert!(matches!(
+                forward_to_stream(&live, vec![0; STREAM_CHUNK_SIZE]),
+                Forwarded::Queued
+            ));
+            // the tunnel's reader waits for the next message from the server here
+            tokio::task::yield_now().await;
+        }
+
+        assert!(reset);
+        assert!(!ACTIVE_STREAMS.read().unwrap().contains_key(&stuck));
+
+        ACTIVE_STREAMS.write().unwrap().remove(&live);
+        let received = tokio::time::timeout(Duration::from_secs(5), reader)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(received, chunks * STREAM_CHUNK_SIZE);
+    }
+
+    #[tokio::test]
+    async fn small_packets_within_the_window_are_never_reset() {
+        // nobody reads the stream, and the server only sends what its window lets through
+        let (stream_id, _rx) = open_stream();
+        let window = SendWindow::new(true);
+        while tokio::time::timeout(Duration::from_millis(10), window.reserve(1)).await.is_ok() {
+            assert!(matches!(forward_to_stream(&stream_id, vec![0]), Forwarded::Queued));
+        }
+        ACTIVE_STREAMS.write().unwrap().remove(&stream_id);
+    }
+}
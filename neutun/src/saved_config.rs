@@ -1,6 +1,7 @@
 use serde::{Deserialize, Serialize};
 use std::fs;
 use std::path::PathBuf;
+use neutun_lib::TunnelKind;
 
 use crate::config::{
     CONFIG_FILE, DAEMONS_DIR, DEFAULT_CONTROL_PORT, DEFAULT_HOST, LAST_SESSION_FILE,
@@ -70,6 +71,27 @@ pub struct SessionConfig {
     /// Control TLS on/off
     pub tls: bool,
     pub dashboard_port: Option<u16>,
+    /// Raw TCP tunnel instead of HTTP
+    #[serde(default)]
+    pub tcp: bool,
+    /// Requested public port for a TCP tunnel
+    #[serde(default)]
+    pub remote_port: Option<u16>,
+    /// TLS passthrough tunnel instead of HTTP
+    #[serde(default)]
+    pub tls_passthrough: bool,
+}
+
+impl SessionConfig {
+    pub fn kind(&self) -> TunnelKind {
+        if self.tcp {
+            TunnelKind::Tcp
+        } else if self.tls_passthrough {
+            TunnelKind::Tls
+        } else {
+            TunnelKind::Http
+        }
+    }
 }
 
 /// Daemon tracking entry stored in daemons/<pid>.json
@@ -0,0 +1,207 @@
+use std::sync::Arc;
+use tokio::sync::Semaphore;
+
+/// How many bytes a peer may send on a stream before it has to wait for a window update
+pub const INITIAL_STREAM_WINDOW: u32 = 256 * 1024;
+
+/// The most the server reads from a public connection at once, and so puts in a single `ControlPacket::Data`
+pub const STREAM_CHUNK_SIZE: usize = 1024;
+
+/// How many messages may queue up for a single stream: a full window of packets (see `charge`),
+/// and a few more to end it. The reader shared by all streams never waits for a full queue, it resets the stream.
+pub const STREAM_QUEUE_SIZE: usize = INITIAL_STREAM_WINDOW as usize / STREAM_CHUNK_SIZE + 8;
+
+/// Consumed bytes are only acknowledged once at least this many have piled up
+const WINDOW_UPDATE_THRESHOLD: u32 = INITIAL_STREAM_WINDOW / 4;
+
+/// The credit a packet of `n` bytes takes: at least a whole chunk,
+/// so a window never lets more packets through than a stream's queue holds, however small they are,
+/// and at most the whole window, so a packet bigger than it can still be sent once everything before it is acknowledged
+pub fn charge(n: usize) -> usize {
+    n.clamp(STREAM_CHUNK_SIZE, INITIAL_STREAM_WINDOW as usize)
+}
+
+/// The credit we have left to send data on a stream.
+/// Streams to peers without flow control support are never throttled.
+#[derive(Debug, Clone)]
+pub struct SendWindow(Option<Arc<Semaphore>>);
+
+impl SendWindow {
+    pub fn new(flow_control: bool) -> Self {
+        if flow_control {
+            SendWindow(Some(Arc::new(Semaphore::new(
+                INITIAL_STREAM_WINDOW as usize,
+            ))))
+        } else {
+            SendWindow(None)
+        }
+    }
+
+    /// Wait until we may send a packet of `n` bytes.
+    /// Returns false if the window was closed while waiting.
+    pub async fn reserve(&self, n: usize) -> bool {
+        let window = match &self.0 {
+            Some(window) => window,
+            None => return true,
+        };
+
+        match window.acquire_many(charge(n) as u32).await {
+            Ok(permit) => {
+                permit.forget();
+                true
+            }
+            Err(_) => false,
+        }
+    }
+
+    /// The peer has consumed `n` more bytes.
+    /// It can't have consumed more than we sent, so credit past the initial window is ignored.
+    pub fn grant(&self, n: u32) {
+        if let Some(window) = &self.0 {
+            let room = (INITIAL_STREAM_WINDOW as usize).saturating_sub(window.available_permits());
+            window.add_permits((n as usize).min(room));
+        }
+    }
+
+    /// Wake up anyone waiting for credit, the stream is gone
+    pub fn close(&self) {
+        if let Some(window) = &self.0 {
+            window.close();
+        }
+    }
+}
+
+/// Tracks bytes we consumed from a stream but haven't acknowledged yet
+#[derive(Debug)]
+pub struct ReceiveWindow {
+    flow_control: bool,
+    consumed: u32,
+}
+
+impl ReceiveWindow {
+    pub fn new(flow_control: bool) -> Self {
+        ReceiveWindow {
+            flow_control,
+            consumed: 0,
+        }
+    }
+
+    /// Record a consumed packet of `n` bytes.
+    /// Returns the credit to send back to the peer once enough has piled up.
+    pub fn consume(&mut self, n: usize) -> Option<u32> {
+        if !self.flow_control {
+            return None;
+        }
+
+        self.consumed += charge(n) as u32;
+        if self.consumed < WINDOW_UPDATE_THRESHOLD {
+            return None;
+        }
+
+        Some(std::mem::take(&mut self.consumed))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use tokio::time::timeout;
+
+    /// Whether `reserve` gets its credit without waiting for a grant
+    async fn reserves_now(window: &SendWindow, n: usize) -> bool {
+        timeout(Duration::from_millis(50), window.reserve(n)).await.is_ok()
+    }
+
+    #[tokio::test]
+    async fn small_packets_take_a_whole_chunk() {
+        let window = SendWindow::new(true);
+        let packets = INITIAL_STREAM_WINDOW as usize / STREAM_CHUNK_SIZE;
+        for _ in 0..packets {
+            assert!(reserves_now(&window, 1).await);
+        }
+        // the queue on the other end holds this many and a few more
+        assert!(packets < STREAM_QUEUE_SIZE);
+        assert!(!reserves_now(&window, 1).await);
+
+        window.grant(charge(1) as u32);
+        assert!(reserves_now(&window, 1).await);
+    }
+
+    #[tokio::test]
+    async fn big_packets_take_their_size() {
+        let window = SendWindow::new(true);
+        assert!(reserves_now(&window, INITIAL_STREAM_WINDOW as usize - 4096).await);
+        assert!(reserves_now(&window, 4096).await);
+        assert!(!reserves_now(&window, 1).await);
+    }
+
+    #[tokio::test]
+    async fn packets_bigger_than_the_window_take_all_of_it() {
+        let oversized = INITIAL_STREAM_WINDOW as usize + 4096;
+        let window = SendWindow::new(true);
+        assert!(reserves_now(&window, oversized).await);
+        assert!(!reserves_now(&window, 1).await);
+
+        let mut receiver = ReceiveWindow::new(true);
+        window.grant(receiver.consume(oversized).unwrap());
+        assert!(reserves_now(&window, oversized).await);
+    }
+
+    #[tokio::test]
+    async fn credit_never_grows_the_window_past_its_size() {
+        let window = SendWindow::new(true);
+        for _ in 0..4 {
+            window.grant(u32::MAX);
+        }
+        assert!(reserves_now(&window, INITIAL_STREAM_WINDOW as usize).await);
+        assert!(!reserves_now(&window, 1).await);
+
+        // what was sent comes back, and no more
+        window.grant(4096);
+        window.grant(u32::MAX);
+        assert!(reserves_now(&window, INITIAL_STREAM_WINDOW as usize).await);
+        assert!(!reserves_now(&window, 1).await);
+    }
+
+    #[tokio::test]
+    async fn close_wakes_up_a_waiting_reserve() {
+        let window = SendWindow::new(true);
+        assert!(window.reserve(INITIAL_STREAM_WINDOW as usize).await);
+
+        let waiting = tokio::spawn({
+            let window = window.clone();
+            async move { window.reserve(1).await }
+        });
+        tokio::task::yield_now().await;
+        window.close();
+
+        let reserved = timeout(Duration::from_secs(1), waiting).await.unwrap().unwrap();
+        assert!(!reserved);
+        assert!(!window.reserve(1).await);
+    }
+
+    #[tokio::test]
+    async fn no_flow_control_never_waits() {
+        let window = SendWindow::new(false);
+        for _ in 0..2 {
+            assert!(reserves_now(&window, INITIAL_STREAM_WINDOW as usize).await);
+        }
+        assert_eq!(ReceiveWindow::new(false).consume(INITIAL_STREAM_WINDOW as usize), None);
+    }
+
+    #[test]
+    fn credit_goes_back_once_past_the_threshold() {
+        let mut window = ReceiveWindow::new(true);
+        let chunks = (WINDOW_UPDATE_THRESHOLD as usize / STREAM_CHUNK_SIZE) as u32;
+        for _ in 1..chunks {
+            assert_eq!(window.consume(10), None);
+        }
+        // small packets are credited like the sender charged them
+        assert_eq!(window.consume(10), Some(chunks * STREAM_CHUNK_SIZE as u32));
+
+        assert_eq!(window.consume(WINDOW_UPDATE_THRESHOLD as usize - 1), None);
+        assert_eq!(window.consume(1), Some(WINDOW_UPDATE_THRESHOLD - 1 + STREAM_CHUNK_SIZE as u32));
+        assert_eq!(window.consume(WINDOW_UPDATE_THRESHOLD as usize), Some(WINDOW_UPDATE_THRESHOLD));
+    }
+}
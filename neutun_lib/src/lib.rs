@@ -2,6 +2,12 @@ use base64::{engine::general_purpose, Engine as _};
 use rand::prelude::*;
 use serde::{Deserialize, Serialize};
 use sha2::Digest;
+use std::net::SocketAddr;
+
+mod flow_control;
+pub use self::flow_control::*;
+mod proxy_protocol;
+pub use self::proxy_protocol::*;
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(transparent)]
@@ -29,6 +35,71 @@ impl SecretKey {
 #[serde(transparent)]
 pub struct ReconnectToken(pub String);
 
+/// The wire protocol version spoken by this build.
+/// Bump this whenever `ControlPacket` framing changes.
+/// Peers that predate versioning don't send one and are treated as version 0.
+///
+/// - 1: the handshake carries the protocol version and capabilities
+/// - 2: `ControlPacket::WindowUpdate`, `StreamMetadata` in `ControlPacket::Init` and `ControlPacket::Notice`,
+///   each only sent to peers with its capability (see `ControlPacket::capability`)
+pub const PROTOCOL_VERSION: u32 = 2;
+
+/// Optional protocol features a peer may advertise during the handshake.
+/// A feature is only used once both sides have advertised it.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum Capability {
+    /// per-stream window updates, see `ControlPacket::WindowUpdate`
+    FlowControl,
+    /// every stream of an http tunnel carries a single request, and is only ended after its whole response.
+    /// either side sends `ControlPacket::End` once it has nothing more to send on a stream.
+    RequestStreams,
+    /// http tunnels can forward requests over http/2, for clients that ask for it with `ClientHello::http2`
+    Http2,
+    /// `ControlPacket::Init` carries `StreamMetadata` about the public connection the stream is for
+    StreamMetadata,
+    /// the server enforces the `EdgePolicy` of `ClientHello::policy`
+    EdgePolicy,
+    /// the server enforces the `RateLimits` of `ClientHello::rate_limits`
+    RateLimits,
+    /// the server sends `ControlPacket::Notice` when it holds back our traffic
+    Notices,
+    /// the server routes `ClientHello::custom_domain` to us, once its dns proves we own it
+    CustomDomains,
+    /// clients of the same key can share a host as a load balanced group, see `ClientHello::group`
+    Groups,
+    /// a client can wait as a hot standby for a host, see `ClientHello::standby`
+    Standby,
+    /// clients can share a host by path, see `ClientHello::path_prefix`
+    PathPrefixes,
+    /// a capability advertised by a newer peer that we don't know about
+    #[serde(other)]
+    Unknown,
+}
+
+/// The capabilities supported by this build
+pub const CAPABILITIES: &[Capability] = &[
+    Capability::FlowControl,
+    Capability::RequestStreams,
+    Capability::Http2,
+    Capability::StreamMetadata,
+    Capability::EdgePolicy,
+    Capability::RateLimits,
+    Capability::Notices,
+    Capability::CustomDomains,
+    Capability::Groups,
+    Capability::Standby,
+    Capability::PathPrefixes,
+];
+
+/// Keep only the capabilities that both sides support
+pub fn negotiate_capabilities(ours: &[Capability], theirs: &[Capability]) -> Vec<Capability> {
+    ours.iter()
+        .filter(|c| **c != Capability::Unknown && theirs.contains(c))
+        .copied()
+        .collect()
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(rename_all = "snake_case")]
 pub enum ServerHello {
@@ -36,6 +107,23 @@ pub enum ServerHello {
         sub_domain: String,
         hostname: String,
         client_id: ClientId,
+        /// the protocol version both sides will speak
+        #[serde(default)]
+        protocol_version: u32,
+        /// the capabilities both sides support
+        #[serde(default)]
+        capabilities: Vec<Capability>,
+        /// the public port allocated for a tcp tunnel
+        #[serde(default)]
+        tcp_port: Option<u16>,
+        /// the sub-domain is reserved for our key, even while we're disconnected
+        #[serde(default)]
+        reserved: bool,
+    },
+    /// the server refuses to talk to a client of this protocol version
+    IncompatibleVersion {
+        min_version: u32,
+        max_version: u32,
     },
     SubDomainInUse,
     InvalidSubDomain,
@@ -71,6 +159,46 @@ pub struct ClientHello {
     pub reconnect_token: Option<ReconnectToken>,
     #[serde(default)]
     pub wildcard: bool,
+    #[serde(default)]
+    pub protocol_version: u32,
+    #[serde(default)]
+    pub capabilities: Vec<Capability>,
+    #[serde(default)]
+    pub kind: TunnelKind,
+    /// the public port we'd like for a tcp tunnel, if available
+    #[serde(default)]
+    pub tcp_port: Option<u16>,
+    /// keep the sub-domain for our key after we disconnect
+    #[serde(default)]
+    pub reserve: bool,
+    /// the local service speaks http/2, i.e. grpc, so requests should reach it over http/2
+    #[serde(default)]
+    pub http2: bool,
+    /// let the server add forwarding headers, i.e. `X-Forwarded-For`, to our requests
+    #[serde(default = "forwarded_headers_default")]
+    pub forwarded_headers: bool,
+    /// who may use the tunnel, checked by the server before anything reaches us
+    #[serde(default)]
+    pub policy: EdgePolicy,
+    /// how hard the public may use the tunnel, on top of the server's own limits
+    #[serde(default)]
+    pub rate_limits: RateLimits,
+    /// a host of our own (i.e. `staging.example.com`) that CNAMEs to the server, routed to us alongside our sub-domain
+    #[serde(default)]
+    pub custom_domain: Option<String>,
+    /// share our host with other clients of the same key, which take turns with its streams
+    #[serde(default)]
+    pub group: Option<Balance>,
+    /// take no traffic while another client of the same key has our host, and take over once it disconnects
+    #[serde(default)]
+    pub standby: bool,
+    /// take only the requests under this path (i.e. `/api`), the longest matching prefix on a host wins
+    #[serde(default)]
+    pub path_prefix: Option<String>,
+}
+
+fn forwarded_headers_default() -> bool {
+    true
 }
 
 impl ClientHello {
@@ -87,6 +215,19 @@ impl ClientHello {
             domain,
             reconnect_token: None,
             wildcard,
+            protocol_version: PROTOCOL_VERSION,
+            capabilities: CAPABILITIES.to_vec(),
+            kind: TunnelKind::Http,
+            tcp_port: None,
+            reserve: false,
+            http2: false,
+            forwarded_headers: true,
+            policy: EdgePolicy::default(),
+            rate_limits: RateLimits::default(),
+            custom_domain: None,
+            group: None,
+            standby: false,
+            path_prefix: None,
         }
     }
 
@@ -98,10 +239,115 @@ impl ClientHello {
             client_type: ClientType::Anonymous,
             reconnect_token: Some(reconnect_token),
             wildcard,
+            protocol_version: PROTOCOL_VERSION,
+            capabilities: CAPABILITIES.to_vec(),
+            kind: TunnelKind::Http,
+            tcp_port: None,
+            reserve: false,
+            http2: false,
+            forwarded_headers: true,
+            policy: EdgePolicy::default(),
+            rate_limits: RateLimits::default(),
+            custom_domain: None,
+            group: None,
+            standby: false,
+            path_prefix: None,
         }
     }
 }
 
+/// Access rules for a tunnel's public traffic, enforced by the server at the edge.
+/// Empty lists don't restrict anything.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct EdgePolicy {
+    /// `user:password` pairs accepted with http basic auth
+    #[serde(default)]
+    pub basic_auth: Vec<String>,
+    /// tokens accepted with `Authorization: Bearer`.
+    /// with basic auth credentials as well, either will do.
+    #[serde(default)]
+    pub bearer_tokens: Vec<String>,
+    /// the only ip addresses or cidr ranges that may connect, i.e. `203.0.113.0/24`
+    #[serde(default)]
+    pub allow_ips: Vec<String>,
+    /// the only http methods allowed, i.e. `GET`
+    #[serde(default)]
+    pub allow_methods: Vec<String>,
+}
+
+impl EdgePolicy {
+    pub fn is_empty(&self) -> bool {
+        self == &EdgePolicy::default()
+    }
+
+    /// The policy has rules that only make sense for http requests
+    pub fn is_http_only(&self) -> bool {
+        !self.basic_auth.is_empty() || !self.bearer_tokens.is_empty() || !self.allow_methods.is_empty()
+    }
+}
+
+/// Rate limits for a tunnel's public traffic, in events per second, allowing bursts of a second's worth.
+/// The server has limits of its own, the tighter of each applies.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RateLimits {
+    /// http requests to the whole tunnel
+    #[serde(default)]
+    pub requests: Option<u32>,
+    /// new public connections to the whole tunnel
+    #[serde(default)]
+    pub connections: Option<u32>,
+    /// http requests from a single remote ip
+    #[serde(default)]
+    pub requests_per_ip: Option<u32>,
+    /// new public connections from a single remote ip
+    #[serde(default)]
+    pub connections_per_ip: Option<u32>,
+}
+
+impl RateLimits {
+    pub fn is_empty(&self) -> bool {
+        self == &RateLimits::default()
+    }
+
+    /// The tighter of two limits for each event
+    pub fn tighten(&self, other: &RateLimits) -> RateLimits {
+        let min = |a: Option<u32>, b: Option<u32>| match (a, b) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        };
+        RateLimits {
+            requests: min(self.requests, other.requests),
+            connections: min(self.connections, other.connections),
+            requests_per_ip: min(self.requests_per_ip, other.requests_per_ip),
+            connections_per_ip: min(self.connections_per_ip, other.connections_per_ip),
+        }
+    }
+}
+
+/// What kind of traffic a tunnel carries
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum TunnelKind {
+    /// http requests routed by their host header
+    #[default]
+    Http,
+    /// raw tcp connections on a server allocated public port
+    Tcp,
+    /// tls connections routed by their sni host, passed through without being decrypted
+    Tls,
+}
+
+/// How a load balanced group spreads new streams over its members
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Balance {
+    /// each member in turn
+    #[default]
+    RoundRobin,
+    /// the member with the fewest open streams
+    LeastStreams,
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum ClientType {
     Auth { key: SecretKey },
@@ -149,13 +395,55 @@ impl StreamId {
     }
 }
 
+/// What the server knows about the public connection behind a stream
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct StreamMetadata {
+    /// the remote client
+    #[serde(default)]
+    pub peer_addr: Option<SocketAddr>,
+    /// the host it asked for, i.e. which sub-domain of a wildcard tunnel
+    #[serde(default)]
+    pub host: String,
+    /// it came in over tls, whether the server terminated it or passed it through
+    #[serde(default)]
+    pub tls: bool,
+    /// the public port it connected to
+    #[serde(default)]
+    pub public_port: Option<u16>,
+}
+
+/// Something about a tunnel's traffic the server wants the client to tell its user
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Notice {
+    /// traffic is slowed down to the throughput cap of the tunnel's key
+    Throttled { bytes_per_sec: u64 },
+    /// the tunnel's key used up its transfer quota for the month, traffic is refused until the next one
+    QuotaExceeded { quota_bytes: u64 },
+    /// a standby tunnel waits for the client holding its host to disconnect
+    StandingBy,
+    /// a standby tunnel took over its host, traffic is coming
+    Promoted,
+    /// a notice from a newer server that we don't know about
+    #[serde(other)]
+    Unknown,
+}
+
 #[derive(Debug, Clone)]
 pub enum ControlPacket {
-    Init(StreamId),
+    /// a new stream, with metadata about its public connection.
+    /// the metadata is only sent to peers with `Capability::StreamMetadata`.
+    Init(StreamId, Option<StreamMetadata>),
     Data(StreamId, Vec<u8>),
     Refused(StreamId),
     End(StreamId),
     Ping(Option<ReconnectToken>),
+    /// the receiver consumed this many more bytes of the stream.
+    /// only sent to peers with `Capability::FlowControl`.
+    WindowUpdate(StreamId, u32),
+    /// the server is holding back the tunnel's traffic.
+    /// only sent to peers with `Capability::Notices`.
+    Notice(Notice),
 }
 
 pub const PING_INTERVAL: u64 = 30;
@@ -166,7 +454,12 @@ const TOKEN_STREAM: StreamId = StreamId([0xF, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00
 impl ControlPacket {
     pub fn serialize(self) -> Vec<u8> {
         match self {
-            ControlPacket::Init(sid) => [vec![0x01], sid.0.to_vec()].concat(),
+            ControlPacket::Init(sid, metadata) => {
+                let data = metadata.map_or(vec![], |metadata| {
+                    serde_json::to_vec(&metadata).unwrap_or_default()
+                });
+                [vec![0x01], sid.0.to_vec(), data].concat()
+            }
             ControlPacket::Data(sid, data) => [vec![0x02], sid.0.to_vec(), data].concat(),
             ControlPacket::Refused(sid) => [vec![0x03], sid.0.to_vec()].concat(),
             ControlPacket::End(sid) => [vec![0x04], sid.0.to_vec()].concat(),
@@ -176,16 +469,35 @@ impl ControlPacket {
                 });
                 [vec![0x05], data].concat()
             }
+            ControlPacket::WindowUpdate(sid, credit) => {
+                [vec![0x06], sid.0.to_vec(), credit.to_be_bytes().to_vec()].concat()
+            }
+            ControlPacket::Notice(notice) => {
+                let data = serde_json::to_vec(&notice).unwrap_or_default();
+                [vec![0x07], EMPTY_STREAM.0.to_vec(), data].concat()
+            }
+        }
+    }
+
+    /// The capability a peer needs to understand the packet, if it's newer than the original framing
+    pub fn capability(&self) -> Option<Capability> {
+        match self {
+            ControlPacket::Init(_, Some(_)) => Some(Capability::StreamMetadata),
+            ControlPacket::WindowUpdate(_, _) => Some(Capability::FlowControl),
+            ControlPacket::Notice(_) => Some(Capability::Notices),
+            _ => None,
         }
     }
 
     pub fn packet_type(&self) -> &str {
         match &self {
             ControlPacket::Ping(_) => "PING",
-            ControlPacket::Init(_) => "INIT STREAM",
+            ControlPacket::Init(_, _) => "INIT STREAM",
             ControlPacket::Data(_, _) => "STREAM DATA",
             ControlPacket::Refused(_) => "REFUSED",
             ControlPacket::End(_) => "END STREAM",
+            ControlPacket::WindowUpdate(_, _) => "WINDOW UPDATE",
+            ControlPacket::Notice(_) => "NOTICE",
         }
     }
 
@@ -199,7 +511,13 @@ impl ControlPacket {
         let stream_id = StreamId(stream_id);
 
         let packet = match data[0] {
-            0x01 => ControlPacket::Init(stream_id),
+            0x01 => {
+                let metadata = match &data[9..] {
+                    [] => None,
+                    metadata => Some(serde_json::from_slice(metadata)?),
+                };
+                ControlPacket::Init(stream_id, metadata)
+            }
             0x02 => ControlPacket::Data(stream_id, data[9..].to_vec()),
             0x03 => ControlPacket::Refused(stream_id),
             0x04 => ControlPacket::End(stream_id),
@@ -212,9 +530,63 @@ impl ControlPacket {
                     )))
                 }
             }
+            0x06 => {
+                let mut credit = [0u8; 4];
+                credit.copy_from_slice(
+                    data.get(9..13)
+                        .ok_or("invalid DataPacket, missing window credit")?,
+                );
+                ControlPacket::WindowUpdate(stream_id, u32::from_be_bytes(credit))
+            }
+            0x07 => ControlPacket::Notice(serde_json::from_slice(&data[9..])?),
             _ => return Err("invalid control byte in DataPacket".into()),
         };
 
         Ok(packet)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn new_packets_need_their_capability() {
+        let stream_id = StreamId::generate();
+        assert_eq!(ControlPacket::Init(stream_id.clone(), None).capability(), None);
+        assert_eq!(
+            ControlPacket::Init(stream_id.clone(), Some(StreamMetadata::default())).capability(),
+            Some(Capability::StreamMetadata)
+        );
+        assert_eq!(
+            ControlPacket::WindowUpdate(stream_id.clone(), 1).capability(),
+            Some(Capability::FlowControl)
+        );
+        assert_eq!(
+            ControlPacket::Notice(Notice::Promoted).capability(),
+            Some(Capability::Notices)
+        );
+        assert_eq!(ControlPacket::Data(stream_id, vec![1]).capability(), None);
+    }
+
+    #[test]
+    fn window_update_round_trip() {
+        let stream_id = StreamId::generate();
+        let data = ControlPacket::WindowUpdate(stream_id.clone(), 65536).serialize();
+        match ControlPacket::deserialize(&data).unwrap() {
+            ControlPacket::WindowUpdate(id, credit) => {
+                assert_eq!(id, stream_id);
+                assert_eq!(credit, 65536);
+            }
+            packet => panic!("unexpected packet {}", packet.packet_type()),
+        }
+        assert!(ControlPacket::deserialize(&data[..11]).is_err());
+    }
+
+    #[test]
+    fn unknown_tag_is_an_error() {
+        let mut data = ControlPacket::End(StreamId::generate()).serialize();
+        data[0] = 0x7f;
+        assert!(ControlPacket::deserialize(&data).is_err());
+    }
+}
@@ -0,0 +1,377 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+/// How every v2 header starts
+const V2_SIGNATURE: &[u8; 12] = b"\r\n\r\n\0\r\nQUIT\n";
+const V1_PREFIX: &[u8] = b"PROXY ";
+/// the longest a v1 header may be, line ending included
+const V1_MAX_LEN: usize = 107;
+/// the shortest header of either version, `PROXY UNKNOWN\r\n`
+const MIN_LEN: usize = 15;
+/// a v2 header up to its length field
+const V2_FIXED_LEN: usize = 16;
+/// the v2 TLV saying the client connected over tls, and its `client` bit for it
+const PP2_TYPE_SSL: u8 = 0x20;
+const PP2_CLIENT_SSL: u8 = 0x01;
+
+/// A PROXY protocol header, which a proxy sends ahead of a connection to say who it really came from.
+/// See https://www.haproxy.org/download/2.9/doc/proxy-protocol.txt
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProxyHeader {
+    /// the client's address and the address it connected to.
+    /// unknown for health checks from the proxy itself and for protocols other than tcp.
+    pub addresses: Option<(SocketAddr, SocketAddr)>,
+    /// whether the client connected over tls. only v2 headers can say so.
+    pub tls: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ParsedProxyHeader {
+    /// the header, and how many bytes it takes up
+    Complete(ProxyHeader, usize),
+    /// at least this many more bytes are needed.
+    /// reading just as many never reads past the header.
+    Incomplete(usize),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InvalidProxyHeader;
+
+impl std::fmt::Display for InvalidProxyHeader {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "invalid proxy protocol header")
+    }
+}
+
+impl std::error::Error for InvalidProxyHeader {}
+
+impl ProxyHeader {
+    /// The client the connection came from, if the proxy knows it
+    pub fn source(&self) -> Option<SocketAddr> {
+        self.addresses.map(|(source, _)| source)
+    }
+
+    /// Parse a v1 or v2 header from the start of `buf`
+    pub fn parse(buf: &[u8]) -> Result<ParsedProxyHeader, InvalidProxyHeader> {
+        if buf.starts_with(V1_PREFIX) {
+            return parse_v1(buf);
+        }
+
+        if buf.starts_with(V2_SIGNATURE) {
+            return parse_v2(buf);
+        }
+
+        if V1_PREFIX.starts_with(buf) {
+            return Ok(ParsedProxyHeader::Incomplete(MIN_LEN - buf.len()));
+        }
+
+        if V2_SIGNATURE.starts_with(buf) {
+            return Ok(ParsedProxyHeader::Incomplete(V2_FIXED_LEN - buf.len()));
+        }
+
+        Err(InvalidProxyHeader)
+    }
+
+    /// The human readable v1 form of the header
+    pub fn to_v1(&self) -> Vec<u8> {
+        let (source, destination) = match self.addresses {
+            Some(addresses) => addresses,
+            None => return b"PROXY UNKNOWN\r\n".to_vec(),
+        };
+
+        let line = match (source.ip(), destination.ip()) {
+            (IpAddr::V4(source_ip), IpAddr::V4(destination_ip)) => format!(
+                "PROXY TCP4 {} {} {} {}\r\n",
+                source_ip,
+                destination_ip,
+                source.port(),
+                destination.port()
+            ),
+            // both addresses have to be of the same family
+            (source_ip, destination_ip) => format!(
+                "PROXY TCP6 {} {} {} {}\r\n",
+                to_ipv6(source_ip),
+                to_ipv6(destination_ip),
+                source.port(),
+                destination.port()
+            ),
+        };
+
+        line.into_bytes()
+    }
+
+    /// The binary v2 form of the header
+    pub fn to_v2(&self) -> Vec<u8> {
+        let (family, body) = match self.addresses {
+            None => (0x00, vec![]),
+            Some((source, destination)) => {
+                let ports = [source.port().to_be_bytes(), destination.port().to_be_bytes()].concat();
+                match (source.ip(), destination.ip()) {
+                    // tcp over ipv4
+                    (IpAddr::V4(source_ip), IpAddr::V4(destination_ip)) => {
+                        (0x11, [&source_ip.octets()[..], &destination_ip.octets(), &ports].concat())
+                    }
+                    // tcp over ipv6, both addresses have to be of the same family
+                    (source_ip, destination_ip) => (
+                        0x21,
+                        [&to_ipv6(source_ip).octets()[..], &to_ipv6(destination_ip).octets(), &ports].concat(),
+                    ),
+                }
+            }
+        };
+
+        // the ssl tlv with just its client field, and no verify result or sub-tlvs
+        let body = match self.tls {
+            true => [&body[..], &[PP2_TYPE_SSL, 0, 5, PP2_CLIENT_SSL, 0, 0, 0, 0]].concat(),
+            false => body,
+        };
+
+        // version 2 with the PROXY command
+        [&V2_SIGNATURE[..], &[0x21, family], &(body.len() as u16).to_be_bytes(), &body].concat()
+    }
+}
+
+fn parse_v1(buf: &[u8]) -> Result<ParsedProxyHeader, InvalidProxyHeader> {
+    let end = match buf.windows(2).take(V1_MAX_LEN - 1).position(|w| w == b"\r\n") {
+        Some(end) => end,
+        None if buf.len() < MIN_LEN => return Ok(ParsedProxyHeader::Incomplete(MIN_LEN - buf.len())),
+        None if buf.len() < V1_MAX_LEN => return Ok(ParsedProxyHeader::Incomplete(1)),
+        None => return Err(InvalidProxyHeader),
+    };
+
+    let line = std::str::from_utf8(&buf[..end]).map_err(|_| InvalidProxyHeader)?;
+    let mut parts = line.split(' ').skip(1);
+
+    let addresses = match parts.next() {
+        Some("UNKNOWN") => None,
+        Some(family @ "TCP4") | Some(family @ "TCP6") => {
+            let mut next = || parts.next().ok_or(InvalidProxyHeader);
+            let source_ip: IpAddr = next()?.parse().map_err(|_| InvalidProxyHeader)?;
+            let destination_ip: IpAddr = next()?.parse().map_err(|_| InvalidProxyHeader)?;
+            let source_port: u16 = next()?.parse().map_err(|_| InvalidProxyHeader)?;
+            let destination_port: u16 = next()?.parse().map_err(|_| InvalidProxyHeader)?;
+            if parts.next().is_some() {
+                return Err(InvalidProxyHeader);
+            }
+
+            if source_ip.is_ipv4() != (family == "TCP4") || destination_ip.is_ipv4() != (family == "TCP4") {
+                return Err(InvalidProxyHeader);
+            }
+
+            Some((
+                SocketAddr::new(source_ip, source_port),
+                SocketAddr::new(destination_ip, destination_port),
+            ))
+        }
+        _ => return Err(InvalidProxyHeader),
+    };
+
+    Ok(ParsedProxyHeader::Complete(ProxyHeader { addresses, tls: false }, end + 2))
+}
+
+fn parse_v2(buf: &[u8]) -> Result<ParsedProxyHeader, InvalidProxyHeader> {
+    if buf.len() < V2_FIXED_LEN {
+        return Ok(ParsedProxyHeader::Incomplete(V2_FIXED_LEN - buf.len()));
+    }
+
+    let version = buf[12] >> 4;
+    let command = buf[12] & 0x0f;
+    let family = buf[13];
+    let len = V2_FIXED_LEN + u16::from_be_bytes([buf[14], buf[15]]) as usize;
+
+    if version != 2 || command > 1 {
+        return Err(InvalidProxyHeader);
+    }
+    if buf.len() < len {
+        return Ok(ParsedProxyHeader::Incomplete(len - buf.len()));
+    }
+
+    let body = &buf[V2_FIXED_LEN..len];
+    let (addresses, tlvs) = match (command, family >> 4) {
+        // a LOCAL connection, i.e. a health check from the proxy itself
+        (0, _) => (None, &[][..]),
+        // tcp or udp over ipv4
+        (_, 0x1) if body.len() >= 12 => {
+            let ip = |at: usize| IpAddr::from(Ipv4Addr::new(body[at], body[at + 1], body[at + 2], body[at + 3]));
+            let addresses = (
+                SocketAddr::new(ip(0), port(body, 8)),
+                SocketAddr::new(ip(4), port(body, 10)),
+            );
+            (Some(addresses), &body[12..])
+        }
+        // tcp or udp over ipv6
+        (_, 0x2) if body.len() >= 36 => {
+            let ip = |at: usize| {
+                let mut octets = [0; 16];
+                octets.copy_from_slice(&body[at..at + 16]);
+                IpAddr::from(Ipv6Addr::from(octets))
+            };
+            let addresses = (
+                SocketAddr::new(ip(0), port(body, 32)),
+                SocketAddr::new(ip(16), port(body, 34)),
+            );
+            (Some(addresses), &body[36..])
+        }
+        (_, 0x1) | (_, 0x2) => return Err(InvalidProxyHeader),
+        // unspecified, with no addresses ahead of the tlvs
+        (_, 0x0) => (None, body),
+        // unix sockets
+        _ => (None, &[][..]),
+    };
+
+    let tls = ssl_client(tlvs).ok_or(InvalidProxyHeader)? & PP2_CLIENT_SSL != 0;
+    Ok(ParsedProxyHeader::Complete(ProxyHeader { addresses, tls }, len))
+}
+
+/// The `client` field of the ssl tlv, or 0 without one. None if the tlvs don't add up.
+fn ssl_client(mut tlvs: &[u8]) -> Option<u8> {
+    let mut client = 0;
+    while !tlvs.is_empty() {
+        let (kind, len) = match tlvs {
+            [kind, high, low, ..] => (*kind, u16::from_be_bytes([*high, *low]) as usize),
+            _ => return None,
+        };
+        let value = tlvs.get(3..3 + len)?;
+        if kind == PP2_TYPE_SSL {
+            client = *value.first()?;
+        }
+        tlvs = &tlvs[3 + len..];
+    }
+    Some(client)
+}
+
+fn port(body: &[u8], at: usize) -> u16 {
+    u16::from_be_bytes([body[at], body[at + 1]])
+}
+
+fn to_ipv6(ip: IpAddr) -> Ipv6Addr {
+    match ip {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+        IpAddr::V6(ip) => ip,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header(source: &str, destination: &str) -> ProxyHeader {
+        ProxyHeader {
+            addresses: Some((source.parse().unwrap(), destination.parse().unwrap())),
+            tls: false,
+        }
+    }
+
+    fn complete(buf: &[u8]) -> (ProxyHeader, usize) {
+        match ProxyHeader::parse(buf) {
+            Ok(ParsedProxyHeader::Complete(header, len)) => (header, len),
+            other => panic!("{:?}: {:?}", String::from_utf8_lossy(buf), other),
+        }
+    }
+
+    fn headers() -> Vec<ProxyHeader> {
+        vec![
+            header("192.168.0.1:56324", "192.168.0.11:443"),
+            header("[2001:db8::1]:56324", "[2001:db8::2]:443"),
+            ProxyHeader { addresses: None, tls: false },
+        ]
+    }
+
+    #[test]
+    fn round_trips_both_versions() {
+        for header in headers() {
+            for encoded in [header.to_v1(), header.to_v2()] {
+                let mut buf = encoded.clone();
+                buf.extend_from_slice(b"GET / HTTP/1.1\r\n");
+                assert_eq!(complete(&buf), (header, encoded.len()));
+            }
+        }
+    }
+
+    #[test]
+    fn v2_says_whether_the_client_used_tls() {
+        for mut header in headers() {
+            header.tls = true;
+            let encoded = header.to_v2();
+            assert_eq!(complete(&encoded), (header, encoded.len()));
+            // v1 has no way to say so
+            assert!(!complete(&header.to_v1()).0.tls);
+        }
+
+        // other tlvs are skipped, ones that run past the header are invalid
+        let mut v2 = header("192.168.0.1:56324", "192.168.0.11:443").to_v2();
+        v2[15] += 4;
+        v2.extend_from_slice(&[0x04, 0, 1, 0]);
+        assert!(!complete(&v2).0.tls);
+        v2[V2_FIXED_LEN + 14] = 2;
+        assert_eq!(ProxyHeader::parse(&v2), Err(InvalidProxyHeader));
+    }
+
+    #[test]
+    fn mixed_families_become_ipv6() {
+        let mixed = header("192.168.0.1:1000", "[2001:db8::2]:443");
+        let expected = header("[::ffff:192.168.0.1]:1000", "[2001:db8::2]:443");
+        assert_eq!(complete(&mixed.to_v1()).0, expected);
+        assert_eq!(complete(&mixed.to_v2()).0, expected);
+    }
+
+    #[test]
+    fn parses_the_spec_examples() {
+        let (parsed, len) = complete(b"PROXY TCP4 192.168.0.1 192.168.0.11 56324 443\r\nGET /");
+        assert_eq!(parsed, header("192.168.0.1:56324", "192.168.0.11:443"));
+        assert_eq!(len, 47);
+        assert_eq!(complete(b"PROXY UNKNOWN\r\n"), (ProxyHeader { addresses: None, tls: false }, 15));
+        // anything may follow UNKNOWN
+        assert_eq!(complete(b"PROXY UNKNOWN ffff::1 ffff::2 1 2\r\n").0.addresses, None);
+
+        // a LOCAL v2 health check, addresses or not
+        let mut local = header("192.168.0.1:1", "192.168.0.11:2").to_v2();
+        local[12] = 0x20;
+        assert_eq!(complete(&local).0.addresses, None);
+    }
+
+    #[test]
+    fn truncated_headers_ask_for_no_more_than_they_need() {
+        for header in headers() {
+            for encoded in [header.to_v1(), header.to_v2()] {
+                for read in 0..encoded.len() {
+                    match ProxyHeader::parse(&encoded[..read]) {
+                        Ok(ParsedProxyHeader::Incomplete(more)) => {
+                            assert!(more > 0 && read + more <= encoded.len(), "{} + {} of {:?}", read, more, encoded)
+                        }
+                        other => panic!("{} bytes of {:?}: {:?}", read, encoded, other),
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn rejects_malformed_headers() {
+        let invalid = |buf: &[u8]| ProxyHeader::parse(buf) == Err(InvalidProxyHeader);
+
+        assert!(invalid(b"GET / HTTP/1.1\r\n"));
+        assert!(invalid(b"PROXY UDP4 192.168.0.1 192.168.0.11 56324 443\r\n"));
+        assert!(invalid(b"PROXY TCP4 192.168.0.1 192.168.0.11 56324\r\n"));
+        assert!(invalid(b"PROXY TCP4 192.168.0.1 192.168.0.11 56324 443 0\r\n"));
+        assert!(invalid(b"PROXY TCP4 192.168.0.1 192.168.0.11 56324 65536\r\n"));
+        assert!(invalid(b"PROXY TCP4 2001:db8::1 192.168.0.11 56324 443\r\n"));
+        assert!(invalid(b"PROXY TCP6 192.168.0.1 192.168.0.11 56324 443\r\n"));
+        assert!(invalid(b"PROXY TCP4 192.168.0.1  192.168.0.11 56324 443\r\n"));
+        assert!(invalid(b"PROXY \xff\r\n"));
+        // no line ending within the longest a v1 header may be
+        assert!(invalid(format!("PROXY TCP4 {}", "1".repeat(V1_MAX_LEN)).as_bytes()));
+
+        let v2 = header("192.168.0.1:56324", "192.168.0.11:443").to_v2();
+        let mut version_one = v2.clone();
+        version_one[12] = 0x11;
+        assert!(invalid(&version_one));
+        let mut unknown_command = v2.clone();
+        unknown_command[12] = 0x22;
+        assert!(invalid(&unknown_command));
+        // an ipv4 body too short for its addresses
+        let mut short = v2[..V2_FIXED_LEN].to_vec();
+        short[15] = 4;
+        short.extend_from_slice(&[0; 4]);
+        assert!(invalid(&short));
+    }
+}
@@ -0,0 +1,273 @@
+use super::{write_private, AcmeConfig, Error};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine as _};
+use ring::digest::{digest, SHA256};
+use ring::rand::SystemRandom;
+use ring::signature::{EcdsaKeyPair, KeyPair, ECDSA_P256_SHA256_FIXED_SIGNING};
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+/// how many times we retry a request the server rejected for a stale nonce
+const MAX_NONCE_RETRIES: usize = 3;
+
+/// The endpoints an acme server advertises
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Directory {
+    new_nonce: String,
+    new_account: String,
+    new_order: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Order {
+    pub status: Status,
+    pub authorizations: Vec<String>,
+    pub finalize: String,
+    pub certificate: Option<String>,
+    pub error: Option<Value>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Authorization {
+    pub identifier: Identifier,
+    pub status: Status,
+    pub challenges: Vec<Challenge>,
+    #[serde(default)]
+    pub wildcard: bool,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Identifier {
+    pub value: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Challenge {
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub url: String,
+    pub token: String,
+    pub error: Option<Value>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    Pending,
+    Ready,
+    Processing,
+    Valid,
+    Invalid,
+    Expired,
+    Deactivated,
+    Revoked,
+}
+
+/// An error document from the acme server
+#[derive(Debug, Deserialize)]
+struct Problem {
+    #[serde(rename = "type", default)]
+    kind: String,
+    #[serde(default)]
+    detail: String,
+}
+
+/// Our registered account with an acme server, signing every request with its key
+pub struct Account {
+    http: reqwest::Client,
+    directory: Directory,
+    key: EcdsaKeyPair,
+    rng: SystemRandom,
+    /// the account url, identifying us once registered
+    kid: Option<String>,
+    nonce: Option<String>,
+}
+
+impl Account {
+    /// Register with the acme server, reusing the account key in our cache if we have one
+    pub async fn load_or_create(config: &AcmeConfig) -> Result<Self, Error> {
+        let rng = SystemRandom::new();
+        let path = config.cache_dir.join("account.pk8");
+        let pkcs8 = match std::fs::read(&path) {
+            Ok(pkcs8) => pkcs8,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                let pkcs8 = EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &rng)
+                    .map_err(|_| Error::Crypto)?;
+                std::fs::create_dir_all(&config.cache_dir)
+                    .map_err(|e| Error::Io(config.cache_dir.clone(), e))?;
+                write_private(&path, pkcs8.as_ref())?;
+                pkcs8.as_ref().to_vec()
+            }
+            Err(e) => return Err(Error::Io(path, e)),
+        };
+        let key = EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &pkcs8, &rng)
+            .map_err(|_| Error::Crypto)?;
+
+        let mut http = reqwest::Client::builder();
+        if let Some(ca_file) = &config.ca_file {
+            let pem = std::fs::read(ca_file).map_err(|e| Error::Io(ca_file.clone(), e))?;
+            http = http.add_root_certificate(reqwest::Certificate::from_pem(&pem)?);
+        }
+        let http = http.build()?;
+
+        let directory = http
+            .get(&config.directory_url)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+
+        let mut account = Account {
+            http,
+            directory,
+            key,
+            rng,
+            kid: None,
+            nonce: None,
+        };
+
+        let contact: Vec<String> = config
+            .contact_email
+            .iter()
+            .map(|email| format!("mailto:{}", email))
+            .collect();
+        let new_account = account.directory.new_account.clone();
+        let response = account
+            .post(
+                &new_account,
+                Some(json!({ "termsOfServiceAgreed": true, "contact": contact })),
+            )
+            .await?;
+        account.kid = Some(location(&response)?);
+
+        Ok(account)
+    }
+
+    /// Place an order for a certificate covering `names`, returning its url
+    pub async fn new_order(&mut self, names: &[String]) -> Result<(String, Order), Error> {
+        let identifiers: Vec<Value> = names
+            .iter()
+            .map(|name| json!({ "type": "dns", "value": name }))
+            .collect();
+        let new_order = self.directory.new_order.clone();
+        let response = self
+            .post(&new_order, Some(json!({ "identifiers": identifiers })))
+            .await?;
+        let url = location(&response)?;
+        Ok((url, response.json().await?))
+    }
+
+    /// Fetch an acme resource
+    pub async fn get<T: DeserializeOwned>(&mut self, url: &str) -> Result<T, Error> {
+        Ok(self.post(url, None).await?.json().await?)
+    }
+
+    /// Post a json payload to an acme resource
+    pub async fn send<T: DeserializeOwned>(&mut self, url: &str, payload: Value) -> Result<T, Error> {
+        Ok(self.post(url, Some(payload)).await?.json().await?)
+    }
+
+    /// Download an issued certificate chain as pem
+    pub async fn download(&mut self, url: &str) -> Result<String, Error> {
+        Ok(self.post(url, None).await?.text().await?)
+    }
+
+    /// The key authorization proving we control the account for a challenge token
+    pub fn key_authorization(&self, token: &str) -> String {
+        let thumbprint = digest(&SHA256, self.jwk().to_string().as_bytes());
+        format!("{}.{}", token, URL_SAFE_NO_PAD.encode(thumbprint))
+    }
+
+    /// Sign and send a request. A `None` payload is a POST-as-GET.
+    async fn post(&mut self, url: &str, payload: Option<Value>) -> Result<reqwest::Response, Error> {
+        let payload = match payload {
+            Some(payload) => URL_SAFE_NO_PAD.encode(payload.to_string()),
+            None => String::new(),
+        };
+
+        let mut retries = 0;
+        loop {
+            let nonce = match self.nonce.take() {
+                Some(nonce) => nonce,
+                None => self.new_nonce().await?,
+            };
+
+            let mut protected = json!({ "alg": "ES256", "nonce": nonce, "url": url });
+            match &self.kid {
+                Some(kid) => protected["kid"] = json!(kid),
+                None => protected["jwk"] = self.jwk(),
+            }
+            let protected = URL_SAFE_NO_PAD.encode(protected.to_string());
+            let signature = self
+                .key
+                .sign(&self.rng, format!("{}.{}", protected, payload).as_bytes())
+                .map_err(|_| Error::Crypto)?;
+            let body = json!({
+                "protected": protected,
+                "payload": payload,
+                "signature": URL_SAFE_NO_PAD.encode(signature),
+            });
+
+            let response = self
+                .http
+                .post(url)
+                .header("content-type", "application/jose+json")
+                .body(body.to_string())
+                .send()
+                .await?;
+            self.nonce = replay_nonce(&response);
+
+            if response.status().is_success() {
+                return Ok(response);
+            }
+
+            let problem: Problem = response.json().await?;
+            if problem.kind.ends_with(":badNonce") && retries < MAX_NONCE_RETRIES {
+                retries += 1;
+                continue;
+            }
+            return Err(Error::Problem(problem.kind, problem.detail));
+        }
+    }
+
+    async fn new_nonce(&self) -> Result<String, Error> {
+        let response = self
+            .http
+            .head(&self.directory.new_nonce)
+            .send()
+            .await?
+            .error_for_status()?;
+        replay_nonce(&response).ok_or(Error::MissingHeader("Replay-Nonce"))
+    }
+
+    /// Our public key, with members in the order required for its thumbprint
+    fn jwk(&self) -> Value {
+        // an uncompressed point: 0x04 || x || y
+        let point = self.key.public_key().as_ref();
+        json!({
+            "crv": "P-256",
+            "kty": "EC",
+            "x": URL_SAFE_NO_PAD.encode(&point[1..33]),
+            "y": URL_SAFE_NO_PAD.encode(&point[33..65]),
+        })
+    }
+}
+
+fn replay_nonce(response: &reqwest::Response) -> Option<String> {
+    response
+        .headers()
+        .get("replay-nonce")
+        .and_then(|v| v.to_str().ok())
+        .map(String::from)
+}
+
+fn location(response: &reqwest::Response) -> Result<String, Error> {
+    response
+        .headers()
+        .get("location")
+        .and_then(|v| v.to_str().ok())
+        .map(String::from)
+        .ok_or(Error::MissingHeader("Location"))
+}
@@ -0,0 +1,661 @@
+use crate::{tls, CONFIG};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine as _};
+use dashmap::{DashMap, DashSet};
+use lazy_static::lazy_static;
+use ring::digest::{digest, SHA256};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use thiserror::Error;
+
+mod client;
+use self::client::{Account, Authorization, Order, Status};
+
+/// where http-01 challenges are fetched from
+const HTTP_CHALLENGE_PATH: &str = "/.well-known/acme-challenge/";
+/// renew certificates this long before they expire
+const RENEW_BEFORE: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+/// how often we check whether our certificates need renewing
+const CHECK_INTERVAL: Duration = Duration::from_secs(12 * 60 * 60);
+/// how soon we try again after failing to issue a certificate
+const RETRY_INTERVAL: Duration = Duration::from_secs(15 * 60);
+/// how often, and how many times, we poll the acme server while it works
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+const MAX_POLLS: usize = 60;
+
+lazy_static! {
+    /// key authorizations for the http-01 challenges we're answering, by token
+    static ref HTTP_CHALLENGES: DashMap<String, String> = DashMap::new();
+    /// the verified custom domains of our clients, kept issued over http-01 alongside our allowed hosts
+    static ref CUSTOM_DOMAINS: DashSet<String> = DashSet::new();
+    /// the hosts we're ordering a certificate for right now
+    static ref ISSUING: DashSet<String> = DashSet::new();
+}
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("IOError at {0:?}: {1}")]
+    Io(PathBuf, std::io::Error),
+
+    #[error("RequestError: {0}")]
+    Request(#[from] reqwest::Error),
+
+    #[error("Acme server error {0}: {1}")]
+    Problem(String, String),
+
+    #[error("Acme response is missing the {0} header")]
+    MissingHeader(&'static str),
+
+    #[error("Failed to sign with the account key")]
+    Crypto,
+
+    #[error("Failed to create the certificate request: {0}")]
+    Csr(#[from] rcgen::Error),
+
+    #[error("Invalid issued certificate: {0}")]
+    Certificate(#[from] tls::Error),
+
+    #[error("DNS hook failed: {0}")]
+    Hook(String),
+
+    #[error("No supported challenge for {0}")]
+    NoChallenge(String),
+
+    #[error("Authorization for {0} failed: {1}")]
+    Invalid(String, String),
+
+    #[error("Timed out waiting for the acme server")]
+    Timeout,
+}
+
+/// Issue certificates for our allowed hosts and our clients' custom domains from an acme directory
+pub struct AcmeConfig {
+    pub directory_url: String,
+    pub contact_email: Option<String>,
+    /// program called as `<hook> set|clear <record name> <value>` to manage dns-01 TXT records
+    pub dns_hook: PathBuf,
+    /// how long we wait for TXT records to be visible to the acme server
+    pub dns_propagation: Duration,
+    /// where the account key and issued certificates are kept
+    pub cache_dir: PathBuf,
+    /// an extra root to trust the acme server with (i.e. pebble's)
+    pub ca_file: Option<PathBuf>,
+}
+
+/// The key authorization for an http-01 challenge request path, if we're answering one
+pub fn http_challenge(path: &str) -> Option<String> {
+    let token = path.strip_prefix(HTTP_CHALLENGE_PATH)?;
+    HTTP_CHALLENGES.get(token).map(|k| k.value().clone())
+}
+
+/// Serve our cached certificates and keep them issued and renewed
+pub fn spawn(config: &'static AcmeConfig) {
+    for host in CONFIG.allowed_hosts.iter() {
+        load_cached(config, host);
+    }
+
+    tokio::spawn(async move {
+        loop {
+            let next_check = match renew(config).await {
+                Ok(_) => CHECK_INTERVAL,
+                Err(error) => {
+                    tracing::error!(%error, "failed to renew acme certificates");
+                    RETRY_INTERVAL
+                }
+            };
+            tokio::time::sleep(next_check).await;
+        }
+    });
+}
+
+/// Serve a certificate for a client's verified custom domain from now on, issuing it over http-01 unless we have it cached
+pub fn add_custom_domain(host: &str) {
+    let config = match CONFIG.acme.as_ref() {
+        Some(config) => config,
+        None => return,
+    };
+    if !CUSTOM_DOMAINS.insert(host.to_string()) {
+        return;
+    }
+
+    load_cached(config, host);
+    if !needs_renewal(config, host) {
+        return;
+    }
+
+    let host = host.to_string();
+    tokio::spawn(async move {
+        tracing::info!(%host, "issuing acme certificate for custom domain");
+        let result = match Account::load_or_create(config).await {
+            Ok(mut account) => issue(config, &mut account, &host).await,
+            Err(error) => Err(error),
+        };
+        match result {
+            Ok(_) => tracing::info!(%host, "issued acme certificate"),
+            // the next renewal check tries again
+            Err(error) => tracing::error!(%host, %error, "failed to issue acme certificate"),
+        }
+    });
+}
+
+/// Stop renewing the certificate of a custom domain none of our clients route any more.
+/// We keep serving it until we restart, in case a client brings the domain back.
+pub fn remove_custom_domain(host: &str) {
+    if CUSTOM_DOMAINS.remove(host).is_some() {
+        tracing::debug!(%host, "no more clients on custom domain");
+    }
+}
+
+fn load_cached(config: &AcmeConfig, host: &str) {
+    let (cert, key) = cache_files(config, host);
+    match tls::load(&cert, &key) {
+        Ok(certified_key) => {
+            tls::add_certificate(&names(host), certified_key);
+            tracing::info!(%host, "loaded cached acme certificate");
+        }
+        Err(error) => tracing::debug!(%host, %error, "no cached acme certificate"),
+    }
+}
+
+/// Issue a certificate for every host that's missing one or expiring soon
+async fn renew(config: &AcmeConfig) -> Result<(), Error> {
+    let mut account = None;
+    let mut result = Ok(());
+
+    let custom_domains: Vec<String> = CUSTOM_DOMAINS.iter().map(|host| host.clone()).collect();
+    for host in CONFIG.allowed_hosts.iter().chain(&custom_domains) {
+        if !needs_renewal(config, host) {
+            continue;
+        }
+
+        let account = match account.as_mut() {
+            Some(account) => account,
+            None => account.insert(Account::load_or_create(config).await?),
+        };
+
+        tracing::info!(%host, "issuing acme certificate");
+        match issue(config, account, host).await {
+            Ok(_) => tracing::info!(%host, "issued acme certificate"),
+            Err(error) => {
+                tracing::error!(%host, %error, "failed to issue acme certificate");
+                result = Err(error);
+            }
+        }
+    }
+
+    result
+}
+
+/// an allowed host and all of its tunnel sub-domains, or a custom domain on its own
+fn names(host: &str) -> Vec<String> {
+    if CONFIG.allowed_hosts.iter().any(|allowed| allowed == host) {
+        vec![host.to_string(), format!("*.{}", host)]
+    } else {
+        vec![host.to_string()]
+    }
+}
+
+fn cache_files(config: &AcmeConfig, host: &str) -> (PathBuf, PathBuf) {
+    let dir = config.cache_dir.join(host);
+    (dir.join("cert.pem"), dir.join("key.pem"))
+}
+
+fn needs_renewal(config: &AcmeConfig, host: &str) -> bool {
+    let (cert, key) = cache_files(config, host);
+    let certified_key = match tls::load(&cert, &key) {
+        Ok(certified_key) => certified_key,
+        Err(_) => return true,
+    };
+
+    let not_after = match x509_parser::parse_x509_certificate(&certified_key.cert[0]) {
+        Ok((_, cert)) => cert.validity().not_after.timestamp(),
+        Err(_) => return true,
+    };
+    let renew_at = not_after - RENEW_BEFORE.as_secs() as i64;
+    chrono::Utc::now().timestamp() >= renew_at
+}
+
+/// Order, authorize and download a certificate for `host`, then start serving it
+async fn issue(config: &AcmeConfig, account: &mut Account, host: &str) -> Result<(), Error> {
+    // a custom domain's first order may still be running when we check for renewals
+    if !ISSUING.insert(host.to_string()) {
+        return Ok(());
+    }
+    let result = order(config, account, host).await;
+    ISSUING.remove(host);
+    result
+}
+
+async fn order(config: &AcmeConfig, account: &mut Account, host: &str) -> Result<(), Error> {
+    let names = names(host);
+    let (order_url, order) = account.new_order(&names).await?;
+
+    let mut authorizations = vec![];
+    for url in order.authorizations.iter() {
+        let authorization: Authorization = account.get(url).await?;
+        if authorization.status != Status::Valid {
+            authorizations.push((url.clone(), authorization));
+        }
+    }
+
+    // always take down our challenges, whether or not they passed
+    let mut cleanup = vec![];
+    let authorized = authorize(config, account, &authorizations, &mut cleanup).await;
+    for challenge in cleanup {
+        if let Err(error) = challenge.clear(config).await {
+            tracing::warn!(%error, "failed to clear acme challenge");
+        }
+    }
+    authorized?;
+
+    // the key never leaves our cache, the server only sees the request
+    let key_pair = rcgen::KeyPair::generate()?;
+    let csr = rcgen::CertificateParams::new(names.clone())?.serialize_request(&key_pair)?;
+    let order: Order = account
+        .send(
+            &order.finalize,
+            serde_json::json!({ "csr": URL_SAFE_NO_PAD.encode(csr.der()) }),
+        )
+        .await?;
+
+    let order = poll_order(account, &order_url, order).await?;
+    let certificate_url = order
+        .certificate
+        .ok_or_else(|| Error::Problem("missing certificate".into(), order_url.clone()))?;
+    let chain = account.download(&certificate_url).await?;
+
+    let (cert, key) = cache_files(config, host);
+    let dir = config.cache_dir.join(host);
+    std::fs::create_dir_all(&dir).map_err(|e| Error::Io(dir, e))?;
+    write_private(&key, key_pair.serialize_pem().as_bytes())?;
+    std::fs::write(&cert, chain).map_err(|e| Error::Io(cert.clone(), e))?;
+
+    tls::add_certificate(&names, tls::load(&cert, &key)?);
+    Ok(())
+}
+
+/// Write a file only we may read, i.e. a private key
+fn write_private(path: &Path, contents: &[u8]) -> Result<(), Error> {
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+
+    let write = || -> std::io::Result<()> {
+        let mut file = options.open(path)?;
+        // the mode only applies to new files, tighten one left behind by an older version too
+        #[cfg(unix)]
+        file.set_permissions(std::os::unix::fs::PermissionsExt::from_mode(0o600))?;
+        file.write_all(contents)
+    };
+    write().map_err(|e| Error::Io(path.into(), e))
+}
+
+/// A challenge we've put up and need to take down again
+enum PendingChallenge {
+    Dns { name: String, value: String },
+    Http { token: String },
+}
+
+impl PendingChallenge {
+    async fn clear(self, config: &AcmeConfig) -> Result<(), Error> {
+        match self {
+            PendingChallenge::Dns { name, value } => run_hook(config, "clear", &name, &value).await,
+            PendingChallenge::Http { token } => {
+                HTTP_CHALLENGES.remove(&token);
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Answer a challenge for each authorization and wait for the server to accept them
+async fn authorize(
+    config: &AcmeConfig,
+    account: &mut Account,
+    authorizations: &[(String, Authorization)],
+    cleanup: &mut Vec<PendingChallenge>,
+) -> Result<(), Error> {
+    let mut challenges = vec![];
+    let mut uses_dns = false;
+
+    for (url, authorization) in authorizations {
+        let domain = &authorization.identifier.value;
+        // wildcards can only be proven over dns, and our own hosts have the hook to do it
+        let kind = if authorization.wildcard || CONFIG.allowed_hosts.contains(domain) {
+            "dns-01"
+        } else {
+            "http-01"
+        };
+        let challenge = authorization
+            .challenges
+            .iter()
+            .find(|c| c.kind == kind)
+            .ok_or_else(|| Error::NoChallenge(domain.clone()))?;
+
+        let key_authorization = account.key_authorization(&challenge.token);
+        if kind == "dns-01" {
+            let name = format!("_acme-challenge.{}", domain);
+            let value = URL_SAFE_NO_PAD.encode(digest(&SHA256, key_authorization.as_bytes()));
+            run_hook(config, "set", &name, &value).await?;
+            cleanup.push(PendingChallenge::Dns { name, value });
+            uses_dns = true;
+        } else {
+            HTTP_CHALLENGES.insert(challenge.token.clone(), key_authorization);
+            cleanup.push(PendingChallenge::Http {
+                token: challenge.token.clone(),
+            });
+        }
+        challenges.push((url, challenge.url.clone()));
+    }
+
+    if uses_dns {
+        tracing::info!(wait = ?config.dns_propagation, "waiting for acme dns records to propagate");
+        tokio::time::sleep(config.dns_propagation).await;
+    }
+
+    for (url, challenge_url) in challenges {
+        let _: serde_json::Value = account.send(&challenge_url, serde_json::json!({})).await?;
+        poll_authorization(account, url).await?;
+    }
+
+    Ok(())
+}
+
+async fn poll_authorization(account: &mut Account, url: &str) -> Result<(), Error> {
+    for _ in 0..MAX_POLLS {
+        let authorization: Authorization = account.get(url).await?;
+        match authorization.status {
+            Status::Valid => return Ok(()),
+            Status::Pending | Status::Processing => {}
+            _ => {
+                let error = authorization
+                    .challenges
+                    .iter()
+                    .find_map(|c| c.error.as_ref())
+                    .map(|e| e.to_string())
+                    .unwrap_or_default();
+                return Err(Error::Invalid(authorization.identifier.value, error));
+            }
+        }
+        tokio::time::sleep(POLL_INTERVAL).await;
+    }
+    Err(Error::Timeout)
+}
+
+async fn poll_order(account: &mut Account, url: &str, mut order: Order) -> Result<Order, Error> {
+    for _ in 0..MAX_POLLS {
+        match order.status {
+            Status::Valid => return Ok(order),
+            Status::Pending | Status::Ready | Status::Processing => {}
+            _ => {
+                let error = order.error.map(|e| e.to_string()).unwrap_or_default();
+                return Err(Error::Problem("order failed".into(), error));
+            }
+        }
+        tokio::time::sleep(POLL_INTERVAL).await;
+        order = account.get(url).await?;
+    }
+    Err(Error::Timeout)
+}
+
+async fn run_hook(config: &AcmeConfig, action: &str, name: &str, value: &str) -> Result<(), Error> {
+    let output = tokio::process::Command::new(&config.dns_hook)
+        .args([action, name, value])
+        .output()
+        .await
+        .map_err(|e| Error::Io(config.dns_hook.clone(), e))?;
+
+    if !output.status.success() {
+        return Err(Error::Hook(format!(
+            "{} {} exited with {}: {}",
+            action,
+            name,
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+    use std::sync::{Arc, Mutex};
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("neutun-acme-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn config(cache_dir: PathBuf) -> AcmeConfig {
+        AcmeConfig {
+            directory_url: "https://localhost:14000/dir".into(),
+            contact_email: None,
+            dns_hook: "/bin/false".into(),
+            dns_propagation: Duration::ZERO,
+            cache_dir,
+            ca_file: None,
+        }
+    }
+
+    /// Cache a self signed certificate for `host` that expires in `days`
+    fn cache_certificate(config: &AcmeConfig, host: &str, days: i64) {
+        let key_pair = rcgen::KeyPair::generate().unwrap();
+        let mut params = rcgen::CertificateParams::new(vec![host.to_string()]).unwrap();
+        let now = chrono::Utc::now();
+        params.not_before = rcgen::date_time_ymd(2020, 1, 1);
+        let not_after = now + chrono::Duration::days(days);
+        params.not_after = rcgen::date_time_ymd(
+            chrono::Datelike::year(&not_after),
+            chrono::Datelike::month(&not_after) as u8,
+            chrono::Datelike::day(&not_after) as u8,
+        );
+        let cert = params.self_signed(&key_pair).unwrap();
+
+        let (cert_file, key_file) = cache_files(config, host);
+        std::fs::create_dir_all(cert_file.parent().unwrap()).unwrap();
+        std::fs::write(cert_file, cert.pem()).unwrap();
+        write_private(&key_file, key_pair.serialize_pem().as_bytes()).unwrap();
+    }
+
+    #[test]
+    fn private_files_are_only_ours() {
+        let dir = temp_dir("private");
+        let path = dir.join("key.pem");
+        write_private(&path, b"secret").unwrap();
+        assert_eq!(std::fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+
+        // a key an older version left readable to everyone
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o644)).unwrap();
+        write_private(&path, b"new secret").unwrap();
+        assert_eq!(std::fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+        assert_eq!(std::fs::read(&path).unwrap(), b"new secret");
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn renews_missing_and_expiring_certificates() {
+        let config = config(temp_dir("renew"));
+        assert!(needs_renewal(&config, "missing.example.com"));
+
+        cache_certificate(&config, "fresh.example.com", 90);
+        assert!(!needs_renewal(&config, "fresh.example.com"));
+
+        cache_certificate(&config, "expiring.example.com", 10);
+        assert!(needs_renewal(&config, "expiring.example.com"));
+        std::fs::remove_dir_all(&config.cache_dir).unwrap();
+    }
+
+    /// An acme server that takes anyone's word for their account,
+    /// and checks http-01 challenges with us directly rather than over the network
+    #[derive(Default)]
+    struct StubAcme {
+        base: String,
+        orders: usize,
+        /// the names whose challenge we've checked
+        validated: Vec<String>,
+        certificate: Option<String>,
+    }
+
+    impl StubAcme {
+        async fn spawn() -> Arc<Mutex<StubAcme>> {
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let stub = Arc::new(Mutex::new(StubAcme {
+                base: format!("http://{}", listener.local_addr().unwrap()),
+                ..Default::default()
+            }));
+
+            let server = stub.clone();
+            tokio::spawn(async move {
+                while let Ok((socket, _)) = listener.accept().await {
+                    let stub = server.clone();
+                    let service = hyper::service::service_fn(move |request| {
+                        let stub = stub.clone();
+                        async move { Ok::<_, std::convert::Infallible>(StubAcme::handle(&stub, request).await) }
+                    });
+                    tokio::spawn(
+                        hyper::server::conn::http1::Builder::new()
+                            .serve_connection(hyper_util::rt::TokioIo::new(socket), service),
+                    );
+                }
+            });
+            stub
+        }
+
+        async fn handle(
+            stub: &Mutex<StubAcme>,
+            request: hyper::Request<hyper::body::Incoming>,
+        ) -> hyper::Response<http_body_util::Full<hyper::body::Bytes>> {
+            use http_body_util::BodyExt;
+
+            let path = request.uri().path().to_string();
+            let body = request.into_body().collect().await.unwrap().to_bytes();
+            // the payload of a signed request, nothing for a POST-as-GET
+            let payload: serde_json::Value = serde_json::from_slice(&body)
+                .ok()
+                .and_then(|jws: serde_json::Value| URL_SAFE_NO_PAD.decode(jws["payload"].as_str()?).ok())
+                .and_then(|payload| serde_json::from_slice(&payload).ok())
+                .unwrap_or_default();
+
+            let mut stub = stub.lock().unwrap();
+            let base = stub.base.clone();
+            let json = |value: serde_json::Value| value.to_string();
+            let (location, body) = match path.split('/').collect::<Vec<_>>()[1..] {
+                ["dir"] => (
+                    None,
+                    json(serde_json::json!({
+                        "newNonce": format!("{}/nonce", base),
+                        "newAccount": format!("{}/account", base),
+                        "newOrder": format!("{}/order", base),
+                    })),
+                ),
+                ["nonce"] => (None, String::new()),
+                ["account"] => (Some(format!("{}/account/1", base)), "{}".into()),
+                ["order"] => {
+                    stub.orders += 1;
+                    let name = payload["identifiers"][0]["value"].as_str().unwrap().to_string();
+                    (Some(format!("{}/order/{}", base, name)), json(stub.order(&name)))
+                }
+                ["authz", name] => {
+                    let status = if stub.validated.iter().any(|n| n == name) { "valid" } else { "pending" };
+                    let authorization = serde_json::json!({
+                        "identifier": { "type": "dns", "value": name },
+                        "status": status,
+                        "challenges": [{
+                            "type": "http-01",
+                            "url": format!("{}/challenge/{}", base, name),
+                            "token": format!("token-{}", name),
+                        }],
+                    });
+                    (None, json(authorization))
+                }
+                ["challenge", name] => {
+                    // we answer on port 80 for real, the key authorization is for the token and our account key
+                    let answer = http_challenge(&format!("{}token-{}", HTTP_CHALLENGE_PATH, name)).unwrap();
+                    assert!(answer.starts_with(&format!("token-{}.", name)));
+                    stub.validated.push(name.to_string());
+                    (None, "{}".into())
+                }
+                ["finalize", name] => {
+                    let csr = URL_SAFE_NO_PAD.decode(payload["csr"].as_str().unwrap()).unwrap();
+                    let csr = rcgen::CertificateSigningRequestParams::from_der(&csr.into()).unwrap();
+                    let ca_key = rcgen::KeyPair::generate().unwrap();
+                    let ca = rcgen::Issuer::new(rcgen::CertificateParams::new(vec![]).unwrap(), ca_key);
+                    stub.certificate = Some(csr.signed_by(&ca).unwrap().pem());
+
+                    let mut order = stub.order(name);
+                    order["status"] = "valid".into();
+                    order["certificate"] = format!("{}/certificate", base).into();
+                    (None, json(order))
+                }
+                ["certificate"] => (None, stub.certificate.clone().unwrap()),
+                _ => panic!("unexpected acme request for {}", path),
+            };
+
+            let mut response = hyper::Response::builder().header("replay-nonce", "nonce");
+            if let Some(location) = location {
+                response = response.status(201).header("location", location);
+            }
+            response.body(http_body_util::Full::from(body)).unwrap()
+        }
+
+        fn order(&self, name: &str) -> serde_json::Value {
+            serde_json::json!({
+                "status": "pending",
+                "authorizations": [format!("{}/authz/{}", self.base, name)],
+                "finalize": format!("{}/finalize/{}", self.base, name),
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn issues_and_renews_custom_domains_over_http() {
+        let stub = StubAcme::spawn().await;
+        let config = AcmeConfig {
+            directory_url: format!("{}/dir", stub.lock().unwrap().base),
+            ..config(temp_dir("issue"))
+        };
+        let host = "shop.acme-stub.test";
+
+        CUSTOM_DOMAINS.insert(host.to_string());
+        renew(&config).await.unwrap();
+        assert_eq!(stub.lock().unwrap().orders, 1);
+        assert_eq!(stub.lock().unwrap().validated, vec![host.to_string()]);
+        // the challenge is down again, the certificate and its key are ours
+        assert_eq!(http_challenge(&format!("{}token-{}", HTTP_CHALLENGE_PATH, host)), None);
+        assert!(!needs_renewal(&config, host));
+        let (_, key) = cache_files(&config, host);
+        assert_eq!(std::fs::metadata(&key).unwrap().permissions().mode() & 0o777, 0o600);
+
+        // nothing to do while the certificate is fresh, a new order once it expires soon
+        renew(&config).await.unwrap();
+        assert_eq!(stub.lock().unwrap().orders, 1);
+        cache_certificate(&config, host, 10);
+        renew(&config).await.unwrap();
+        assert_eq!(stub.lock().unwrap().orders, 2);
+
+        // a domain nobody routes any more isn't renewed
+        remove_custom_domain(host);
+        std::fs::remove_dir_all(config.cache_dir.join(host)).unwrap();
+        renew(&config).await.unwrap();
+        assert_eq!(stub.lock().unwrap().orders, 2);
+        std::fs::remove_dir_all(&config.cache_dir).unwrap();
+    }
+
+    #[test]
+    fn answers_only_pending_http_challenges() {
+        HTTP_CHALLENGES.insert("token1".into(), "token1.thumbprint".into());
+        assert_eq!(
+            http_challenge("/.well-known/acme-challenge/token1").as_deref(),
+            Some("token1.thumbprint")
+        );
+        assert_eq!(http_challenge("/.well-known/acme-challenge/token2"), None);
+        assert_eq!(http_challenge("/token1"), None);
+        HTTP_CHALLENGES.remove("token1");
+        assert_eq!(http_challenge("/.well-known/acme-challenge/token1"), None);
+    }
+}
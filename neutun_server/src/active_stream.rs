@@ -2,17 +2,27 @@
 pub struct ActiveStream {
     pub id: StreamId,
     pub client: ConnectedClient,
-    pub tx: UnboundedSender<StreamMessage>,
+    pub tx: Sender<StreamMessage>,
+    pub window: SendWindow,
+    /// what we know about the public connection, for the client
+    pub metadata: StreamMetadata,
+    /// keeps the stream counted against its client until the last clone is gone
+    _open: Arc<OpenStream>,
 }
 
 impl ActiveStream {
-    pub fn new(client: ConnectedClient) -> (Self, UnboundedReceiver<StreamMessage>) {
-        let (tx, rx) = unbounded();
+    pub fn new(client: ConnectedClient, metadata: StreamMetadata) -> (Self, Receiver<StreamMessage>) {
+        let (tx, rx) = channel(STREAM_QUEUE_SIZE);
+        let window = SendWindow::new(client.supports(Capability::FlowControl));
+        let open = Arc::new(client.stats.open_stream());
         (
             ActiveStream {
                 id: StreamId::generate(),
                 client,
                 tx,
+                window,
+                metadata,
+                _open: open,
             },
             rx,
         )
@@ -27,4 +37,6 @@ pub enum StreamMessage {
     Data(Vec<u8>),
     TunnelRefused,
     NoClientTunnel,
+    /// the client has nothing more to send
+    End,
 }
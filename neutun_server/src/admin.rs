@@ -0,0 +1,599 @@
+use super::*;
+use crate::auth::key_store::{self, ApiKey};
+use crate::auth::reservations;
+use chrono::{DateTime, Utc};
+use ipnet::IpNet;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use warp::http::StatusCode;
+use warp::reply::{Reply, Response};
+use warp::Rejection;
+
+/// Rejected for a missing or wrong admin api key
+#[derive(Debug)]
+struct Unauthorized;
+impl warp::reject::Reject for Unauthorized {}
+
+/// The admin api under `/api/admin`, authenticated with `Authorization: Bearer <ADMIN_API_KEY>`
+pub fn routes() -> impl Filter<Extract = (Response,), Error = Rejection> + Clone {
+    api(CONFIG.admin_key.clone())
+}
+
+fn api(admin_key: Option<String>) -> impl Filter<Extract = (Response,), Error = Rejection> + Clone {
+    let list_clients = warp::get().and(warp::path!("clients")).map(list_clients);
+    let disconnect = warp::delete()
+        .and(warp::path!("clients" / String))
+        .map(disconnect);
+
+    let list_blocked_ips = warp::get()
+        .and(warp::path!("blocked_ips"))
+        .map(list_blocked_ips);
+    let block_ip = warp::post()
+        .and(warp::path!("blocked_ips"))
+        .and(warp::body::json())
+        .map(block_ip);
+    let unblock_ip = warp::delete()
+        .and(warp::path!("blocked_ips" / IpAddr))
+        .map(|ip| unblock(IpNet::from(ip)));
+    let unblock_range = warp::delete()
+        .and(warp::path!("blocked_ips" / IpAddr / u8))
+        .map(|ip, prefix_len| match IpNet::new(ip, prefix_len) {
+            Ok(range) => unblock(range.trunc()),
+            Err(_) => error(StatusCode::BAD_REQUEST, "invalid prefix length"),
+        });
+
+    let list_keys = warp::get().and(warp::path!("keys")).map(list_keys);
+    let add_key = warp::post()
+        .and(warp::path!("keys"))
+        .and(warp::body::json())
+        .map(add_key);
+    let remove_key = warp::delete()
+        .and(warp::path!("keys" / String))
+        .map(remove_key);
+    let reset_usage = warp::delete()
+        .and(warp::path!("keys" / String / "usage"))
+        .map(reset_usage);
+
+    let list_reservations = warp::get()
+        .and(warp::path!("reservations"))
+        .map(list_reservations);
+    let reserve = warp::post()
+        .and(warp::path!("reservations"))
+        .and(warp::body::json())
+        .map(reserve);
+    let release = warp::delete()
+        .and(warp::path!("reservations" / String))
+        .map(release);
+    let release_many = warp::delete()
+        .and(warp::path!("reservations"))
+        .and(warp::query())
+        .map(release_many);
+
+    warp::path("api")
+        .and(warp::path("admin"))
+        .and(authorized(admin_key))
+        .and(
+            list_clients
+                .or(disconnect)
+                .unify()
+                .or(list_blocked_ips)
+                .unify()
+                .or(block_ip)
+                .unify()
+                .or(unblock_ip)
+                .unify()
+                .or(unblock_range)
+                .unify()
+                .or(list_keys)
+                .unify()
+                .or(add_key)
+                .unify()
+                .or(remove_key)
+                .unify()
+                .or(reset_usage)
+                .unify()
+                .or(list_reservations)
+                .unify()
+                .or(reserve)
+                .unify()
+                .or(release)
+                .unify()
+                .or(release_many)
+                .unify(),
+        )
+        .recover(handle_rejection)
+        .unify()
+}
+
+/// Prometheus metrics at `/metrics`, behind the admin api key too
+pub fn metrics() -> impl Filter<Extract = (Response,), Error = Rejection> + Clone {
+    metrics_with(CONFIG.admin_key.clone())
+}
+
+fn metrics_with(admin_key: Option<String>) -> impl Filter<Extract = (Response,), Error = Rejection> + Clone {
+    warp::get()
+        .and(warp::path("metrics"))
+        .and(warp::path::end())
+        .and(authorized(admin_key))
+        .map(|| metrics::render().into_response())
+        .recover(handle_rejection)
+        .unify()
+}
+
+/// Only let requests with the admin key through, the api doesn't exist without one
+fn authorized(admin_key: Option<String>) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    warp::header::optional::<String>("authorization")
+        .and_then(move |header: Option<String>| {
+            let admin_key = admin_key.clone();
+            async move {
+                let admin_key = admin_key.ok_or_else(warp::reject::not_found)?;
+                match header.as_deref().and_then(|h| h.strip_prefix("Bearer ")) {
+                    Some(token) if key_store::same_key(token, &admin_key) => Ok(()),
+                    _ => Err(warp::reject::custom(Unauthorized)),
+                }
+            }
+        })
+        .untuple_one()
+}
+
+async fn handle_rejection(rejection: Rejection) -> Result<Response, Rejection> {
+    if rejection.find::<Unauthorized>().is_some() {
+        return Ok(error(StatusCode::UNAUTHORIZED, "invalid admin api key"));
+    }
+    Err(rejection)
+}
+
+fn error(status: StatusCode, message: impl ToString) -> Response {
+    let body = serde_json::json!({ "error": message.to_string() });
+    warp::reply::with_status(warp::reply::json(&body), status).into_response()
+}
+
+fn ok<T: Serialize>(status: StatusCode, body: &T) -> Response {
+    warp::reply::with_status(warp::reply::json(body), status).into_response()
+}
+
+#[derive(Serialize)]
+struct ClientInfo {
+    id: ClientId,
+    host: String,
+    /// a host of its own, routed to it alongside `host`
+    custom_domain: Option<String>,
+    /// how its load balanced group spreads streams, if it's in one
+    group: Option<Balance>,
+    /// waiting to take over its host from another client
+    standby: bool,
+    /// it only takes the requests under this path
+    path_prefix: Option<String>,
+    kind: TunnelKind,
+    http2: bool,
+    ip: IpAddr,
+    connected_at: DateTime<Utc>,
+    key_name: Option<String>,
+    protocol_version: u32,
+    streams: usize,
+    /// bytes from the public, forwarded to the client
+    bytes_in: u64,
+    /// bytes from the client, forwarded to the public
+    bytes_out: u64,
+    /// the limits in effect, the tighter of the server's and the client's
+    rate_limits: RateLimits,
+}
+
+fn list_clients() -> Response {
+    let clients: Vec<ClientInfo> = CONNECTIONS
+        .get_all_clients()
+        .into_iter()
+        .map(|c| ClientInfo {
+            host: if c.wildcard {
+                format!("*.{}", c.domain)
+            } else {
+                c.full_host()
+            },
+            custom_domain: c.custom_domain.clone(),
+            group: c.group,
+            standby: Connections::is_standing_by(&c),
+            path_prefix: c.path_prefix.clone(),
+            kind: c.kind,
+            http2: c.http2,
+            ip: c.ip,
+            connected_at: c.connected_at,
+            key_name: c.key_name.clone(),
+            protocol_version: c.protocol_version,
+            streams: c.stats.streams(),
+            bytes_in: c.stats.bytes_in(),
+            bytes_out: c.stats.bytes_out(),
+            rate_limits: *c.rate_limiter.limits(),
+            id: c.id,
+        })
+        .collect();
+    ok(StatusCode::OK, &clients)
+}
+
+/// Drop every client matching `filter`, returning how many there were
+fn disconnect_where(filter: impl Fn(&ConnectedClient) -> bool) -> usize {
+    let clients: Vec<ConnectedClient> = CONNECTIONS
+        .get_all_clients()
+        .into_iter()
+        .filter(|c| filter(c))
+        .collect();
+
+    for client in clients.iter() {
+        tracing::info!(client_id = %client.id, host = %client.full_host(), "admin disconnecting client");
+        Connections::remove(client);
+    }
+    clients.len()
+}
+
+fn disconnect(client_id: String) -> Response {
+    match disconnect_where(|c| c.id.to_string() == client_id) {
+        0 => error(StatusCode::NOT_FOUND, "no such client"),
+        _ => ok(StatusCode::OK, &serde_json::json!({ "disconnected": client_id })),
+    }
+}
+
+fn list_blocked_ips() -> Response {
+    let blocked: Vec<String> = IP_FILTER.blocked().iter().map(ip_filter::display_range).collect();
+    ok(StatusCode::OK, &blocked)
+}
+
+#[derive(Deserialize)]
+struct BlockIp {
+    /// an ip address or a cidr range
+    ip: String,
+}
+
+fn block_ip(body: BlockIp) -> Response {
+    let range = match ip_filter::parse_range(&body.ip) {
+        Ok(range) => range,
+        Err(e) => return error(StatusCode::BAD_REQUEST, e),
+    };
+
+    IP_FILTER.block(range);
+    let range = ip_filter::display_range(&range);
+    tracing::info!(%range, "admin blocked ip");
+    let disconnected = disconnect_where(|c| !IP_FILTER.allows(c.ip));
+    ok(
+        StatusCode::OK,
+        &serde_json::json!({ "blocked": range, "disconnected": disconnected }),
+    )
+}
+
+fn unblock(range: IpNet) -> Response {
+    match IP_FILTER.unblock(&range) {
+        Some(true) => ok(
+            StatusCode::OK,
+            &serde_json::json!({ "unblocked": ip_filter::display_range(&range) }),
+        ),
+        Some(false) => error(StatusCode::NOT_FOUND, "ip is not blocked"),
+        None => error(
+            StatusCode::CONFLICT,
+            "ip is blocked by BLOCKED_IPS or IP_LISTS_FILE",
+        ),
+    }
+}
+
+#[derive(Serialize)]
+struct KeyInfo {
+    #[serde(flatten)]
+    key: ApiKey,
+    /// how many tunnels are open with the key right now
+    tunnels: usize,
+    /// bytes its tunnels transferred this month, counting against `monthly_quota_bytes`
+    bytes_this_month: u64,
+}
+
+fn list_keys() -> Response {
+    let keys: BTreeMap<String, KeyInfo> = KEYS
+        .list()
+        .into_iter()
+        .map(|(name, key)| {
+            let tunnels = Connections::count_for_key(&name);
+            let bytes_this_month = USAGE.get(&name);
+            (name, KeyInfo { key, tunnels, bytes_this_month })
+        })
+        .collect();
+    ok(StatusCode::OK, &keys)
+}
+
+#[derive(Deserialize)]
+struct NewKey {
+    name: String,
+    /// use this key rather than generating one
+    key: Option<String>,
+    #[serde(default)]
+    allowed_domains: Vec<String>,
+    #[serde(default)]
+    sub_domains: Vec<String>,
+    #[serde(default)]
+    wildcard: bool,
+    max_tunnels: Option<usize>,
+    max_bytes_per_sec: Option<u64>,
+    monthly_quota_bytes: Option<u64>,
+    #[serde(default)]
+    custom_domains: bool,
+}
+
+fn add_key(body: NewKey) -> Response {
+    if body.name.is_empty() {
+        return error(StatusCode::BAD_REQUEST, "a key needs a name");
+    }
+
+    let secret = body.key.unwrap_or_else(|| SecretKey::generate().0);
+    let key = ApiKey {
+        key_hash: key_store::hash_key(&secret),
+        allowed_domains: body.allowed_domains,
+        sub_domains: body.sub_domains,
+        wildcard: body.wildcard,
+        max_tunnels: body.max_tunnels,
+        max_bytes_per_sec: body.max_bytes_per_sec,
+        monthly_quota_bytes: body.monthly_quota_bytes,
+        custom_domains: body.custom_domains,
+    };
+
+    match KEYS.add(&body.name, key) {
+        Ok(_) => {
+            tracing::info!(name = %body.name, "admin added api key");
+            // the only time the key is ever shown
+            ok(
+                StatusCode::CREATED,
+                &serde_json::json!({ "name": body.name, "key": secret }),
+            )
+        }
+        Err(e @ key_store::Error::Exists(_)) => error(StatusCode::CONFLICT, e),
+        Err(e @ key_store::Error::NoKeysFile) => error(StatusCode::BAD_REQUEST, e),
+        Err(e) => error(StatusCode::INTERNAL_SERVER_ERROR, e),
+    }
+}
+
+fn remove_key(name: String) -> Response {
+    match KEYS.remove(&name) {
+        Ok(Some(key)) => {
+            tracing::info!(%name, "admin removed api key");
+            let disconnected = disconnect_where(|c| c.key_name.as_deref() == Some(name.as_str()));
+            // nobody could ever claim the key's sub-domains again otherwise
+            let released = match RESERVATIONS.release_owned_by(&key.key_hash) {
+                Ok(released) => released,
+                Err(e) => return error(StatusCode::INTERNAL_SERVER_ERROR, e),
+            };
+            ok(
+                StatusCode::OK,
+                &serde_json::json!({ "removed": name, "disconnected": disconnected, "released": released }),
+            )
+        }
+        Ok(None) => error(StatusCode::NOT_FOUND, "no such key"),
+        Err(e @ key_store::Error::NoKeysFile) => error(StatusCode::BAD_REQUEST, e),
+        Err(e) => error(StatusCode::INTERNAL_SERVER_ERROR, e),
+    }
+}
+
+fn reset_usage(name: String) -> Response {
+    if KEYS.get(&name).is_none() {
+        return error(StatusCode::NOT_FOUND, "no such key");
+    }
+
+    match USAGE.reset(&name) {
+        Ok(_) => {
+            tracing::info!(%name, "admin reset key usage");
+            ok(StatusCode::OK, &serde_json::json!({ "reset": name }))
+        }
+        Err(e) => error(StatusCode::INTERNAL_SERVER_ERROR, e),
+    }
+}
+
+#[derive(Serialize)]
+struct ReservationInfo {
+    #[serde(flatten)]
+    reservation: reservations::Reservation,
+    /// the name of the key holding it, if it's in the key store
+    key_name: Option<String>,
+}
+
+fn list_reservations() -> Response {
+    let keys = KEYS.list();
+    let reservations: BTreeMap<String, ReservationInfo> = RESERVATIONS
+        .list()
+        .into_iter()
+        .map(|(host, reservation)| {
+            let key_name = keys
+                .iter()
+                .find(|(_, k)| k.key_hash == reservation.owner)
+                .map(|(name, _)| name.clone());
+            (host, ReservationInfo { reservation, key_name })
+        })
+        .collect();
+    ok(StatusCode::OK, &reservations)
+}
+
+#[derive(Deserialize)]
+struct NewReservation {
+    /// the full host, i.e. `api.foo.bar`
+    host: String,
+    key_name: String,
+}
+
+fn reserve(body: NewReservation) -> Response {
+    let host = body.host.to_lowercase();
+    let on_allowed_host = CONFIG
+        .allowed_hosts
+        .iter()
+        .any(|allowed| host.strip_suffix(allowed.as_str()).is_some_and(|s| s.ends_with('.')));
+    if !on_allowed_host {
+        return error(StatusCode::BAD_REQUEST, "host is not a sub-domain of an allowed host");
+    }
+
+    let key = match KEYS.get(&body.key_name) {
+        Some(key) => key,
+        None => return error(StatusCode::NOT_FOUND, "no such key"),
+    };
+
+    match RESERVATIONS.reserve(&host, key.key_hash, None) {
+        Ok(_) => {
+            tracing::info!(%host, key = %body.key_name, "admin reserved sub-domain");
+            ok(
+                StatusCode::CREATED,
+                &serde_json::json!({ "reserved": host, "key_name": body.key_name }),
+            )
+        }
+        Err(e @ reservations::Error::ReservedByOther) => error(StatusCode::CONFLICT, e),
+        Err(e) => error(StatusCode::INTERNAL_SERVER_ERROR, e),
+    }
+}
+
+fn release(host: String) -> Response {
+    match RESERVATIONS.release(&host.to_lowercase()) {
+        Ok(Some(_)) => {
+            tracing::info!(%host, "admin released sub-domain");
+            ok(StatusCode::OK, &serde_json::json!({ "released": host }))
+        }
+        Ok(None) => error(StatusCode::NOT_FOUND, "no such reservation"),
+        Err(e) => error(StatusCode::INTERNAL_SERVER_ERROR, e),
+    }
+}
+
+/// Which reservations to release at once, exactly one of these
+#[derive(Deserialize)]
+struct ReleaseMany {
+    key_name: Option<String>,
+    /// the hash a reservation is held under, for keys that are gone or never were in the key store
+    owner: Option<String>,
+    #[serde(default)]
+    all: bool,
+}
+
+fn release_many(query: ReleaseMany) -> Response {
+    let owner = match (query.key_name, query.owner, query.all) {
+        (Some(key_name), None, false) => match KEYS.get(&key_name) {
+            Some(key) => Some(key.key_hash),
+            None => return error(StatusCode::NOT_FOUND, "no such key"),
+        },
+        (None, Some(owner), false) => Some(owner),
+        (None, None, true) => None,
+        _ => return error(StatusCode::BAD_REQUEST, "give one of key_name, owner or all=true"),
+    };
+
+    let released = match &owner {
+        Some(owner) => RESERVATIONS.release_owned_by(owner),
+        None => RESERVATIONS.release_where(|_| true),
+    };
+    match released {
+        Ok(released) => {
+            tracing::info!(?owner, count = released.len(), "admin released sub-domains");
+            ok(StatusCode::OK, &serde_json::json!({ "released": released }))
+        }
+        Err(e) => error(StatusCode::INTERNAL_SERVER_ERROR, e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use warp::test::request;
+
+    const ADMIN_KEY: &str = "admin-test-key";
+
+    async fn call(method: &str, path: &str, body: Option<serde_json::Value>) -> (StatusCode, serde_json::Value) {
+        let mut request = request()
+            .method(method)
+            .path(path)
+            .header("authorization", format!("Bearer {}", ADMIN_KEY));
+        if let Some(body) = body {
+            request = request.json(&body);
+        }
+        let response = request.reply(&api(Some(ADMIN_KEY.to_string()))).await;
+        let body = serde_json::from_slice(response.body()).unwrap_or_default();
+        (response.status(), body)
+    }
+
+    #[tokio::test]
+    async fn needs_the_admin_key() {
+        let api = api(Some(ADMIN_KEY.to_string()));
+        let status = |request: warp::test::RequestBuilder| {
+            let api = api.clone();
+            async move { request.reply(&api).await.status() }
+        };
+
+        assert_eq!(status(request().path("/api/admin/clients")).await, StatusCode::UNAUTHORIZED);
+        let wrong = request().path("/api/admin/clients").header("authorization", "Bearer admin-test-kez");
+        assert_eq!(status(wrong).await, StatusCode::UNAUTHORIZED);
+        let basic = request().path("/api/admin/clients").header("authorization", ADMIN_KEY);
+        assert_eq!(status(basic).await, StatusCode::UNAUTHORIZED);
+        assert_eq!(call("GET", "/api/admin/clients", None).await.0, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn does_not_exist_without_an_admin_key() {
+        let response = request()
+            .path("/api/admin/clients")
+            .header("authorization", "Bearer ")
+            .reply(&api(None))
+            .await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn metrics_need_the_admin_key_too() {
+        let metrics = metrics_with(Some(ADMIN_KEY.to_string()));
+        let response = request().path("/metrics").reply(&metrics).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        let response = request()
+            .path("/metrics")
+            .header("authorization", format!("Bearer {}", ADMIN_KEY))
+            .reply(&metrics)
+            .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(String::from_utf8_lossy(response.body()).contains("neutun_connected_clients"));
+
+        let response = request().path("/metrics").reply(&metrics_with(None)).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn blocks_and_unblocks_ips() {
+        let range = serde_json::json!({ "ip": "203.0.113.7/24" });
+        let (status, body) = call("POST", "/api/admin/blocked_ips", Some(range)).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["blocked"], "203.0.113.0/24");
+        assert!(!IP_FILTER.allows("203.0.113.9".parse().unwrap()));
+
+        let (_, blocked) = call("GET", "/api/admin/blocked_ips", None).await;
+        assert!(blocked.as_array().unwrap().contains(&"203.0.113.0/24".into()));
+
+        let invalid = serde_json::json!({ "ip": "203.0.113.0/33" });
+        assert_eq!(call("POST", "/api/admin/blocked_ips", Some(invalid)).await.0, StatusCode::BAD_REQUEST);
+
+        assert_eq!(call("DELETE", "/api/admin/blocked_ips/203.0.113.0/24", None).await.0, StatusCode::OK);
+        assert!(IP_FILTER.allows("203.0.113.9".parse().unwrap()));
+        assert_eq!(call("DELETE", "/api/admin/blocked_ips/203.0.113.0/24", None).await.0, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn disconnects_clients() {
+        let (client, _rx) = ConnectedClient::for_tests("admin-disconnect");
+        Connections::add(client.clone()).unwrap();
+
+        let (_, clients) = call("GET", "/api/admin/clients", None).await;
+        let listed = clients.as_array().unwrap().iter().find(|c| c["id"] == client.id.to_string());
+        assert_eq!(listed.unwrap()["host"], "admin-disconnect.example.com");
+
+        let path = format!("/api/admin/clients/{}", client.id);
+        assert_eq!(call("DELETE", &path, None).await.0, StatusCode::OK);
+        assert!(Connections::get(&client.id).is_none());
+        assert!(client.tx.is_closed());
+        assert_eq!(call("DELETE", &path, None).await.0, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn releases_reservations_in_bulk() {
+        let (status, body) = call("DELETE", "/api/admin/reservations?owner=nobody-holds-this", None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["released"], serde_json::json!([]));
+
+        // exactly one of them
+        assert_eq!(call("DELETE", "/api/admin/reservations", None).await.0, StatusCode::BAD_REQUEST);
+        let both = "/api/admin/reservations?owner=nobody-holds-this&all=true";
+        assert_eq!(call("DELETE", both, None).await.0, StatusCode::BAD_REQUEST);
+        let unknown = "/api/admin/reservations?key_name=no-such-key";
+        assert_eq!(call("DELETE", unknown, None).await.0, StatusCode::NOT_FOUND);
+    }
+}
@@ -1,9 +1,17 @@
 use crate::auth::reconnect_token::ReconnectTokenPayload;
+use crate::auth::key_store::{hash_key, ApiKey, KeyAccess};
 use crate::auth::{AuthResult, AuthService};
+use crate::auth::reservations::Reservations;
+use crate::connected_clients::{route, AddError, Connections, HostClaim};
+use crate::custom_domain;
+use crate::edge_policy::Policy;
 use crate::{ReconnectToken, CONFIG};
 use futures::{SinkExt, StreamExt};
 use tracing::error;
-use neutun_lib::{ClientHello, ClientId, ClientType, ServerHello};
+use neutun_lib::{
+    negotiate_capabilities, Balance, Capability, ClientHello, ClientId, ClientType, RateLimits,
+    ServerHello, TunnelKind, CAPABILITIES, PROTOCOL_VERSION,
+};
 use warp::filters::ws::{Message, WebSocket};
 
 pub struct ClientHandshake {
@@ -11,7 +19,42 @@ pub struct ClientHandshake {
     pub sub_domain: String,
     pub domain: String,
     pub is_anonymous: bool,
+    /// the key store name of the key the client authenticated with
+    pub key_name: Option<String>,
+    /// how many tunnels the client's key may have open at once
+    pub max_tunnels: Option<usize>,
     pub wildcard: bool,
+    pub protocol_version: u32,
+    pub capabilities: Vec<Capability>,
+    pub kind: TunnelKind,
+    pub tcp_port: Option<u16>,
+    /// the sub-domain is reserved for the client's key
+    pub reserved: bool,
+    /// requests should reach the client's local service over http/2
+    pub http2: bool,
+    /// add forwarding headers to the client's requests
+    pub forwarded_headers: bool,
+    /// who may use the client's tunnel
+    pub policy: Policy,
+    /// how hard the public may use the client's tunnel, before the server's own limits
+    pub rate_limits: RateLimits,
+    /// a host of the client's own, its dns proved the client's key owns it
+    pub custom_domain: Option<String>,
+    /// the hash of the api key the client authenticated with
+    pub owner: Option<String>,
+    /// how the load balanced group it shares its hosts with spreads streams
+    pub group: Option<Balance>,
+    /// wait while another client of its key has its hosts
+    pub standby: bool,
+    /// take only the requests under this path on its hosts
+    pub path_prefix: Option<String>,
+}
+
+/// Refuse a client's handshake with a failed server hello
+pub async fn reject(websocket: &mut WebSocket, hello: ServerHello) {
+    crate::metrics::handshake_failed(&hello);
+    let data = serde_json::to_vec(&hello).unwrap_or_default();
+    let _ = websocket.send(Message::binary(data)).await;
 }
 
 #[tracing::instrument(skip(websocket))]
@@ -39,19 +82,80 @@ async fn auth_client(
         Ok(ch) => ch,
         Err(error) => {
             error!(?error, "invalid client hello");
-            let data = serde_json::to_vec(&ServerHello::AuthFailed).unwrap_or_default();
-            let _ = websocket.send(Message::binary(data)).await;
+            reject(&mut websocket, ServerHello::AuthFailed).await;
+            return None;
+        }
+    };
+
+    // refuse clients that are too old, and downgrade newer ones to our version
+    if client_hello.protocol_version < CONFIG.min_protocol_version {
+        error!(
+            client_version = client_hello.protocol_version,
+            "invalid client hello: unsupported protocol version"
+        );
+        reject(&mut websocket, ServerHello::IncompatibleVersion {
+            min_version: CONFIG.min_protocol_version,
+            max_version: PROTOCOL_VERSION,
+        }).await;
+        return None;
+    }
+    let protocol_version = client_hello.protocol_version.min(PROTOCOL_VERSION);
+    let capabilities = negotiate_capabilities(CAPABILITIES, &client_hello.capabilities);
+
+    if client_hello.kind == TunnelKind::Tcp {
+        let problem = if CONFIG.tcp_ports.is_none() {
+            Some("TCP tunnels are not enabled on this server")
+        } else if client_hello.wildcard {
+            Some("TCP tunnels cannot be wildcards")
+        } else {
+            None
+        };
+
+        if let Some(problem) = problem {
+            error!("invalid client hello: {}", problem);
+            reject(&mut websocket, ServerHello::Error(problem.into())).await;
+            return None;
+        }
+    }
+
+    let policy = match Policy::new(&client_hello.policy, client_hello.kind) {
+        Ok(policy) => policy,
+        Err(error) => {
+            error!(%error, "invalid client hello: bad edge policy");
+            reject(&mut websocket, ServerHello::Error(error.to_string())).await;
             return None;
         }
     };
 
+    let rate_limits = client_hello.rate_limits;
+    let problem = if rate_limits.requests.is_some() || rate_limits.requests_per_ip.is_some() {
+        (client_hello.kind != TunnelKind::Http).then_some("Request rate limits only apply to HTTP tunnels")
+    } else {
+        None
+    };
+    let problem = problem.or_else(|| {
+        [
+            rate_limits.requests,
+            rate_limits.connections,
+            rate_limits.requests_per_ip,
+            rate_limits.connections_per_ip,
+        ]
+        .contains(&Some(0))
+        .then_some("Rate limits must allow at least one per second")
+    });
+
+    if let Some(problem) = problem {
+        error!("invalid client hello: {}", problem);
+        reject(&mut websocket, ServerHello::Error(problem.into())).await;
+        return None;
+    }
+
     // Determine the domain
     let domain = match client_hello.domain {
         Some(ref d) => {
             if !CONFIG.allowed_hosts.contains(d) {
                 error!("invalid client hello: domain not allowed!");
-                let data = serde_json::to_vec(&ServerHello::InvalidSubDomain).unwrap_or_default();
-                let _ = websocket.send(Message::binary(data)).await;
+                reject(&mut websocket, ServerHello::InvalidSubDomain).await;
                 return None;
             }
             d.clone()
@@ -61,18 +165,95 @@ async fn auth_client(
                 first.clone()
             } else {
                  error!("no allowed hosts configured on server!");
-                 let data = serde_json::to_vec(&ServerHello::Error("Server misconfigured".into())).unwrap_or_default();
-                 let _ = websocket.send(Message::binary(data)).await;
+                 reject(&mut websocket, ServerHello::Error("Server misconfigured".into())).await;
                  return None;
             }
         }
     };
 
+    // authenticate the key, then hold the client to what it permits
+    let access = match &client_hello.client_type {
+        ClientType::Auth { key } => match crate::KEYS.authenticate(&key.0) {
+            Some(access) => Some(access),
+            None => {
+                error!("invalid client hello: unknown api key");
+                reject(&mut websocket, ServerHello::AuthFailed).await;
+                return None;
+            }
+        },
+        ClientType::Anonymous => None,
+    };
+
+    if let Some(KeyAccess::Named(name, key)) = &access {
+        if let Some(problem) = key_problem(name, key, &domain, client_hello.wildcard) {
+            error!(key = %name, "invalid client hello: {}", problem);
+            reject(&mut websocket, ServerHello::Error(problem)).await;
+            return None;
+        }
+    }
+
+    // the members of a group and their standbys all connect with the same key
+    let owner = match &client_hello.client_type {
+        ClientType::Auth { key } => Some(hash_key(&key.0)),
+        ClientType::Anonymous => None,
+    };
+    let problem = if client_hello.group.is_some() && client_hello.standby {
+        Some("A standby can't be part of a group")
+    } else if (client_hello.group.is_some() || client_hello.standby)
+        && (client_hello.kind == TunnelKind::Tcp || client_hello.wildcard)
+    {
+        Some("Only HTTP and TLS passthrough tunnels can be grouped or stand by, without a wildcard")
+    } else if client_hello.path_prefix.is_some()
+        && (client_hello.kind != TunnelKind::Http || client_hello.wildcard)
+    {
+        Some("Only HTTP tunnels can take a path prefix, without a wildcard")
+    } else {
+        None
+    };
+
+    if let Some(problem) = problem {
+        error!("invalid client hello: {}", problem);
+        reject(&mut websocket, ServerHello::Error(problem.into())).await;
+        return None;
+    }
+
+    let path_prefix = match &client_hello.path_prefix {
+        Some(path) => match parse_path_prefix(path) {
+            Some(path_prefix) => Some(path_prefix),
+            None => {
+                error!(%path, "invalid client hello: bad path prefix");
+                reject(&mut websocket, ServerHello::Error(format!(
+                    "Invalid path prefix {}, it should look like /api",
+                    path
+                ))).await;
+                return None;
+            }
+        },
+        None => None,
+    };
+
+    let claim = HostClaim {
+        kind: client_hello.kind,
+        owner: owner.as_deref(),
+        group: client_hello.group,
+        standby: client_hello.standby,
+        path_prefix: path_prefix.as_deref(),
+    };
+
+    let custom_domain = match &client_hello.custom_domain {
+        Some(host) => match validate_custom_domain(host, &client_hello, access.as_ref(), &claim).await {
+            Ok(host) => Some(host),
+            Err(hello) => {
+                reject(&mut websocket, hello).await;
+                return None;
+            }
+        },
+        None => None,
+    };
 
     let (auth_key, client_id, requested_sub_domain) = match client_hello.client_type {
         ClientType::Anonymous => {
-            let data = serde_json::to_vec(&ServerHello::AuthFailed).unwrap_or_default();
-            let _ = websocket.send(Message::binary(data)).await;
+            reject(&mut websocket, ServerHello::AuthFailed).await;
             return None;
         }
         ClientType::Auth { key } => match client_hello.sub_domain {
@@ -85,6 +266,7 @@ async fn auth_client(
                     &domain,
                     &client_id,
                     client_hello.wildcard,
+                    &claim,
                 )
                 .await
                 {
@@ -93,11 +275,21 @@ async fn auth_client(
                 };
                 websocket = ws;
 
-                (key, client_id, sub_domain)
+                (Some(key), client_id, sub_domain)
             }
             None => {
                 if let Some(token) = client_hello.reconnect_token {
-                    return handle_reconnect_token(token, websocket, client_hello.wildcard, domain).await;
+                    let (ws, payload) = handle_reconnect_token(
+                        token,
+                        websocket,
+                        client_hello.wildcard,
+                        client_hello.kind,
+                        &domain,
+                    )
+                    .await?;
+                    websocket = ws;
+
+                    (None, payload.client_id, payload.sub_domain)
                 } else {
                     let sub_domain = ServerHello::random_domain();
                     let client_id = ClientId::generate();
@@ -109,6 +301,7 @@ async fn auth_client(
                         &domain,
                         &client_id,
                         client_hello.wildcard,
+                        &claim,
                     )
                     .await
                     {
@@ -117,41 +310,86 @@ async fn auth_client(
                     };
                     websocket = ws;
 
-                    (key, client_id, sub_domain)
+                    (Some(key), client_id, sub_domain)
                 }
             }
         },
     };
 
-    tracing::info!(requested_sub_domain=%requested_sub_domain, domain=%domain, "will auth sub domain");
-
-    // next authenticate the sub-domain
-    // Note: Auth service currently just checks against key.
-    let sub_domain = match crate::AUTH_DB_SERVICE
-        .auth_sub_domain(&auth_key.0, &requested_sub_domain)
-        .await
-    {
-        Ok(AuthResult::Available) | Ok(AuthResult::ReservedByYou) => requested_sub_domain,
-        Ok(AuthResult::ReservedByYouButDelinquent) | Ok(AuthResult::PaymentRequired) => {
-            tracing::info!(requested_sub_domain=%requested_sub_domain, "payment required");
-            let data = serde_json::to_vec(&ServerHello::AuthFailed).unwrap_or_default();
-            let _ = websocket.send(Message::binary(data)).await;
+    // reconnect tokens are signed by us, so they already hold an authenticated sub-domain
+    let is_anonymous = auth_key.is_none();
+    let key_name = access.as_ref().and_then(|a| a.name()).map(String::from);
+    let max_tunnels = match &access {
+        Some(KeyAccess::Named(_, key)) => key.max_tunnels,
+        _ => None,
+    };
+    if let Some(KeyAccess::Named(name, key)) = &access {
+        if !key.allows_sub_domain(&requested_sub_domain) {
+            error!(key = %name, sub_domain = %requested_sub_domain, "invalid client hello: sub-domain not allowed for key");
+            reject(&mut websocket, ServerHello::Error(format!(
+                "This key may not use the sub-domain {}",
+                requested_sub_domain
+            ))).await;
             return None;
         }
-        Ok(AuthResult::ReservedByOther) => {
-            let data = serde_json::to_vec(&ServerHello::SubDomainInUse).unwrap_or_default();
-            let _ = websocket.send(Message::binary(data)).await;
-            return None;
+    }
+
+    let mut reserved = false;
+    let sub_domain = match auth_key {
+        None => {
+            // the sub-domain may have been reserved for another key since the token was issued
+            let host = format!("{}.{}", requested_sub_domain, domain);
+            match reconnect_reservation(&crate::RESERVATIONS, &host, owner.as_deref()) {
+                Ok(reserved_by_you) => reserved = reserved_by_you,
+                Err(hello) => {
+                    error!(%host, "invalid reconnect: sub-domain reserved by another key");
+                    reject(&mut websocket, hello).await;
+                    return None;
+                }
+            }
+            requested_sub_domain
         }
-        Err(error) => {
-            error!(?error, "error auth-ing user");
-            let data = serde_json::to_vec(&ServerHello::AuthFailed).unwrap_or_default();
-            let _ = websocket.send(Message::binary(data)).await;
-            return None;
+        Some(auth_key) => {
+            tracing::info!(requested_sub_domain=%requested_sub_domain, domain=%domain, "will auth sub domain");
+
+            // next authenticate the sub-domain
+            // Note: Auth service currently just checks against key.
+            match crate::AUTH_DB_SERVICE
+                .auth_sub_domain(&auth_key.0, &requested_sub_domain, &domain)
+                .await
+            {
+                Ok(AuthResult::ReservedByYou) => {
+                    reserved = true;
+                    requested_sub_domain
+                }
+                Ok(AuthResult::Available) => {
+                    if client_hello.reserve {
+                        reserved = crate::AUTH_DB_SERVICE
+                            .reserve_sub_domain(&auth_key.0, &requested_sub_domain, &domain)
+                            .await
+                            .is_ok();
+                    }
+                    requested_sub_domain
+                }
+                Ok(AuthResult::ReservedByYouButDelinquent) | Ok(AuthResult::PaymentRequired) => {
+                    tracing::info!(requested_sub_domain=%requested_sub_domain, "payment required");
+                    reject(&mut websocket, ServerHello::AuthFailed).await;
+                    return None;
+                }
+                Ok(AuthResult::ReservedByOther) => {
+                    reject(&mut websocket, ServerHello::SubDomainInUse).await;
+                    return None;
+                }
+                Err(error) => {
+                    error!(?error, "error auth-ing user");
+                    reject(&mut websocket, ServerHello::AuthFailed).await;
+                    return None;
+                }
+            }
         }
     };
 
-    tracing::info!(subdomain=%sub_domain, domain=%domain, "did auth sub_domain");
+    tracing::info!(subdomain=%sub_domain, domain=%domain, ?custom_domain, group = ?client_hello.group, standby = client_hello.standby, ?path_prefix, reserved, "did auth sub_domain");
 
     Some((
         websocket,
@@ -159,25 +397,137 @@ async fn auth_client(
             id: client_id,
             sub_domain,
             domain,
-            is_anonymous: false,
+            is_anonymous,
+            key_name,
+            max_tunnels,
             wildcard: client_hello.wildcard,
+            protocol_version,
+            capabilities,
+            kind: client_hello.kind,
+            tcp_port: client_hello.tcp_port,
+            reserved,
+            http2: client_hello.http2 && client_hello.kind == TunnelKind::Http,
+            forwarded_headers: client_hello.forwarded_headers,
+            policy,
+            rate_limits,
+            custom_domain,
+            owner,
+            group: client_hello.group,
+            standby: client_hello.standby,
+            path_prefix,
         },
     ))
 }
 
+/// Whether a host handed back by a reconnect token is reserved for the key reconnecting with it,
+/// refusing it if it is reserved for another one
+fn reconnect_reservation(reservations: &Reservations, host: &str, owner: Option<&str>) -> Result<bool, ServerHello> {
+    match reservations.get(host) {
+        Some(reservation) if Some(reservation.owner.as_str()) == owner => Ok(true),
+        Some(_) => Err(ServerHello::SubDomainInUse),
+        None => Ok(false),
+    }
+}
+
+/// Why a key from the key store may not open a tunnel on the domain, if it may not.
+/// Its tunnel count only saves us the rest of the handshake, `Connections::add` checks it for good.
+fn key_problem(name: &str, key: &ApiKey, domain: &str, wildcard: bool) -> Option<String> {
+    if !key.allows_domain(domain) {
+        return Some(format!("This key may not open tunnels on {}", domain));
+    }
+    if wildcard && !key.wildcard {
+        return Some("This key may not open wildcard tunnels".to_string());
+    }
+    if let Some(max) = key.max_tunnels.filter(|max| Connections::count_for_key(name) >= *max) {
+        return Some(AddError::TooManyTunnels(max).to_string());
+    }
+    if key.monthly_quota_bytes.is_some_and(|quota| crate::USAGE.get(name) >= quota) {
+        return Some("This key has used up its transfer quota for the month".to_string());
+    }
+    None
+}
+
+/// The path prefix the way we route it (i.e. `/api/v1`, without a trailing slash), if it's a valid one
+fn parse_path_prefix(path: &str) -> Option<String> {
+    let path = path.trim_end_matches('/');
+    let valid = path.starts_with('/')
+        && path[1..].split('/').all(|segment| {
+            !matches!(segment, "" | "." | "..")
+                && segment
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || "-._~%".contains(c))
+        });
+    valid.then(|| path.to_string())
+}
+
+/// Check the client may route its custom domain, which nobody else has, and that the domain's dns proves its key owns it
+async fn validate_custom_domain(
+    host: &str,
+    client_hello: &ClientHello,
+    access: Option<&KeyAccess>,
+    claim: &HostClaim<'_>,
+) -> Result<String, ServerHello> {
+    let auth_key = match (&client_hello.client_type, access) {
+        (ClientType::Auth { key: auth_key }, Some(access)) => {
+            if let KeyAccess::Named(name, stored_key) = access {
+                if !stored_key.custom_domains {
+                    error!(key = %name, "invalid client hello: key may not use custom domains");
+                    return Err(ServerHello::Error("This key may not use custom domains".into()));
+                }
+            }
+            auth_key
+        }
+        _ => {
+            error!("invalid client hello: custom domain without a key");
+            return Err(ServerHello::Error("Custom domains need an api key".into()));
+        }
+    };
+
+    if client_hello.kind == TunnelKind::Tcp || client_hello.wildcard {
+        error!("invalid client hello: custom domain on a tcp or wildcard tunnel");
+        return Err(ServerHello::Error(
+            "Custom domains only apply to HTTP and TLS passthrough tunnels, without a wildcard".into(),
+        ));
+    }
+
+    let host = custom_domain::parse_host(host).map_err(|error| {
+        error!(%error, "invalid client hello: bad custom domain");
+        ServerHello::Error(error.to_string())
+    })?;
+
+    // nobody else may be routing it, here or on another instance, unless it joins their group or stands by for them.
+    // this only saves a dns lookup, `Connections::add` claims it for good.
+    let in_use = !Connections::may_take(&host, claim)
+        || crate::network::instance_for_host(&route(&host, claim.path_prefix), None)
+            .await
+            .is_ok();
+    if in_use {
+        error!(%host, "invalid client hello: custom domain in use already!");
+        return Err(ServerHello::SubDomainInUse);
+    }
+
+    if let Err(error) = custom_domain::verify(&host, &hash_key(&auth_key.0)).await {
+        error!(%host, %error, "invalid client hello: custom domain not verified");
+        return Err(ServerHello::Error(error.to_string()));
+    }
+
+    tracing::info!(%host, "verified custom domain");
+    Ok(host)
+}
+
 #[tracing::instrument(skip(token, websocket))]
 async fn handle_reconnect_token(
     token: ReconnectToken,
     mut websocket: WebSocket,
     wildcard: bool,
-    domain: String,
-) -> Option<(WebSocket, ClientHandshake)> {
+    kind: TunnelKind,
+    domain: &str,
+) -> Option<(WebSocket, ReconnectTokenPayload)> {
     let payload = match ReconnectTokenPayload::verify(token, &CONFIG.master_sig_key) {
         Ok(payload) => payload,
         Err(error) => {
             error!(?error, "invalid reconnect token");
-            let data = serde_json::to_vec(&ServerHello::AuthFailed).unwrap_or_default();
-            let _ = websocket.send(Message::binary(data)).await;
+            reject(&mut websocket, ServerHello::AuthFailed).await;
             return None;
         }
     };
@@ -189,26 +539,16 @@ async fn handle_reconnect_token(
 
     if wildcard {
         use crate::connected_clients::Connections;
-        if let Some(existing_wildcard) = Connections::find_wildcard(&domain) {
+        if let Some(existing_wildcard) = Connections::find_wildcard(domain, kind) {
              if &existing_wildcard.id != &payload.client_id {
                 error!("invalid client hello: wildcard in use!");
-                let data = serde_json::to_vec(&ServerHello::SubDomainInUse).unwrap_or_default();
-                let _ = websocket.send(Message::binary(data)).await;
+                reject(&mut websocket, ServerHello::SubDomainInUse).await;
                 return None;
              }
         }
     }
 
-    Some((
-        websocket,
-        ClientHandshake {
-            id: payload.client_id,
-            sub_domain: payload.sub_domain,
-            domain,
-            is_anonymous: true,
-            wildcard,
-        },
-    ))
+    Some((websocket, payload))
 }
 
 async fn sanitize_sub_domain_and_pre_validate(
@@ -217,6 +557,7 @@ async fn sanitize_sub_domain_and_pre_validate(
     domain: &str,
     client_id: &ClientId,
     wildcard: bool,
+    claim: &HostClaim<'_>,
 ) -> Option<(WebSocket, String)> {
     // ignore uppercase
     let sub_domain = requested_sub_domain.to_lowercase();
@@ -228,42 +569,38 @@ async fn sanitize_sub_domain_and_pre_validate(
         > 0
     {
         error!("invalid client hello: only alphanumeric/hyphen chars allowed!");
-        let data = serde_json::to_vec(&ServerHello::InvalidSubDomain).unwrap_or_default();
-        let _ = websocket.send(Message::binary(data)).await;
+        reject(&mut websocket, ServerHello::InvalidSubDomain).await;
         return None;
     }
 
     // ensure it's not a restricted one
     if CONFIG.blocked_sub_domains.contains(&sub_domain) {
         error!("invalid client hello: sub-domain restrict!");
-        let data = serde_json::to_vec(&ServerHello::SubDomainInUse).unwrap_or_default();
-        let _ = websocket.send(Message::binary(data)).await;
+        reject(&mut websocket, ServerHello::SubDomainInUse).await;
         return None;
     }
 
     // ensure this sub-domain isn't taken
     let full_host = format!("{}.{}", sub_domain, domain);
 
-    // Check locally for existing client
-    if crate::connected_clients::Connections::find_by_host(&full_host).is_some() {
+    // Check locally for existing client, the members of a group and their standbys share their host
+    if !crate::connected_clients::Connections::may_take(&full_host, claim) {
         // Since we generate random IDs for every connection, we can't easily check for "same session reconnect" here
         // without comparing account IDs. But "same session reconnect" is usually handled by Reconnect Tokens.
         // For new connections, if it's taken, it's taken.
         // Exception: If the existing client is dead? TCP keepalives should handle that.
         error!("invalid client hello: requested sub domain in use already!");
-        let data = serde_json::to_vec(&ServerHello::SubDomainInUse).unwrap_or_default();
-        let _ = websocket.send(Message::binary(data)).await;
+        reject(&mut websocket, ServerHello::SubDomainInUse).await;
         return None;
     }
 
-    // check all instances (Remote Gossip - currently stubbed)
-    match crate::network::instance_for_host(&full_host).await {
+    // check the other instances of the cluster
+    match crate::network::instance_for_host(&route(&full_host, claim.path_prefix), None).await {
         Err(crate::network::Error::DoesNotServeHost) => {}
         Ok((_, existing_client)) => {
             if &existing_client != client_id {
                 error!("invalid client hello: requested sub domain in use already!");
-                let data = serde_json::to_vec(&ServerHello::SubDomainInUse).unwrap_or_default();
-                let _ = websocket.send(Message::binary(data)).await;
+                reject(&mut websocket, ServerHello::SubDomainInUse).await;
                 return None;
             }
         }
@@ -274,11 +611,10 @@ async fn sanitize_sub_domain_and_pre_validate(
 
     if wildcard {
         use crate::connected_clients::Connections;
-        if let Some(existing_wildcard) = Connections::find_wildcard(domain) {
+        if let Some(existing_wildcard) = Connections::find_wildcard(domain, claim.kind) {
              if &existing_wildcard.id != client_id {
                 error!("invalid client hello: wildcard in use!");
-                let data = serde_json::to_vec(&ServerHello::SubDomainInUse).unwrap_or_default();
-                let _ = websocket.send(Message::binary(data)).await;
+                reject(&mut websocket, ServerHello::SubDomainInUse).await;
                 return None;
              }
         }
@@ -286,3 +622,54 @@ async fn sanitize_sub_domain_and_pre_validate(
 
     Some((websocket, sub_domain))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_path_prefixes() {
+        assert_eq!(parse_path_prefix("/api").as_deref(), Some("/api"));
+        assert_eq!(parse_path_prefix("/api/v1/").as_deref(), Some("/api/v1"));
+        assert_eq!(parse_path_prefix("/a-b/c.d/~e_f/%20").as_deref(), Some("/a-b/c.d/~e_f/%20"));
+
+        for invalid in ["", "/", "api", "/api//v1", "/a/../b", "/a/./b", "/..", "/a b", "/a?b", "/a#b", "/ä"] {
+            assert_eq!(parse_path_prefix(invalid), None, "{:?}", invalid);
+        }
+    }
+
+    #[test]
+    fn reconnecting_respects_newer_reservations() {
+        let path = std::env::temp_dir().join(format!("neutun-reconnect-reservations-{}.json", std::process::id()));
+        let reservations = Reservations::load(path.clone());
+        let (mine, theirs) = (hash_key("my-key"), hash_key("their-key"));
+        reservations.reserve("mine.example.com", mine.clone(), None).unwrap();
+        reservations.reserve("theirs.example.com", theirs, None).unwrap();
+
+        let reserved = |host| reconnect_reservation(&reservations, host, Some(&mine));
+        assert!(matches!(reserved("mine.example.com"), Ok(true)));
+        assert!(matches!(reserved("free.example.com"), Ok(false)));
+        assert!(matches!(reserved("theirs.example.com"), Err(ServerHello::SubDomainInUse)));
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn holds_keys_to_their_permissions() {
+        let key: ApiKey = serde_json::from_value(serde_json::json!({
+            "key_hash": "",
+            "allowed_domains": ["tunnel.example.com"],
+            "max_tunnels": 0,
+        }))
+        .unwrap();
+        let problem = |key: &ApiKey, domain, wildcard| key_problem("permissions-test", key, domain, wildcard);
+
+        assert!(problem(&key, "other.example.com", false).unwrap().contains("may not open tunnels on"));
+        assert!(problem(&key, "tunnel.example.com", true).unwrap().contains("wildcard"));
+        assert!(problem(&key, "tunnel.example.com", false).unwrap().contains("most tunnels"));
+
+        let key = ApiKey { wildcard: true, max_tunnels: Some(1), ..key };
+        assert_eq!(problem(&key, "tunnel.example.com", true), None);
+        let key = ApiKey { monthly_quota_bytes: Some(0), ..key };
+        assert!(problem(&key, "tunnel.example.com", false).unwrap().contains("quota"));
+    }
+}
@@ -0,0 +1,289 @@
+use crate::CONFIG;
+use serde::{Deserialize, Serialize};
+use sha2::Digest;
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::sync::RwLock;
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("IOError: {0}")]
+    Io(#[from] std::io::Error),
+
+    #[error("invalid json: {0}")]
+    Json(#[from] serde_json::Error),
+
+    #[error("keys can only be managed with API_KEYS_FILE set")]
+    NoKeysFile,
+
+    #[error("a key named {0} already exists")]
+    Exists(String),
+}
+
+/// A named api key and what it may tunnel
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ApiKey {
+    /// hex encoded sha256 of the key, we never store the key itself
+    pub key_hash: String,
+    /// the allowed hosts this key may open tunnels on, any of them if empty
+    #[serde(default)]
+    pub allowed_domains: Vec<String>,
+    /// the sub-domains this key may use, `*` matches any run of characters (i.e. `alice-*`).
+    /// any sub-domain if empty.
+    #[serde(default)]
+    pub sub_domains: Vec<String>,
+    /// whether this key may open wildcard tunnels
+    #[serde(default)]
+    pub wildcard: bool,
+    /// how many tunnels this key may have open at once, unlimited if unset
+    #[serde(default)]
+    pub max_tunnels: Option<usize>,
+    /// the throughput all of this key's tunnels share, both ways together, unlimited if unset
+    #[serde(default)]
+    pub max_bytes_per_sec: Option<u64>,
+    /// how many bytes this key's tunnels may transfer each calendar month (utc), unlimited if unset
+    #[serde(default)]
+    pub monthly_quota_bytes: Option<u64>,
+    /// whether this key may route custom domains to its tunnels, once their dns proves ownership
+    #[serde(default)]
+    pub custom_domains: bool,
+}
+
+impl ApiKey {
+    pub fn allows_domain(&self, domain: &str) -> bool {
+        self.allowed_domains.is_empty() || self.allowed_domains.iter().any(|d| d == domain)
+    }
+
+    pub fn allows_sub_domain(&self, sub_domain: &str) -> bool {
+        self.sub_domains.is_empty()
+            || self
+                .sub_domains
+                .iter()
+                .any(|pattern| matches_pattern(pattern, sub_domain))
+    }
+}
+
+/// What an authenticated key may do
+#[derive(Debug, Clone)]
+pub enum KeyAccess {
+    /// the master key, or any key on a server without keys: no restrictions
+    Master,
+    /// a key from the key store, restricted to its permissions
+    Named(String, ApiKey),
+}
+
+impl KeyAccess {
+    /// The key store name, for restricted keys
+    pub fn name(&self) -> Option<&str> {
+        match self {
+            KeyAccess::Master => None,
+            KeyAccess::Named(name, _) => Some(name),
+        }
+    }
+}
+
+/// Named api keys by name, loaded from `API_KEYS_FILE`
+pub struct KeyStore {
+    path: Option<PathBuf>,
+    by_name: RwLock<BTreeMap<String, ApiKey>>,
+}
+
+impl KeyStore {
+    pub fn load(path: Option<&PathBuf>) -> Self {
+        let by_name = match path {
+            Some(path) => {
+                let data = std::fs::read(path)
+                    .unwrap_or_else(|e| panic!("failed to read api keys file {:?}: {}", path, e));
+                serde_json::from_slice(&data)
+                    .unwrap_or_else(|e| panic!("invalid api keys file {:?}: {}", path, e))
+            }
+            None => BTreeMap::new(),
+        };
+
+        KeyStore {
+            path: path.cloned(),
+            by_name: RwLock::new(by_name),
+        }
+    }
+
+    pub fn list(&self) -> BTreeMap<String, ApiKey> {
+        self.read().clone()
+    }
+
+    pub fn get(&self, name: &str) -> Option<ApiKey> {
+        self.read().get(name).cloned()
+    }
+
+    /// Add a key and persist it to the keys file
+    pub fn add(&self, name: &str, key: ApiKey) -> Result<(), Error> {
+        let path = self.path.as_ref().ok_or(Error::NoKeysFile)?;
+        let mut by_name = self.by_name.write().unwrap_or_else(|e| e.into_inner());
+        if by_name.contains_key(name) {
+            return Err(Error::Exists(name.to_string()));
+        }
+
+        let mut updated = by_name.clone();
+        updated.insert(name.to_string(), key);
+        save(path, &updated)?;
+        *by_name = updated;
+        Ok(())
+    }
+
+    /// Remove a key and persist the keys file, returning the key if there was one
+    pub fn remove(&self, name: &str) -> Result<Option<ApiKey>, Error> {
+        let path = self.path.as_ref().ok_or(Error::NoKeysFile)?;
+        let mut by_name = self.by_name.write().unwrap_or_else(|e| e.into_inner());
+        if !by_name.contains_key(name) {
+            return Ok(None);
+        }
+
+        let mut updated = by_name.clone();
+        let removed = updated.remove(name);
+        save(path, &updated)?;
+        *by_name = updated;
+        Ok(removed)
+    }
+
+    fn read(&self) -> std::sync::RwLockReadGuard<'_, BTreeMap<String, ApiKey>> {
+        self.by_name.read().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Find what a key may do, or `None` if we don't accept it
+    pub fn authenticate(&self, auth_key: &str) -> Option<KeyAccess> {
+        self.authenticate_with(auth_key, CONFIG.master_key.as_deref(), CONFIG.keys_file.is_some())
+    }
+
+    fn authenticate_with(&self, auth_key: &str, master_key: Option<&str>, keys_file: bool) -> Option<KeyAccess> {
+        if master_key.is_some_and(|master_key| same_key(master_key, auth_key)) {
+            return Some(KeyAccess::Master);
+        }
+
+        let key_hash = hash_key(auth_key);
+        if let Some((name, key)) = self.read().iter().find(|(_, k)| k.key_hash == key_hash) {
+            return Some(KeyAccess::Named(name.clone(), key.clone()));
+        }
+
+        // without any keys configured the server is open to everyone
+        if master_key.is_none() && !keys_file {
+            return Some(KeyAccess::Master);
+        }
+
+        None
+    }
+}
+
+fn save(path: &PathBuf, by_name: &BTreeMap<String, ApiKey>) -> Result<(), Error> {
+    // write a temporary file first, so a crash never leaves a truncated file behind
+    let tmp = path.with_extension("tmp");
+    std::fs::write(&tmp, serde_json::to_vec_pretty(by_name)?)?;
+    std::fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+/// The hex encoded sha256 of a key, as stored in the key store
+pub fn hash_key(auth_key: &str) -> String {
+    hex::encode(sha2::Sha256::digest(auth_key.as_bytes()))
+}
+
+/// Whether two keys are the same. Only their digests are compared, so the time it takes gives nothing of them away.
+pub fn same_key(key: &str, other: &str) -> bool {
+    sha2::Sha256::digest(key.as_bytes()) == sha2::Sha256::digest(other.as_bytes())
+}
+
+/// Match a sub-domain against a pattern where `*` matches any run of characters
+fn matches_pattern(pattern: &str, sub_domain: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or_default();
+    let mut rest = match sub_domain.strip_prefix(first) {
+        Some(rest) => rest,
+        None => return false,
+    };
+
+    let parts: Vec<&str> = parts.collect();
+    let (last, middle) = match parts.split_last() {
+        Some(split) => split,
+        // no `*` at all, so it must be an exact match
+        None => return rest.is_empty(),
+    };
+
+    for part in middle {
+        match rest.find(part) {
+            Some(index) => rest = &rest[index + part.len()..],
+            None => return false,
+        }
+    }
+    rest.ends_with(last)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches_sub_domain_patterns() {
+        assert!(matches_pattern("alice", "alice"));
+        assert!(!matches_pattern("alice", "alice2"));
+        assert!(!matches_pattern("alice", "xalice"));
+
+        assert!(matches_pattern("alice-*", "alice-"));
+        assert!(matches_pattern("alice-*", "alice-dev"));
+        assert!(!matches_pattern("alice-*", "bob-dev"));
+        assert!(matches_pattern("*-dev", "alice-dev"));
+        assert!(!matches_pattern("*-dev", "alice-prod"));
+        assert!(matches_pattern("*", "anything"));
+
+        assert!(matches_pattern("a*b*c", "abc"));
+        assert!(matches_pattern("a*b*c", "axxbyyc"));
+        assert!(!matches_pattern("a*b*c", "axxcyyb"));
+        // the parts can't overlap
+        assert!(!matches_pattern("ab*ba", "aba"));
+        assert!(matches_pattern("a**b", "ab"));
+    }
+
+    #[test]
+    fn restricts_domains_and_sub_domains() {
+        let open: ApiKey = serde_json::from_value(serde_json::json!({ "key_hash": "" })).unwrap();
+        assert!(open.allows_domain("example.com"));
+        assert!(open.allows_sub_domain("anything"));
+
+        let key = ApiKey {
+            allowed_domains: vec!["example.com".to_string()],
+            sub_domains: vec!["alice".to_string(), "alice-*".to_string()],
+            ..open
+        };
+        assert!(key.allows_domain("example.com"));
+        assert!(!key.allows_domain("example.org"));
+        assert!(key.allows_sub_domain("alice"));
+        assert!(key.allows_sub_domain("alice-dev"));
+        assert!(!key.allows_sub_domain("bob"));
+    }
+
+    #[test]
+    fn authenticates_the_master_key_and_stored_keys() {
+        let key: ApiKey = serde_json::from_value(serde_json::json!({ "key_hash": hash_key("alice-key") })).unwrap();
+        let store = KeyStore {
+            path: None,
+            by_name: RwLock::new(BTreeMap::from([("alice".to_string(), key)])),
+        };
+        let name = |access: Option<KeyAccess>| access.map(|access| access.name().map(String::from));
+
+        assert_eq!(name(store.authenticate_with("master", Some("master"), true)), Some(None));
+        assert_eq!(
+            name(store.authenticate_with("alice-key", Some("master"), true)),
+            Some(Some("alice".to_string()))
+        );
+        assert_eq!(name(store.authenticate_with("master2", Some("master"), true)), None);
+        assert_eq!(name(store.authenticate_with("", Some("master"), false)), None);
+        assert_eq!(name(store.authenticate_with("bob-key", None, true)), None);
+        // a server without keys takes any
+        assert_eq!(name(store.authenticate_with("bob-key", None, false)), Some(None));
+    }
+
+    #[test]
+    fn compares_keys() {
+        assert!(same_key("secret", "secret"));
+        assert!(!same_key("secret", "secret2"));
+        assert!(!same_key("secret", ""));
+    }
+}
@@ -6,7 +6,9 @@ use std::fmt::Formatter;
 
 pub mod simple_auth;
 pub mod client_auth;
+pub mod key_store;
 pub mod reconnect_token;
+pub mod reservations;
 
 #[derive(Clone)]
 pub struct SigKey([u8; 32]);
@@ -60,16 +62,25 @@ pub trait AuthService {
         &self,
         auth_key: &Self::AuthKey,
         subdomain: &str,
+        domain: &str,
     ) -> Result<AuthResult, Self::Error>;
+
+    /// Hold a subdomain for an AuthKey, even while it's disconnected
+    async fn reserve_sub_domain(
+        &self,
+        auth_key: &Self::AuthKey,
+        subdomain: &str,
+        domain: &str,
+    ) -> Result<(), Self::Error>;
 }
 
 /// A result for authenticating a subdomain
 pub enum AuthResult {
-    #[allow(dead_code)]
     ReservedByYou,
     ReservedByOther,
     #[allow(dead_code)]
     ReservedByYouButDelinquent,
+    #[allow(dead_code)]
     PaymentRequired,
     Available,
 }
@@ -0,0 +1,268 @@
+use super::key_store::hash_key;
+use chrono::{DateTime, Utc};
+use dashmap::mapref::entry::Entry;
+use dashmap::DashMap;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("IOError: {0}")]
+    Io(#[from] std::io::Error),
+
+    #[error("invalid json: {0}")]
+    Json(#[from] serde_json::Error),
+
+    #[error("already reserved by another key")]
+    ReservedByOther,
+
+    #[error("this key already reserved the most sub-domains it may ({0})")]
+    TooMany(usize),
+}
+
+/// A sub-domain held for an api key, even while nobody is connected on it
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Reservation {
+    /// the hash of the api key holding it (see `key_store::hash_key`), we never store the key itself
+    pub owner: String,
+    pub created_at: DateTime<Utc>,
+}
+
+/// Reservations by full host (i.e. `api.foo.bar`), persisted to a json file
+pub struct Reservations {
+    path: PathBuf,
+    by_host: DashMap<String, Reservation>,
+    /// serializes writes to the file
+    save_lock: Mutex<()>,
+    /// held while an owner's reservations are counted and one is added, so nobody gets past their limit
+    reserving: Mutex<()>,
+}
+
+impl Reservations {
+    /// Load the reservations file, starting empty if it doesn't exist yet
+    pub fn load(path: PathBuf) -> Self {
+        let by_host: BTreeMap<String, Reservation> = match std::fs::read(&path) {
+            Ok(data) => serde_json::from_slice(&data)
+                .unwrap_or_else(|e| panic!("invalid reservations file {:?}: {}", path, e)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(e) => panic!("failed to read reservations file {:?}: {}", path, e),
+        };
+
+        Reservations {
+            path,
+            by_host: by_host.into_iter().collect(),
+            save_lock: Mutex::new(()),
+            reserving: Mutex::new(()),
+        }
+    }
+
+    /// The identity reservations are held under for an api key
+    pub fn owner(auth_key: &str) -> String {
+        hash_key(auth_key)
+    }
+
+    pub fn get(&self, host: &str) -> Option<Reservation> {
+        self.by_host.get(host).map(|r| r.value().clone())
+    }
+
+    pub fn list(&self) -> BTreeMap<String, Reservation> {
+        self.by_host
+            .iter()
+            .map(|r| (r.key().clone(), r.value().clone()))
+            .collect()
+    }
+
+    /// Reserve `host` for `owner`, succeeding if they already hold it.
+    /// With a `max`, an owner holding that many reservations already gets no more.
+    pub fn reserve(&self, host: &str, owner: String, max: Option<usize>) -> Result<(), Error> {
+        let _reserving = self.reserving.lock().unwrap_or_else(|e| e.into_inner());
+        match self.get(host) {
+            Some(existing) if existing.owner == owner => return Ok(()),
+            Some(_) => return Err(Error::ReservedByOther),
+            None => {}
+        }
+        if let Some(max) = max {
+            if self.by_host.iter().filter(|r| r.owner == owner).count() >= max {
+                return Err(Error::TooMany(max));
+            }
+        }
+
+        match self.by_host.entry(host.to_string()) {
+            Entry::Occupied(existing) if existing.get().owner == owner => return Ok(()),
+            Entry::Occupied(_) => return Err(Error::ReservedByOther),
+            Entry::Vacant(entry) => {
+                entry.insert(Reservation {
+                    owner,
+                    created_at: Utc::now(),
+                });
+            }
+        }
+
+        if let Err(error) = self.save() {
+            self.by_host.remove(host);
+            return Err(error);
+        }
+        Ok(())
+    }
+
+    /// Release a reservation, returning it if there was one
+    pub fn release(&self, host: &str) -> Result<Option<Reservation>, Error> {
+        let released = match self.by_host.remove(host) {
+            Some((_, reservation)) => reservation,
+            None => return Ok(None),
+        };
+
+        if let Err(error) = self.save() {
+            self.by_host.insert(host.to_string(), released);
+            return Err(error);
+        }
+        Ok(Some(released))
+    }
+
+    /// Release every reservation `owner` holds, i.e. when its key is removed, returning their hosts
+    pub fn release_owned_by(&self, owner: &str) -> Result<Vec<String>, Error> {
+        self.release_where(|reservation| reservation.owner == owner)
+    }
+
+    /// Release every reservation matching `release`, returning their hosts
+    pub fn release_where(&self, release: impl Fn(&Reservation) -> bool) -> Result<Vec<String>, Error> {
+        let mut released = vec![];
+        self.by_host.retain(|host, reservation| {
+            if release(reservation) {
+                released.push((host.clone(), reservation.clone()));
+                return false;
+            }
+            true
+        });
+        if released.is_empty() {
+            return Ok(vec![]);
+        }
+
+        if let Err(error) = self.save() {
+            for (host, reservation) in released {
+                self.by_host.insert(host, reservation);
+            }
+            return Err(error);
+        }
+        Ok(released.into_iter().map(|(host, _)| host).collect())
+    }
+
+    fn save(&self) -> Result<(), Error> {
+        let _guard = self.save_lock.lock().unwrap_or_else(|e| e.into_inner());
+        let by_host = self.list();
+
+        // write a temporary file first, so a crash never leaves a truncated file behind
+        let tmp = self.path.with_extension("tmp");
+        std::fs::write(&tmp, serde_json::to_vec_pretty(&by_host)?)?;
+        std::fs::rename(&tmp, &self.path)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn load(name: &str) -> (Reservations, PathBuf) {
+        let path = std::env::temp_dir().join(format!("neutun-reservations-{}-{}.json", name, std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        (Reservations::load(path.clone()), path)
+    }
+
+    #[test]
+    fn holds_a_host_for_its_key_only() {
+        let (reservations, path) = load("reserve");
+        let (mine, theirs) = (Reservations::owner("my-key"), Reservations::owner("their-key"));
+
+        reservations.reserve("api.example.com", mine.clone(), None).unwrap();
+        // again is fine, for the same key
+        reservations.reserve("api.example.com", mine.clone(), None).unwrap();
+        assert!(matches!(
+            reservations.reserve("api.example.com", theirs.clone(), None),
+            Err(Error::ReservedByOther)
+        ));
+        assert_eq!(reservations.get("api.example.com").unwrap().owner, mine);
+
+        reservations.reserve("web.example.com", theirs, None).unwrap();
+        assert_eq!(reservations.list().len(), 2);
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn releasing_frees_the_host() {
+        let (reservations, path) = load("release");
+        let (mine, theirs) = (Reservations::owner("my-key"), Reservations::owner("their-key"));
+
+        reservations.reserve("api.example.com", mine.clone(), None).unwrap();
+        assert_eq!(reservations.release("api.example.com").unwrap().unwrap().owner, mine);
+        assert!(reservations.release("api.example.com").unwrap().is_none());
+        assert!(reservations.get("api.example.com").is_none());
+
+        reservations.reserve("api.example.com", theirs, None).unwrap();
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn limits_the_reservations_of_a_key() {
+        let (reservations, path) = load("limit");
+        let (mine, theirs) = (Reservations::owner("my-key"), Reservations::owner("their-key"));
+        reservations.reserve("api.example.com", mine.clone(), Some(2)).unwrap();
+        reservations.reserve("web.example.com", mine.clone(), Some(2)).unwrap();
+
+        assert!(matches!(
+            reservations.reserve("app.example.com", mine.clone(), Some(2)),
+            Err(Error::TooMany(2))
+        ));
+        // the ones it holds still are its own, and others have limits of their own
+        reservations.reserve("api.example.com", mine.clone(), Some(2)).unwrap();
+        reservations.reserve("app.example.com", theirs, Some(2)).unwrap();
+
+        reservations.release("web.example.com").unwrap();
+        reservations.reserve("www.example.com", mine, Some(2)).unwrap();
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn releases_everything_a_key_holds() {
+        let (reservations, path) = load("release-owned");
+        let (mine, theirs) = (Reservations::owner("my-key"), Reservations::owner("their-key"));
+        reservations.reserve("api.example.com", mine.clone(), None).unwrap();
+        reservations.reserve("web.example.com", mine.clone(), None).unwrap();
+        reservations.reserve("app.example.com", theirs.clone(), None).unwrap();
+
+        let mut released = reservations.release_owned_by(&mine).unwrap();
+        released.sort();
+        assert_eq!(released, ["api.example.com", "web.example.com"]);
+        assert!(reservations.release_owned_by(&mine).unwrap().is_empty());
+        assert_eq!(reservations.get("app.example.com").unwrap().owner, theirs);
+
+        // the hosts are free for other keys again
+        reservations.reserve("api.example.com", theirs, None).unwrap();
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn reservations_survive_a_restart() {
+        let (reservations, path) = load("reload");
+        let owner = Reservations::owner("my-key");
+        reservations.reserve("api.example.com", owner.clone(), None).unwrap();
+        reservations.reserve("web.example.com", owner.clone(), None).unwrap();
+        reservations.release("web.example.com").unwrap();
+
+        let reloaded = Reservations::load(path.clone());
+        assert_eq!(reloaded.get("api.example.com").unwrap().owner, owner);
+        assert!(reloaded.get("web.example.com").is_none());
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn owners_are_the_hashes_of_keys() {
+        let owner = Reservations::owner("my-key");
+        assert_eq!(owner.len(), 64);
+        assert!(owner.chars().all(|c| c.is_ascii_hexdigit()));
+        assert_ne!(owner, Reservations::owner("their-key"));
+    }
+}
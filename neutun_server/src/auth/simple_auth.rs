@@ -1,6 +1,7 @@
 use super::{AuthResult, AuthService};
 use async_trait::async_trait;
-use crate::CONFIG;
+use super::reservations::Reservations;
+use crate::{CONFIG, RESERVATIONS};
 use crate::connected_clients::Connections;
 
 pub struct SimpleAuthService;
@@ -14,19 +15,44 @@ impl AuthService for SimpleAuthService {
         &self,
         auth_key: &String,
         subdomain: &str,
+        domain: &str,
     ) -> Result<AuthResult, Self::Error> {
-        // 1. Validate Master Key
-        if let Some(master_key) = &CONFIG.master_key {
-            if auth_key != master_key {
-                return Ok(AuthResult::PaymentRequired); // Or a more appropriate "Forbidden" mapping if available
+        // the key itself was already checked against the key store
+        let host = format!("{}.{}", subdomain, domain);
+
+        // 1. Check for a reservation, which holds even while its owner is disconnected
+        if let Some(reservation) = RESERVATIONS.get(&host) {
+            if reservation.owner == Reservations::owner(auth_key) {
+                return Ok(AuthResult::ReservedByYou);
             }
+            return Ok(AuthResult::ReservedByOther);
         }
 
-        // 2. Check for collision
-        if Connections::find_by_host(&subdomain.to_string()).is_some() {
+        // 2. Check for collision, the key's own group may share the host
+        if Connections::is_taken_from(&host, &Reservations::owner(auth_key)) {
              return Ok(AuthResult::ReservedByOther);
         }
 
         Ok(AuthResult::Available)
     }
+
+    async fn reserve_sub_domain(
+        &self,
+        auth_key: &String,
+        subdomain: &str,
+        domain: &str,
+    ) -> Result<(), Self::Error> {
+        let host = format!("{}.{}", subdomain, domain);
+        // a server without keys takes any string as one, so anyone could hold every sub-domain for good
+        if CONFIG.master_key.is_none() && CONFIG.keys_file.is_none() {
+            tracing::info!(%host, "not reserving sub-domain: reservations need api keys");
+            return Err(());
+        }
+
+        RESERVATIONS
+            .reserve(&host, Reservations::owner(auth_key), Some(CONFIG.max_reservations))
+            .map_err(|error| {
+                tracing::error!(%host, %error, "failed to reserve sub-domain");
+            })
+    }
 }
@@ -1,6 +1,13 @@
 use crate::auth::SigKey;
-use std::net::IpAddr;
-use std::str::FromStr;
+use crate::acme::AcmeConfig;
+use crate::network::Discovery;
+use crate::ip_filter;
+use crate::tls::TlsFiles;
+use neutun_lib::RateLimits;
+use ipnet::IpNet;
+use std::net::SocketAddr;
+use std::ops::RangeInclusive;
+use std::path::PathBuf;
 
 /// Global service configuration
 pub struct Config {
@@ -22,14 +29,72 @@ pub struct Config {
     /// internal port for instance-to-instance gossip coms
     pub internal_network_port: u16,
 
+    /// how to find the other instances of our cluster, we run alone if unset
+    pub cluster: Option<Discovery>,
+
     /// our signature key
     pub master_sig_key: SigKey,
 
-    /// Blocked IP addresses
-    pub blocked_ips: Vec<IpAddr>,
+    /// Blocked IP addresses and ranges
+    pub blocked_ips: Vec<IpNet>,
+
+    /// the only ip addresses and ranges that may connect, anyone may if empty
+    pub allowed_ips: Vec<IpNet>,
+
+    /// reverse proxies in front of the control port, whose `X-Forwarded-For` and `X-Real-IP` we believe
+    pub trusted_proxies: Vec<IpNet>,
+
+    /// more blocked and allowed ranges, reloaded whenever the file changes
+    pub ip_lists_file: Option<PathBuf>,
 
     /// Master API Key for authentication
     pub master_key: Option<String>,
+
+    /// named api keys with their own permissions, alongside the master key
+    pub keys_file: Option<PathBuf>,
+
+    /// bearer token for the admin api, which is disabled if unset
+    pub admin_key: Option<String>,
+
+    /// Oldest client protocol version we accept
+    pub min_protocol_version: u32,
+
+    /// public ports handed out to tcp tunnels, tcp tunnels are disabled if unset
+    /// i.e:    20000-20100
+    pub tcp_ports: Option<RangeInclusive<u16>>,
+
+    /// certificate and key to terminate tls with on the public and control ports
+    pub tls: Option<TlsFiles>,
+
+    /// issue certificates for our allowed hosts from an acme directory (i.e. let's encrypt)
+    pub acme: Option<AcmeConfig>,
+
+    /// where sub-domains reserved for api keys are kept
+    pub reservations_file: PathBuf,
+
+    /// the most sub-domains a key may reserve for itself, the admin api may reserve more
+    pub max_reservations: usize,
+
+    /// where the bytes each api key transferred this month are kept
+    pub usage_file: PathBuf,
+
+    /// our listeners behind a load balancer that sends a proxy protocol header ahead of every connection
+    pub proxy_protocol: ProxyProtocol,
+
+    /// the most any tunnel may take, clients may only ask for tighter limits
+    pub rate_limits: RateLimits,
+
+    /// the dns server we ask to verify custom domains, the system's if unset
+    pub dns_server: Option<SocketAddr>,
+}
+
+/// Which listeners expect a PROXY protocol header
+#[derive(Debug, Default, Clone, Copy)]
+pub struct ProxyProtocol {
+    /// the public port
+    pub public: bool,
+    /// the control port
+    pub control: bool,
 }
 
 impl Config {
@@ -49,26 +114,135 @@ impl Config {
             SigKey::generate()
         };
 
-        let blocked_ips = std::env::var("BLOCKED_IPS")
+        let blocked_ips = get_ranges("BLOCKED_IPS");
+        let allowed_ips = get_ranges("ALLOWED_IPS");
+        let ip_lists_file = std::env::var("IP_LISTS_FILE").ok().map(Into::into);
+        let trusted_proxies = get_ranges("TRUSTED_PROXIES");
+
+        let master_key = std::env::var("MASTER_API_KEY").ok().or_else(|| std::env::var("NEUTUN_MASTER_KEY").ok());
+
+        let min_protocol_version = std::env::var("MIN_PROTOCOL_VERSION")
+            .map(|s| s.parse().expect("invalid MIN_PROTOCOL_VERSION: not a number"))
+            .unwrap_or(0);
+
+        let tcp_ports = std::env::var("TCP_PORTS").ok().map(|s| {
+            let (start, end) = s.split_once("-").unwrap_or((&s, &s));
+            let start: u16 = start.trim().parse().expect("invalid TCP_PORTS: bad start port");
+            let end: u16 = end.trim().parse().expect("invalid TCP_PORTS: bad end port");
+            assert!(start <= end, "invalid TCP_PORTS: empty range");
+            start..=end
+        });
+
+        let tls = match (std::env::var("TLS_CERT_FILE"), std::env::var("TLS_KEY_FILE")) {
+            (Ok(cert), Ok(key)) => Some(TlsFiles {
+                cert: cert.into(),
+                key: key.into(),
+            }),
+            (Err(_), Err(_)) => None,
+            _ => panic!("invalid tls config: TLS_CERT_FILE and TLS_KEY_FILE must be set together"),
+        };
+
+        let acme = std::env::var("ACME_DIRECTORY_URL").ok().map(|directory_url| AcmeConfig {
+            directory_url,
+            contact_email: std::env::var("ACME_EMAIL").ok(),
+            dns_hook: std::env::var("ACME_DNS_HOOK")
+                .expect("invalid acme config: ACME_DNS_HOOK is required for wildcard certificates")
+                .into(),
+            dns_propagation: std::time::Duration::from_secs(
+                std::env::var("ACME_DNS_PROPAGATION_SECS")
+                    .map(|s| s.parse().expect("invalid ACME_DNS_PROPAGATION_SECS: not a number"))
+                    .unwrap_or(60),
+            ),
+            cache_dir: std::env::var("ACME_CACHE_DIR").unwrap_or("acme".into()).into(),
+            ca_file: std::env::var("ACME_CA_FILE").ok().map(Into::into),
+        });
+
+        let internal_network_port = get_port("NET_PORT", 6000);
+
+        let cluster = match (std::env::var("CLUSTER_PEERS"), std::env::var("CLUSTER_DNS")) {
+            (Ok(peers), Err(_)) => Some(Discovery::Peers(
+                peers
+                    .split(",")
+                    .map(str::trim)
+                    .filter(|peer| !peer.is_empty())
+                    .map(|peer| match peer.rsplit_once(":") {
+                        Some(_) => peer.to_string(),
+                        None => format!("{}:{}", peer, internal_network_port),
+                    })
+                    .collect(),
+            )),
+            (Err(_), Ok(name)) => Some(Discovery::Dns(name)),
+            (Err(_), Err(_)) => None,
+            _ => panic!("invalid cluster config: set only one of CLUSTER_PEERS and CLUSTER_DNS"),
+        };
+
+        let keys_file = std::env::var("API_KEYS_FILE").ok().map(Into::into);
+
+        let admin_key = std::env::var("ADMIN_API_KEY").ok();
+
+        let reservations_file = std::env::var("RESERVATIONS_FILE")
+            .unwrap_or("reservations.json".into())
+            .into();
+
+        let max_reservations = std::env::var("MAX_RESERVATIONS")
+            .map(|s| s.parse().expect("invalid MAX_RESERVATIONS: not a number"))
+            .unwrap_or(10);
+
+        let usage_file = std::env::var("USAGE_FILE").unwrap_or("usage.json".into()).into();
+
+        let proxy_protocol = std::env::var("PROXY_PROTOCOL")
             .map(|s| {
-                s.split(",")
-                    .map(IpAddr::from_str)
-                    .filter_map(Result::ok)
-                    .collect()
+                let mut proxy_protocol = ProxyProtocol::default();
+                for listener in s.split(",").map(str::trim).filter(|l| !l.is_empty()) {
+                    match listener {
+                        "public" => proxy_protocol.public = true,
+                        "control" => proxy_protocol.control = true,
+                        _ => panic!("invalid PROXY_PROTOCOL: unknown listener {}", listener),
+                    }
+                }
+                proxy_protocol
             })
-            .unwrap_or(vec![]);
+            .unwrap_or_default();
 
-        let master_key = std::env::var("MASTER_API_KEY").ok().or_else(|| std::env::var("NEUTUN_MASTER_KEY").ok());
+        let rate_limits = RateLimits {
+            requests: get_rate("TUNNEL_REQUESTS_PER_SEC"),
+            connections: get_rate("TUNNEL_CONNECTIONS_PER_SEC"),
+            requests_per_ip: get_rate("IP_REQUESTS_PER_SEC"),
+            connections_per_ip: get_rate("IP_CONNECTIONS_PER_SEC"),
+        };
+
+        let dns_server = std::env::var("DNS_SERVER").ok().map(|server| {
+            server
+                .parse()
+                .or_else(|_| server.parse().map(|ip| SocketAddr::new(ip, 53)))
+                .unwrap_or_else(|_| panic!("invalid DNS_SERVER: {}", server))
+        });
 
         Config {
             allowed_hosts,
             blocked_sub_domains,
             control_port: get_port("CTRL_PORT", 5000),
             remote_port: get_port("PORT", 8080),
-            internal_network_port: get_port("NET_PORT", 6000),
+            internal_network_port,
+            cluster,
             master_sig_key,
             blocked_ips,
+            allowed_ips,
+            trusted_proxies,
+            ip_lists_file,
             master_key,
+            min_protocol_version,
+            tcp_ports,
+            tls,
+            acme,
+            reservations_file,
+            max_reservations,
+            usage_file,
+            keys_file,
+            admin_key,
+            proxy_protocol,
+            rate_limits,
+            dns_server,
         }
     }
 }
@@ -82,3 +256,26 @@ fn get_port(var: &'static str, default: u16) -> u16 {
         default
     }
 }
+
+/// A rate limit in events per second, unlimited if unset
+fn get_rate(var: &'static str) -> Option<u32> {
+    let rate = std::env::var(var).ok()?;
+    match rate.parse() {
+        Ok(rate) if rate > 0 => Some(rate),
+        _ => panic!("invalid {}: not a positive number", var),
+    }
+}
+
+/// A comma separated list of ip addresses and cidr ranges
+fn get_ranges(var: &'static str) -> Vec<IpNet> {
+    std::env::var(var)
+        .map(|s| {
+            s.split(",")
+                .filter(|range| !range.trim().is_empty())
+                .map(|range| {
+                    ip_filter::parse_range(range).unwrap_or_else(|error| panic!("invalid {}: {}", var, error))
+                })
+                .collect()
+        })
+        .unwrap_or(vec![])
+}
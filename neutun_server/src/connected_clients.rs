@@ -1,21 +1,305 @@
 use super::*;
+use chrono::{DateTime, Utc};
 use dashmap::DashMap;
 use std::fmt::Formatter;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::Instant;
+use thiserror::Error;
+
+/// How many control packets may queue up for a single client
+pub const CLIENT_QUEUE_SIZE: usize = 256;
 
 #[derive(Clone)]
 pub struct ConnectedClient {
     pub id: ClientId,
     pub host: String, // subdomain
     pub domain: String, // root domain
+    /// a host of the client's own, routed to it alongside its sub-domain
+    pub custom_domain: Option<String>,
+    /// the hash of the api key it authenticated with (see `key_store::hash_key`)
+    pub owner: Option<String>,
+    /// how the load balanced group it shares its hosts with spreads streams
+    pub group: Option<Balance>,
+    /// it asked to wait while another client has its hosts
+    pub standby: bool,
+    /// it only takes the requests under this path on its hosts
+    pub path_prefix: Option<String>,
     pub is_anonymous: bool,
+    /// the key store name of the key it authenticated with
+    pub key_name: Option<String>,
+    /// how many tunnels its key may have open at once
+    pub max_tunnels: Option<usize>,
     pub wildcard: bool,
-    pub tx: UnboundedSender<ControlPacket>,
+    pub protocol_version: u32,
+    pub capabilities: Vec<Capability>,
+    pub kind: TunnelKind,
+    /// forward requests to the client over http/2
+    pub http2: bool,
+    /// add forwarding headers to requests for the client
+    pub forwarded_headers: bool,
+    /// who may use the tunnel
+    pub policy: Arc<Policy>,
+    /// how hard the public may use the tunnel
+    pub rate_limiter: Arc<RateLimiter>,
+    /// the throughput cap and transfer quota of its key
+    pub quota: Arc<Quota>,
+    pub ip: IpAddr,
+    pub connected_at: DateTime<Utc>,
+    pub stats: Arc<ClientStats>,
+    /// when the client last answered a ping
+    pub last_pong: Arc<Mutex<Instant>>,
+    pub tx: Sender<ControlPacket>,
+}
+
+#[cfg(test)]
+impl ConnectedClient {
+    /// An http tunnel on `sub_domain.example.com` with nothing but a queue behind it
+    pub fn for_tests(sub_domain: &str) -> (Self, Receiver<ControlPacket>) {
+        let (tx, rx) = channel(CLIENT_QUEUE_SIZE);
+        let client = ConnectedClient {
+            id: ClientId::generate(),
+            host: sub_domain.to_string(),
+            domain: "example.com".to_string(),
+            custom_domain: None,
+            owner: None,
+            group: None,
+            standby: false,
+            path_prefix: None,
+            is_anonymous: true,
+            key_name: None,
+            max_tunnels: None,
+            wildcard: false,
+            protocol_version: neutun_lib::PROTOCOL_VERSION,
+            capabilities: neutun_lib::CAPABILITIES.to_vec(),
+            kind: TunnelKind::Http,
+            http2: false,
+            forwarded_headers: true,
+            policy: Arc::new(Policy::default()),
+            rate_limiter: Arc::new(RateLimiter::new(&Default::default())),
+            quota: Arc::new(Quota::default()),
+            ip: IpAddr::from([127, 0, 0, 1]),
+            connected_at: Utc::now(),
+            stats: Arc::new(ClientStats::new(format!("{}.example.com", sub_domain))),
+            last_pong: Arc::new(Mutex::new(Instant::now())),
+            tx,
+        };
+        (client, rx)
+    }
+}
+
+/// What a new client asks for on its hosts, to check against the clients already there
+#[derive(Debug, Clone, Copy)]
+pub struct HostClaim<'a> {
+    pub kind: TunnelKind,
+    /// the hash of the api key it connects with (see `key_store::hash_key`)
+    pub owner: Option<&'a str>,
+    pub group: Option<Balance>,
+    pub standby: bool,
+    pub path_prefix: Option<&'a str>,
+}
+
+/// The clients on a host: more than one member only for a load balanced group,
+/// and the standbys waiting to take over once the members are gone
+#[derive(Default)]
+struct HostClients {
+    members: Vec<ConnectedClient>,
+    /// first come, first promoted
+    standbys: Vec<ConnectedClient>,
+    /// the last member round robin picked
+    next: usize,
+}
+
+impl HostClients {
+    /// Add a client, or replace it if it's on the host already.
+    /// Returns false, leaving the host alone, if the client may not share it with the clients on it.
+    fn insert(&mut self, client: ConnectedClient) -> bool {
+        if let Some(existing) = self
+            .members
+            .iter_mut()
+            .chain(self.standbys.iter_mut())
+            .find(|c| c.id == client.id)
+        {
+            *existing = client;
+            return true;
+        }
+
+        let is_empty = self.members.is_empty() && self.standbys.is_empty();
+        if !is_empty && !self.may_take(&client.claim()) {
+            return false;
+        }
+        if client.standby && !self.members.is_empty() {
+            self.standbys.push(client);
+        } else {
+            self.members.push(client);
+        }
+        true
+    }
+
+    /// A client may share the host by joining its group or waiting as a standby,
+    /// with the same key and kind of tunnel as everyone on it
+    fn may_take(&self, claim: &HostClaim) -> bool {
+        let same_key = |c: &ConnectedClient| {
+            claim.owner.is_some() && c.owner.as_deref() == claim.owner && c.kind == claim.kind
+        };
+        self.members.iter().chain(&self.standbys).all(same_key)
+            && (claim.standby || (claim.group.is_some() && self.members.iter().all(|c| c.group == claim.group)))
+    }
+
+    /// The rate limiter of the clients on the host, for a client joining them.
+    /// A group shares one, so its limits are a single client's however many members it has.
+    fn rate_limiter(&self) -> Option<Arc<RateLimiter>> {
+        self.members
+            .iter()
+            .chain(&self.standbys)
+            .next()
+            .map(|c| c.rate_limiter.clone())
+    }
+
+    /// Take a client off the host, promoting the first standby once the last member is gone
+    fn remove(&mut self, client_id: &ClientId) -> Option<ConnectedClient> {
+        self.members.retain(|c| &c.id != client_id);
+        self.standbys.retain(|c| &c.id != client_id && !c.tx.is_closed());
+        if !self.members.is_empty() || self.standbys.is_empty() {
+            return None;
+        }
+
+        let promoted = self.standbys.remove(0);
+        self.members.push(promoted.clone());
+        Some(promoted)
+    }
+
+    /// The member to take the next stream
+    fn pick(&mut self) -> Option<ConnectedClient> {
+        // skip the members whose connection is gone, they're on their way out
+        let live: Vec<&ConnectedClient> = self.members.iter().filter(|c| !c.tx.is_closed()).collect();
+        let picked = match (live.len(), live.first().and_then(|c| c.group)) {
+            (0, _) => self.members.first(),
+            (1, _) | (_, None) => live.first().copied(),
+            (_, Some(Balance::RoundRobin)) => {
+                self.next = self.next.wrapping_add(1);
+                live.get(self.next % live.len()).copied()
+            }
+            (_, Some(Balance::LeastStreams)) => live
+                .iter()
+                .min_by_key(|c| c.stats.streams())
+                .copied(),
+        };
+        picked.cloned()
+    }
+
+    /// A member that would take streams, without moving round robin on
+    fn any(&self) -> Option<&ConnectedClient> {
+        self.members.iter().find(|c| !c.tx.is_closed()).or(self.members.first())
+    }
+}
+
+/// Traffic through a client's tunnel, shared by all of its clones
+#[derive(Debug)]
+pub struct ClientStats {
+    /// bytes from the public, forwarded to the client
+    bytes_in: AtomicU64,
+    /// bytes from the client, forwarded to the public
+    bytes_out: AtomicU64,
+    /// the metrics of the host it counts under (see `ClientStats::new`), counting the same bytes across all of its clients
+    host_bytes: metrics::HostBytes,
+    /// streams open through the client, see `OpenStream`
+    streams: AtomicUsize,
+}
+
+/// Counts a stream among its client's open streams until it's dropped
+#[derive(Debug)]
+pub struct OpenStream(Arc<ClientStats>);
+
+impl Drop for OpenStream {
+    fn drop(&mut self) {
+        self.0.streams.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl ClientStats {
+    /// Stats counted under a tunnel's host in the metrics: its custom domain if it has one, its sub-domain host otherwise
+    pub fn new(host: String) -> Self {
+        ClientStats {
+            bytes_in: AtomicU64::new(0),
+            bytes_out: AtomicU64::new(0),
+            host_bytes: metrics::HostBytes::new(host),
+            streams: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn open_stream(self: &Arc<Self>) -> OpenStream {
+        self.streams.fetch_add(1, Ordering::Relaxed);
+        OpenStream(self.clone())
+    }
+
+    pub fn streams(&self) -> usize {
+        self.streams.load(Ordering::Relaxed)
+    }
+
+    pub fn add_in(&self, n: usize) {
+        self.bytes_in.fetch_add(n as u64, Ordering::Relaxed);
+        self.host_bytes.bytes_in.inc_by(n as u64);
+    }
+
+    pub fn add_out(&self, n: usize) {
+        self.bytes_out.fetch_add(n as u64, Ordering::Relaxed);
+        self.host_bytes.bytes_out.inc_by(n as u64);
+    }
+
+    pub fn bytes_in(&self) -> u64 {
+        self.bytes_in.load(Ordering::Relaxed)
+    }
+
+    pub fn bytes_out(&self) -> u64 {
+        self.bytes_out.load(Ordering::Relaxed)
+    }
+
+    pub fn host(&self) -> &str {
+        self.host_bytes.host()
+    }
 }
 
 impl ConnectedClient {
     pub fn full_host(&self) -> String {
         format!("{}.{}", self.host, self.domain)
     }
+
+    /// Every host routed to the client, with its path prefix
+    pub fn routes(&self) -> Vec<String> {
+        std::iter::once(self.full_host())
+            .chain(self.custom_domain.clone())
+            .map(|host| route(&host, self.path_prefix.as_deref()))
+            .collect()
+    }
+
+    pub fn supports(&self, capability: Capability) -> bool {
+        self.capabilities.contains(&capability)
+    }
+
+    fn claim(&self) -> HostClaim<'_> {
+        HostClaim {
+            kind: self.kind,
+            owner: self.owner.as_deref(),
+            group: self.group,
+            standby: self.standby,
+            path_prefix: self.path_prefix.as_deref(),
+        }
+    }
+
+    /// Tell the client it takes traffic now
+    fn promote(&self) {
+        tracing::info!(client_id = %self.id, host = %self.full_host(), "promoting standby");
+        if !self.supports(Capability::Notices) {
+            return;
+        }
+
+        let mut tx = self.tx.clone();
+        tokio::spawn(async move {
+            let _ = tx.send(ControlPacket::Notice(Notice::Promoted)).await;
+        });
+    }
 }
 
 impl std::fmt::Debug for ConnectedClient {
@@ -24,14 +308,61 @@ impl std::fmt::Debug for ConnectedClient {
             .field("id", &self.id)
             .field("sub", &self.host)
             .field("domain", &self.domain)
+            .field("custom_domain", &self.custom_domain)
+            .field("group", &self.group)
+            .field("standby", &self.standby)
+            .field("path_prefix", &self.path_prefix)
             .field("anon", &self.is_anonymous)
+            .field("key", &self.key_name)
+            .field("ip", &self.ip)
+            .field("kind", &self.kind)
+            .field("http2", &self.http2)
+            .field("protocol", &self.protocol_version)
+            .field("capabilities", &self.capabilities)
             .finish()
     }
 }
 
 pub struct Connections {
     clients: Arc<DashMap<ClientId, ConnectedClient>>,
-    hosts: Arc<DashMap<String, ConnectedClient>>,
+    /// by host and path prefix, see `route`
+    hosts: Arc<DashMap<String, HostClients>>,
+    /// held while a new client claims its custom domain or a tunnel of its key, so two can't both take it
+    claiming: Mutex<()>,
+}
+
+/// Why a new client can't be added after all: another client got there first
+#[derive(Error, Debug)]
+pub enum AddError {
+    #[error("{0} is in use already")]
+    HostTaken(String),
+
+    #[error("This key already has the most tunnels it may open at once ({0})")]
+    TooManyTunnels(usize),
+}
+
+/// Where a client takes requests: the host, followed by its path prefix if it has one
+pub fn route(host: &str, path_prefix: Option<&str>) -> String {
+    format!("{}{}", host, path_prefix.unwrap_or_default())
+}
+
+/// The routes that may take a request for the path on the host, the longest path prefix first
+fn path_routes(host: &str, path: &str) -> Vec<String> {
+    let mut route = route(host, Some(path));
+    let mut routes = vec![route.clone()];
+    // only cut at the slashes of the path, so `/api` doesn't take `/apis`
+    while let Some(slash) = route.rfind('/').filter(|slash| *slash >= host.len()) {
+        route.truncate(slash);
+        routes.push(route.clone());
+    }
+    routes
+}
+
+/// Whether the route is on the host, under any path prefix
+fn is_on_host(route: &str, host: &str) -> bool {
+    route
+        .strip_prefix(host)
+        .is_some_and(|path| path.is_empty() || path.starts_with('/'))
 }
 
 impl Connections {
@@ -39,35 +370,71 @@ impl Connections {
         Self {
             clients: Arc::new(DashMap::new()),
             hosts: Arc::new(DashMap::new()),
+            claiming: Mutex::new(()),
         }
     }
 
-    pub fn update_host(client: &ConnectedClient) {
-        CONNECTIONS
-            .hosts
-            .insert(client.full_host(), client.clone());
+    /// Put the client on its routes, failing on the first one another client holds that it may not share
+    fn update_host(client: &ConnectedClient) -> Result<(), AddError> {
+        for route in client.routes() {
+            if !CONNECTIONS.hosts.entry(route.clone()).or_default().insert(client.clone()) {
+                return Err(AddError::HostTaken(route));
+            }
+        }
+        Ok(())
     }
 
     pub fn remove(client: &ConnectedClient) {
-        client.tx.close_channel();
+        client.tx.clone().close_channel();
+        tcp_tunnel::release(&client.id);
 
-        // ensure another client isn't using this host
-        let full_host = client.full_host();
-        if CONNECTIONS
-            .hosts
-            .get(&full_host)
-            .map_or(false, |c| c.id == client.id)
-        {
-            tracing::debug!("dropping sub-domain: {}", &full_host);
-            CONNECTIONS.hosts.remove(&full_host);
-        };
+        // wake up any streams waiting on this client for credit
+        for stream in ACTIVE_STREAMS.iter() {
+            if stream.client.id == client.id {
+                stream.window.close();
+            }
+        }
+
+        // leave the other clients on these hosts alone
+        let mut promoted: Vec<ConnectedClient> = vec![];
+        for route in client.routes() {
+            if let Some(standby) = CONNECTIONS
+                .hosts
+                .get_mut(&route)
+                .and_then(|mut clients| clients.remove(&client.id))
+            {
+                if !promoted.iter().any(|c| c.id == standby.id) {
+                    promoted.push(standby);
+                }
+            }
+            if CONNECTIONS
+                .hosts
+                .remove_if(&route, |_, clients| clients.members.is_empty())
+                .is_some()
+            {
+                tracing::debug!("dropping route: {}", &route);
+            }
+        }
+        promoted.iter().for_each(ConnectedClient::promote);
 
         CONNECTIONS.clients.remove(&client.id);
         tracing::debug!("rm client: {}", &client.id);
+
+        let _claiming = CONNECTIONS.claiming.lock().unwrap();
+        // stop renewing the certificate of a custom domain nobody routes any more
+        if let Some(custom_domain) = &client.custom_domain {
+            let in_use = CONNECTIONS
+                .clients
+                .iter()
+                .any(|c| c.custom_domain.as_ref() == Some(custom_domain));
+            if !in_use {
+                crate::acme::remove_custom_domain(custom_domain);
+            }
+        }
     }
 
-    pub fn client_for_host(host: &String) -> Option<ClientId> {
-        CONNECTIONS.hosts.get(host).map(|c| c.id.clone())
+    pub fn client_for_host(host: &str) -> Option<ClientId> {
+        Self::peek_by_host(host).map(|c| c.id)
     }
 
     pub fn get(client_id: &ClientId) -> Option<ConnectedClient> {
@@ -77,27 +444,403 @@ impl Connections {
             .map(|c| c.value().clone())
     }
 
+    /// The client to take a new stream for the host, balanced over the members of a group
     pub fn find_by_host(host: &String) -> Option<ConnectedClient> {
-        CONNECTIONS.hosts.get(host).map(|c| c.value().clone())
+        CONNECTIONS.hosts.get_mut(host)?.pick()
+    }
+
+    /// The client to take a new stream for the request path on the host, by the longest path prefix that matches it
+    pub fn find_by_path(host: &str, path: &str) -> Option<ConnectedClient> {
+        path_routes(host, path)
+            .iter()
+            .find_map(|route| CONNECTIONS.hosts.get_mut(route).and_then(|mut clients| clients.pick()))
+    }
+
+    /// Like `find_by_host`, for when we only ask who takes the host and won't open a stream to it
+    pub fn peek_by_host(host: &str) -> Option<ConnectedClient> {
+        CONNECTIONS.hosts.get(host)?.any().cloned()
+    }
+
+    /// Like `find_by_path`, for when we only ask who takes the path and won't open a stream to it
+    pub fn peek_by_path(host: &str, path: &str) -> Option<ConnectedClient> {
+        path_routes(host, path)
+            .iter()
+            .find_map(|route| CONNECTIONS.hosts.get(route)?.any().cloned())
+    }
+
+    /// Whether a new client may have the host: nobody has its path on it, or it joins the group or stands by for the clients that do.
+    /// Only clients of the same key share a host by path.
+    pub fn may_take(host: &str, claim: &HostClaim) -> bool {
+        let route = route(host, claim.path_prefix);
+        let may_take = CONNECTIONS
+            .hosts
+            .get(&route)
+            .is_none_or(|clients| clients.may_take(claim));
+        may_take
+            && CONNECTIONS
+                .hosts
+                .iter()
+                .filter(|entry| entry.key() != &route && is_on_host(entry.key(), host))
+                .all(|entry| {
+                    entry
+                        .members
+                        .iter()
+                        .chain(&entry.standbys)
+                        .all(|c| claim.owner.is_some() && c.owner.as_deref() == claim.owner)
+                })
     }
 
-    pub fn find_wildcard(domain: &str) -> Option<ConnectedClient> {
+    /// Whether a client of another key (see `key_store::hash_key`) is on the host, under any path prefix
+    pub fn is_taken_from(host: &str, owner: &str) -> bool {
+        CONNECTIONS
+            .hosts
+            .iter()
+            .filter(|entry| is_on_host(entry.key(), host))
+            .any(|entry| {
+                entry
+                    .members
+                    .iter()
+                    .chain(&entry.standbys)
+                    .any(|c| c.owner.as_deref() != Some(owner))
+            })
+    }
+
+    /// Whether any client here takes streams for the host, under any path prefix or by a wildcard
+    pub fn serves_host(host: &str, domain: Option<&str>) -> bool {
+        CONNECTIONS.hosts.iter().any(|entry| is_on_host(entry.key(), host))
+            || domain.is_some_and(|domain| {
+                CONNECTIONS
+                    .clients
+                    .iter()
+                    .any(|client| client.wildcard && client.domain == domain)
+            })
+    }
+
+    /// Whether the client waits for the clients holding its host
+    pub fn is_standing_by(client: &ConnectedClient) -> bool {
+        CONNECTIONS
+            .hosts
+            .get(&route(&client.full_host(), client.path_prefix.as_deref()))
+            .is_some_and(|clients| clients.standbys.iter().any(|c| c.id == client.id))
+    }
+
+    pub fn find_wildcard(domain: &str, kind: TunnelKind) -> Option<ConnectedClient> {
         for entry in CONNECTIONS.clients.iter() {
-            if entry.value().wildcard && entry.value().domain == domain {
+            if entry.value().wildcard
+                && entry.value().kind == kind
+                && entry.value().domain == domain
+            {
                 return Some(entry.value().clone());
             }
         }
         None
     }
 
+    /// How many tunnels are open with a key store key
+    pub fn count_for_key(key_name: &str) -> usize {
+        CONNECTIONS
+            .clients
+            .iter()
+            .filter(|c| c.value().key_name.as_deref() == Some(key_name))
+            .count()
+    }
+
     pub fn get_all_clients(&self) -> Vec<ConnectedClient> {
         self.clients.iter().map(|c| c.value().clone()).collect()
     }
 
-    pub fn add(client: ConnectedClient) {
+    /// Add a client, or refresh one we have already.
+    /// A new client only gets its sub-domain and custom domain if nobody took them since we checked the handshake,
+    /// and only if its key has a tunnel left. A client we have already fails to refresh once it lost a host,
+    /// it should be removed rather than take the host back.
+    pub fn add(client: ConnectedClient) -> Result<(), AddError> {
+        let _claiming = CONNECTIONS.claiming.lock().unwrap();
+        let is_new = !CONNECTIONS.clients.contains_key(&client.id);
+        if is_new {
+            for host in std::iter::once(client.full_host()).chain(client.custom_domain.clone()) {
+                if !Self::may_take(&host, &client.claim()) {
+                    return Err(AddError::HostTaken(host));
+                }
+            }
+        }
+        if let (Some(max), Some(key_name)) = (client.max_tunnels.filter(|_| is_new), &client.key_name) {
+            if Self::count_for_key(key_name) >= max {
+                return Err(AddError::TooManyTunnels(max));
+            }
+        }
+
+        let mut client = client;
+        let primary = route(&client.full_host(), client.path_prefix.as_deref());
+        if let Some(rate_limiter) = CONNECTIONS.hosts.get(&primary).and_then(|clients| clients.rate_limiter()) {
+            client.rate_limiter = rate_limiter;
+        }
+
         CONNECTIONS
             .clients
             .insert(client.id.clone(), client.clone());
-        CONNECTIONS.hosts.insert(client.full_host(), client);
+        Self::update_host(&client)?;
+
+        // tls passthrough tunnels bring their own certificate
+        if let Some(custom_domain) = client.custom_domain.as_deref().filter(|_| is_new) {
+            if client.kind == TunnelKind::Http {
+                crate::acme::add_custom_domain(custom_domain);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn a_key_gets_no_more_tunnels_than_it_may_have() {
+        let client = |sub_domain: &str| {
+            let (client, rx) = ConnectedClient::for_tests(sub_domain);
+            let client = ConnectedClient {
+                key_name: Some("max-tunnels-test".to_string()),
+                max_tunnels: Some(2),
+                ..client
+            };
+            (client, rx)
+        };
+        let (first, _first_rx) = client("max-tunnels-1");
+        let (second, _second_rx) = client("max-tunnels-2");
+        let (third, _third_rx) = client("max-tunnels-3");
+
+        Connections::add(first.clone()).unwrap();
+        Connections::add(second.clone()).unwrap();
+        assert!(matches!(Connections::add(third.clone()), Err(AddError::TooManyTunnels(2))));
+        assert!(Connections::find_by_host(&third.full_host()).is_none());
+        // the ones it has may still refresh
+        Connections::add(second.clone()).unwrap();
+
+        Connections::remove(&first);
+        Connections::add(third.clone()).unwrap();
+        Connections::remove(&second);
+        Connections::remove(&third);
+        assert_eq!(Connections::count_for_key("max-tunnels-test"), 0);
+    }
+
+    /// A client of `owner`'s key on `host.example.com`
+    fn keyed(owner: Option<&str>, group: Option<Balance>, standby: bool) -> (ConnectedClient, Receiver<ControlPacket>) {
+        let (client, rx) = ConnectedClient::for_tests("host");
+        let client = ConnectedClient {
+            owner: owner.map(str::to_string),
+            group,
+            standby,
+            ..client
+        };
+        (client, rx)
+    }
+
+    fn ids(clients: &[ConnectedClient]) -> Vec<&ClientId> {
+        clients.iter().map(|c| &c.id).collect()
+    }
+
+    #[test]
+    fn a_group_shares_its_host_round_robin() {
+        let mut host = HostClients::default();
+        let (a, _a_rx) = keyed(Some("k"), Some(Balance::RoundRobin), false);
+        let (b, _b_rx) = keyed(Some("k"), Some(Balance::RoundRobin), false);
+        assert!(host.may_take(&a.claim()));
+        host.insert(a.clone());
+        assert!(host.may_take(&b.claim()));
+        host.insert(b.clone());
+        assert_eq!(ids(&host.members), [&a.id, &b.id]);
+
+        // only the same key and group may join
+        let (other_key, _rx) = keyed(Some("other"), Some(Balance::RoundRobin), false);
+        let (other_group, _rx) = keyed(Some("k"), Some(Balance::LeastStreams), false);
+        let (no_group, _rx) = keyed(Some("k"), None, false);
+        let (anonymous, _rx) = keyed(None, Some(Balance::RoundRobin), false);
+        for claim in [&other_key, &other_group, &no_group, &anonymous].map(ConnectedClient::claim) {
+            assert!(!host.may_take(&claim));
+        }
+
+        let picked: Vec<ClientId> = (0..4).map(|_| host.pick().unwrap().id).collect();
+        assert_eq!(picked, [b.id.clone(), a.id.clone(), b.id.clone(), a.id.clone()]);
+
+        // asking who takes the host doesn't count as a pick
+        assert_eq!(host.any().unwrap().id, a.id);
+        assert_eq!(host.pick().unwrap().id, b.id);
+    }
+
+    #[test]
+    fn least_streams_picks_the_least_busy_member() {
+        let mut host = HostClients::default();
+        let (a, _a_rx) = keyed(Some("k"), Some(Balance::LeastStreams), false);
+        let (b, _b_rx) = keyed(Some("k"), Some(Balance::LeastStreams), false);
+        host.insert(a.clone());
+        host.insert(b.clone());
+
+        let open = a.stats.open_stream();
+        assert_eq!(host.pick().unwrap().id, b.id);
+        let _open = [b.stats.open_stream(), b.stats.open_stream()];
+        assert_eq!(host.pick().unwrap().id, a.id);
+
+        drop(open);
+        assert_eq!(a.stats.streams(), 0);
+    }
+
+    #[test]
+    fn skips_members_that_are_gone() {
+        let mut host = HostClients::default();
+        let (a, a_rx) = keyed(Some("k"), Some(Balance::RoundRobin), false);
+        let (b, _b_rx) = keyed(Some("k"), Some(Balance::RoundRobin), false);
+        host.insert(a.clone());
+        host.insert(b.clone());
+
+        drop(a_rx);
+        for _ in 0..3 {
+            assert_eq!(host.pick().unwrap().id, b.id);
+        }
+        assert_eq!(host.any().unwrap().id, b.id);
+    }
+
+    #[test]
+    fn promotes_the_first_standby_once_the_members_are_gone() {
+        let mut host = HostClients::default();
+        let (a, _a_rx) = keyed(Some("k"), None, false);
+        let (first, _first_rx) = keyed(Some("k"), None, true);
+        let (second, _second_rx) = keyed(Some("k"), None, true);
+        host.insert(a.clone());
+        assert!(host.may_take(&first.claim()));
+        host.insert(first.clone());
+        host.insert(second.clone());
+        assert_eq!(ids(&host.standbys), [&first.id, &second.id]);
+        assert_eq!(host.pick().unwrap().id, a.id);
+
+        assert_eq!(host.remove(&a.id).map(|c| c.id), Some(first.id.clone()));
+        assert_eq!(ids(&host.members), [&first.id]);
+        assert_eq!(ids(&host.standbys), [&second.id]);
+
+        // a standby leaving promotes nobody
+        assert!(host.remove(&second.id).is_none());
+        assert!(host.remove(&first.id).is_none());
+        assert!(host.members.is_empty());
+    }
+
+    #[test]
+    fn a_client_that_may_not_share_is_turned_away() {
+        let mut host = HostClients::default();
+        let (a, _a_rx) = keyed(Some("k"), None, false);
+        let (standby, _standby_rx) = keyed(Some("k"), None, true);
+        assert!(host.insert(a.clone()));
+        assert!(host.insert(standby.clone()));
+
+        // another key can't take it over, nor can an anonymous client, even standing by
+        let (other, _other_rx) = keyed(Some("other"), None, false);
+        let (anonymous, _anonymous_rx) = keyed(None, None, true);
+        for client in [other, anonymous] {
+            assert!(!host.may_take(&client.claim()));
+            assert!(!host.insert(client));
+        }
+        assert_eq!(ids(&host.members), [&a.id]);
+        assert_eq!(ids(&host.standbys), [&standby.id]);
+
+        // while a client already on the host is just refreshed
+        assert!(host.insert(a.clone()));
+        assert_eq!(host.members.len(), 1);
+    }
+
+    #[test]
+    fn only_one_key_gets_a_host_however_close_they_connect() {
+        let (a, _a_rx) = ConnectedClient::for_tests("add-race");
+        let (b, _b_rx) = ConnectedClient::for_tests("add-race");
+        let a = ConnectedClient { owner: Some("add-race-a".to_string()), ..a };
+        let b = ConnectedClient { owner: Some("add-race-b".to_string()), ..b };
+
+        // both passed their handshakes before either was added
+        Connections::add(a.clone()).unwrap();
+        assert!(matches!(Connections::add(b.clone()), Err(AddError::HostTaken(_))));
+        assert_eq!(Connections::peek_by_host(&a.full_host()).unwrap().id, a.id);
+        assert!(Connections::get(&b.id).is_none());
+
+        // a client that lost its host can't take it back by refreshing
+        Connections::remove(&a);
+        Connections::add(b.clone()).unwrap();
+        CONNECTIONS.clients.insert(a.id.clone(), a.clone());
+        assert!(matches!(Connections::add(a.clone()), Err(AddError::HostTaken(_))));
+        assert_eq!(Connections::peek_by_host(&b.full_host()).unwrap().id, b.id);
+
+        CONNECTIONS.clients.remove(&a.id);
+        Connections::remove(&b);
+    }
+
+    #[test]
+    fn reports_bytes_per_host_while_anyone_counts_on_it() {
+        let series = "host=\"bytes-metrics.example.com\"";
+        let (a, a_rx) = ConnectedClient::for_tests("bytes-metrics");
+        Connections::add(a.clone()).unwrap();
+        a.stats.add_in(10);
+        assert!(metrics::render().contains(series));
+
+        // a client reconnecting on the host has its stats before the old one is gone
+        let (b, b_rx) = ConnectedClient::for_tests("bytes-metrics");
+        Connections::remove(&a);
+        drop((a, a_rx));
+        Connections::add(b.clone()).unwrap();
+        b.stats.add_out(20);
+        assert!(metrics::render().contains(&format!("neutun_bytes_total{{direction=\"out\",{}}} 20", series)));
+
+        Connections::remove(&b);
+        drop((b, b_rx));
+        assert!(!metrics::render().contains(series));
+    }
+
+    #[test]
+    fn a_group_shares_its_rate_limits() {
+        let member = || {
+            let (client, rx) = ConnectedClient::for_tests("shared-limits");
+            let client = ConnectedClient {
+                owner: Some("shared-limits-key".to_string()),
+                group: Some(Balance::RoundRobin),
+                ..client
+            };
+            (client, rx)
+        };
+        let (a, _a_rx) = member();
+        let (b, _b_rx) = member();
+
+        Connections::add(a.clone()).unwrap();
+        Connections::add(b.clone()).unwrap();
+        // refreshing on a ping doesn't bring back the limiter the client connected with
+        Connections::add(b.clone()).unwrap();
+        let shares = |client: &ConnectedClient| Arc::ptr_eq(&client.rate_limiter, &a.rate_limiter);
+        assert!(Connections::get(&b.id).is_some_and(|b| shares(&b)));
+        assert!(CONNECTIONS.hosts.get(&a.full_host()).unwrap().members.iter().all(shares));
+
+        Connections::remove(&a);
+        Connections::remove(&b);
+    }
+
+    #[test]
+    fn tries_the_longest_path_prefix_first() {
+        assert_eq!(
+            path_routes("a.example.com", "/api/v1/users"),
+            ["a.example.com/api/v1/users", "a.example.com/api/v1", "a.example.com/api", "a.example.com"]
+        );
+        assert_eq!(path_routes("a.example.com", "/"), ["a.example.com/", "a.example.com"]);
+        assert_eq!(path_routes("a.example.com", "/api/"), ["a.example.com/api/", "a.example.com/api", "a.example.com"]);
+        // a path that isn't one still finds the tunnel without a prefix
+        assert_eq!(path_routes("a.example.com", ""), ["a.example.com"]);
+    }
+
+    #[test]
+    fn matches_whole_path_segments_only() {
+        let routes = path_routes("a.example.com", "/apis/x");
+        assert!(!routes.contains(&route("a.example.com", Some("/api"))));
+        assert!(routes.contains(&route("a.example.com", Some("/apis"))));
+    }
+
+    #[test]
+    fn routes_are_on_their_host() {
+        assert!(is_on_host("a.example.com", "a.example.com"));
+        assert!(is_on_host("a.example.com/api", "a.example.com"));
+        assert!(!is_on_host("a.example.com.evil", "a.example.com"));
+        assert!(!is_on_host("ab.example.com/api", "a"));
+        assert!(!is_on_host("b.example.com", "a.example.com"));
     }
 }
@@ -2,15 +2,29 @@ pub use super::*;
 use crate::auth::reconnect_token::ReconnectTokenPayload;
 use crate::client_auth::ClientHandshake;
 use chrono::Utc;
+use hyper::service::{service_fn, Service};
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use hyper_util::server::conn::auto;
+use hyper_util::service::TowerToHyperService;
+use crate::ip_filter;
+use ipnet::IpNet;
 use std::net::{IpAddr, SocketAddr};
 use std::str::FromStr;
-use std::time::Duration;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
 use tracing::{error, Instrument};
 
+/// How long a client may go without answering our pings before we consider it dead
+const PONG_TIMEOUT: Duration = Duration::from_secs(PING_INTERVAL * 2 + 10);
+
+/// The address a control connection came from, stashed in each request's extensions
+#[derive(Debug, Clone, Copy)]
+struct PeerAddr(SocketAddr);
+
 pub fn spawn<A: Into<SocketAddr>>(addr: A) {
     let health_check = warp::get().and(warp::path("health_check")).map(|| {
         tracing::debug!("Health Check #2 triggered");
-        "ok"
+        String::from("ok")
     });
 
     // Add endpoint to list allowed domains
@@ -27,7 +41,7 @@ pub fn spawn<A: Into<SocketAddr>>(addr: A) {
             if c.wildcard {
                 format!("*.{}", c.domain)
             } else {
-                format!("{}.{}", c.host, c.domain)
+                connected_clients::route(&c.full_host(), c.path_prefix.as_deref())
             }
         }).collect();
         warp::reply::json(&taken)
@@ -42,59 +56,197 @@ pub fn spawn<A: Into<SocketAddr>>(addr: A) {
         },
     );
 
-    let routes = client_conn.or(health_check).or(list_domains).or(list_taken);
+    let routes = client_conn
+        .or(health_check)
+        .or(list_domains)
+        .or(list_taken)
+        .or(admin::metrics())
+        .or(admin::routes());
 
     // spawn our websocket control server
-    tokio::spawn(warp::serve(routes).run(addr.into()));
+    let addr = addr.into();
+    let service = TowerToHyperService::new(warp::service(routes));
+    tokio::spawn(async move {
+        let listener = TcpListener::bind(addr)
+            .await
+            .expect("failed to bind control server");
+
+        loop {
+            let (socket, peer_addr) = match listener.accept().await {
+                Ok(accepted) => accepted,
+                Err(error) => {
+                    error!(?error, "failed to accept control socket");
+                    tokio::time::sleep(tcp_tunnel::ACCEPT_ERROR_BACKOFF).await;
+                    continue;
+                }
+            };
+
+            let service = service.clone();
+            tokio::spawn(async move {
+                let mut socket = RemoteStream::new(socket);
+                let peer_addr = match control_peer(&mut socket, peer_addr, &IP_FILTER).await {
+                    Some(peer_addr) => peer_addr,
+                    None => return,
+                };
+
+                let service = service_fn(move |mut request: hyper::Request<_>| {
+                    request.extensions_mut().insert(PeerAddr(peer_addr));
+                    service.call(request)
+                });
+
+                // plaintext is still accepted, i.e. behind a reverse proxy or redirected from the public port
+                let socket = match tls::accept(socket).await {
+                    Some(socket) => socket,
+                    None => return,
+                };
+
+                let _ = auto::Builder::new(TokioExecutor::new())
+                    .serve_connection_with_upgrades(TokioIo::new(socket), service)
+                    .await
+                    .map_err(|error| tracing::debug!(?error, "control connection error"));
+            });
+        }
+    });
+}
+
+/// Who a control connection from `peer_addr` is for, if they may connect.
+/// Our public port passes requests for us on from loopback with a PROXY protocol header (see `remote_http::to_control`),
+/// so those are checked, and later known, by the visitor's address rather than ours.
+async fn control_peer(socket: &mut RemoteStream, peer_addr: SocketAddr, ip_filter: &IpFilter) -> Option<SocketAddr> {
+    let proxied = CONFIG.proxy_protocol.control
+        || (peer_addr.ip().to_canonical().is_loopback() && socket.starts_with_proxy_header().await.unwrap_or(false));
+    let peer_addr = if proxied {
+        socket.proxied_peer_addr(peer_addr).await?
+    } else {
+        peer_addr
+    };
+
+    if !ip_filter.allows(peer_addr.ip()) {
+        tracing::debug!(%peer_addr, "control ip is not allowed, dropping connection");
+        return None;
+    }
+    Some(peer_addr)
 }
 
 fn client_ip() -> impl Filter<Extract = (IpAddr,), Error = warp::Rejection> + Copy {
     warp::any()
         .and(warp::header::optional::<String>("X-Forwarded-For"))
         .and(warp::header::optional::<String>("X-Real-IP"))
+        .and(warp::ext::optional::<PeerAddr>())
         .map(
-            |fwd: Option<String>, real_ip: Option<String>| {
-                // Try X-Forwarded-For first (first IP in the comma-separated list)
-                let from_fwd = fwd.and_then(|s| {
-                    s.split(',')
-                        .next()
-                        .and_then(|ip| IpAddr::from_str(ip.trim()).ok())
-                });
-                // Then try X-Real-IP
-                let from_real = real_ip.and_then(|s| IpAddr::from_str(s.trim()).ok());
-                from_fwd
-                    .or(from_real)
+            |fwd: Option<String>, real_ip: Option<String>, peer: Option<PeerAddr>| {
+                let peer = peer.map(|p| p.0.ip().to_canonical());
+                forwarded_client_ip(peer, fwd.as_deref(), real_ip.as_deref(), &CONFIG.trusted_proxies)
                     .unwrap_or(IpAddr::from([0, 0, 0, 0]))
             },
         )
 }
 
+/// The client behind a control connection from `peer`, the socket's or the PROXY protocol's address.
+/// Forwarding headers are anyone's to send, so they only count when `peer` is one of our trusted proxies:
+/// the client is then the last address in `X-Forwarded-For` that isn't a trusted proxy as well.
+fn forwarded_client_ip(
+    peer: Option<IpAddr>,
+    fwd: Option<&str>,
+    real_ip: Option<&str>,
+    trusted_proxies: &[IpNet],
+) -> Option<IpAddr> {
+    let is_trusted = |ip: &IpAddr| ip_filter::contains(trusted_proxies, *ip);
+    if !peer.is_some_and(|peer| is_trusted(&peer)) {
+        return peer;
+    }
+
+    let from_fwd = fwd.and_then(|fwd| {
+        fwd.rsplit(',')
+            .map(|ip| IpAddr::from_str(ip.trim()).map(|ip| ip.to_canonical()))
+            .find(|ip| !ip.as_ref().is_ok_and(is_trusted))?
+            .ok()
+    });
+    let from_real = real_ip.and_then(|ip| IpAddr::from_str(ip.trim()).ok());
+    from_fwd.or(from_real).or(peer)
+}
+
 #[tracing::instrument(skip(websocket))]
 async fn handle_new_connection(client_ip: IpAddr, websocket: WebSocket) {
     // check if this client is blocked
-    if CONFIG.blocked_ips.contains(&client_ip) {
-        tracing::warn!(?client_ip, "client ip is on block list, denying connection");
+    if !IP_FILTER.allows(client_ip) {
+        tracing::warn!(?client_ip, "client ip is not allowed, denying connection");
         let _ = websocket.close().await;
         return;
     }
 
-    let (websocket, handshake) = match try_client_handshake(websocket).await {
+    let (mut websocket, handshake, tcp_listener, hello) = match try_client_handshake(websocket).await {
         Some(ws) => ws,
         None => return,
     };
 
     tracing::info!(client_ip=%client_ip, subdomain=%handshake.sub_domain, domain=%handshake.domain, "open tunnel");
 
-    let (tx, rx) = unbounded::<ControlPacket>();
+    let (tx, rx) = channel::<ControlPacket>(CLIENT_QUEUE_SIZE);
+    let stats_host = handshake
+        .custom_domain
+        .clone()
+        .unwrap_or_else(|| format!("{}.{}", handshake.sub_domain, handshake.domain));
+    let stats = Arc::new(ClientStats::new(stats_host));
+    let quota = Arc::new(Quota::new(handshake.key_name.as_deref()));
     let mut client = ConnectedClient {
         id: handshake.id,
         host: handshake.sub_domain,
         domain: handshake.domain,
+        custom_domain: handshake.custom_domain,
+        owner: handshake.owner,
+        group: handshake.group,
+        standby: handshake.standby,
+        path_prefix: handshake.path_prefix,
         is_anonymous: handshake.is_anonymous,
+        key_name: handshake.key_name,
+        max_tunnels: handshake.max_tunnels,
         wildcard: handshake.wildcard,
+        protocol_version: handshake.protocol_version,
+        capabilities: handshake.capabilities,
+        kind: handshake.kind,
+        http2: handshake.http2,
+        forwarded_headers: handshake.forwarded_headers,
+        policy: Arc::new(handshake.policy),
+        rate_limiter: Arc::new(RateLimiter::new(&handshake.rate_limits)),
+        quota,
+        ip: client_ip,
+        connected_at: Utc::now(),
+        stats,
+        last_pong: Arc::new(Mutex::new(Instant::now())),
         tx,
     };
-    Connections::add(client.clone());
+    // claim the hosts before telling the client it has them
+    if let Err(error) = Connections::add(client.clone()) {
+        error!(%error, "client lost a race while it connected");
+        let hello = match error {
+            AddError::HostTaken(_) => ServerHello::SubDomainInUse,
+            AddError::TooManyTunnels(_) => ServerHello::Error(error.to_string()),
+        };
+        client_auth::reject(&mut websocket, hello).await;
+        return;
+    }
+
+    let data = serde_json::to_vec(&hello).unwrap_or_default();
+    if let Err(error) = websocket.send(Message::binary(data)).await {
+        error!(?error, "aborting...failed to write server hello");
+        Connections::remove(&client);
+        return;
+    }
+
+    tracing::debug!(
+        "new client connected: {:?}{}",
+        &client.id,
+        if client.is_anonymous { " (anonymous)" } else { "" }
+    );
+
+    if Connections::is_standing_by(&client) && client.supports(Capability::Notices) {
+        let _ = client.tx.clone().send(ControlPacket::Notice(Notice::StandingBy)).await;
+    }
+
+    if let Some(listener) = tcp_listener {
+        tcp_tunnel::spawn(client.clone(), listener);
+    }
 
     let (sink, stream) = websocket.split();
 
@@ -120,6 +272,13 @@ async fn handle_new_connection(client_ip: IpAddr, websocket: WebSocket) {
     tokio::spawn(
         async move {
             loop {
+                // a client that stopped answering would still get streams, which matters most in a group
+                if client.last_pong.lock().unwrap().elapsed() > PONG_TIMEOUT {
+                    tracing::info!(client_id = %client.id, "client stopped answering pings, removing client");
+                    Connections::remove(&client);
+                    return;
+                }
+
                 tracing::trace!("sending ping");
 
                 // create a new reconnect token for anonymous clients
@@ -153,48 +312,60 @@ async fn handle_new_connection(client_ip: IpAddr, websocket: WebSocket) {
 }
 
 #[tracing::instrument(skip(websocket))]
-async fn try_client_handshake(websocket: WebSocket) -> Option<(WebSocket, ClientHandshake)> {
+async fn try_client_handshake(
+    websocket: WebSocket,
+) -> Option<(WebSocket, ClientHandshake, Option<TcpListener>, ServerHello)> {
     // Authenticate client handshake
     let (mut websocket, client_handshake) = client_auth::auth_client_handshake(websocket).await?;
 
-    // Send server hello success
+    // tcp tunnels get their own public port
+    let tcp_listener = match client_handshake.kind {
+        TunnelKind::Http | TunnelKind::Tls => None,
+        TunnelKind::Tcp => match tcp_tunnel::bind(client_handshake.tcp_port).await {
+            Some(listener) => Some(listener),
+            None => {
+                let hello = ServerHello::Error("No TCP tunnel ports available".into());
+                client_auth::reject(&mut websocket, hello).await;
+                return None;
+            }
+        },
+    };
+    let tcp_port = tcp_listener
+        .as_ref()
+        .and_then(|l| l.local_addr().ok())
+        .map(|addr| addr.port());
+
+    // the hostname we tell the client it has
     let hostname = if client_handshake.wildcard {
         format!("*.{}", &client_handshake.domain)
     } else {
         format!("{}.{}", &client_handshake.sub_domain, &client_handshake.domain)
     };
 
-    let data = serde_json::to_vec(&ServerHello::Success {
+    // only sent once the client has its hosts
+    let hello = ServerHello::Success {
         sub_domain: client_handshake.sub_domain.clone(),
         hostname,
         client_id: client_handshake.id.clone(),
-    })
-    .unwrap_or_default();
-
-    let send_result = websocket.send(Message::binary(data)).await;
-    if let Err(error) = send_result {
-        error!(?error, "aborting...failed to write server hello");
-        return None;
-    }
-
-    tracing::debug!(
-        "new client connected: {:?}{}",
-        &client_handshake.id,
-        if client_handshake.is_anonymous {
-            " (anonymous)"
-        } else {
-            ""
-        }
-    );
-    Some((websocket, client_handshake))
+        protocol_version: client_handshake.protocol_version,
+        capabilities: client_handshake.capabilities.clone(),
+        tcp_port,
+        reserved: client_handshake.reserved,
+    };
+    Some((websocket, client_handshake, tcp_listener, hello))
 }
 
 /// Send the client a "stream init" message
 pub async fn send_client_stream_init(mut stream: ActiveStream) {
+    let metadata = stream
+        .client
+        .supports(Capability::StreamMetadata)
+        .then(|| stream.metadata.clone());
+
     match stream
         .client
         .tx
-        .send(ControlPacket::Init(stream.id.clone()))
+        .send(ControlPacket::Init(stream.id.clone(), metadata))
         .await
     {
         Ok(_) => {
@@ -242,29 +413,74 @@ async fn process_client_messages(client: ConnectedClient, mut client_conn: Split
         let (stream_id, message) = match packet {
             ControlPacket::Data(stream_id, data) => {
                 tracing::debug!(?stream_id, num_bytes=?data.len(),"forwarding to stream");
+                client.stats.add_out(data.len());
                 (stream_id, StreamMessage::Data(data))
             }
             ControlPacket::Refused(stream_id) => {
                 tracing::debug!("tunnel says: refused");
                 (stream_id, StreamMessage::TunnelRefused)
             }
-            ControlPacket::Init(_) | ControlPacket::End(_) => {
-                error!("invalid protocol control::init message");
+            ControlPacket::End(stream_id) => {
+                tracing::debug!(?stream_id, "tunnel says: end");
+                (stream_id, StreamMessage::End)
+            }
+            ControlPacket::Init(_, _) | ControlPacket::Notice(_) => {
+                error!(packet_type = packet.packet_type(), "invalid protocol message from client");
+                continue;
+            }
+            ControlPacket::WindowUpdate(stream_id, credit) => {
+                tracing::trace!(?stream_id, credit, "window update");
+                if let Some(stream) = ACTIVE_STREAMS.get(&stream_id) {
+                    stream.window.grant(credit);
+                }
                 continue;
             }
             ControlPacket::Ping(_) => {
                 tracing::trace!("pong");
-                Connections::add(client.clone());
+                *client.last_pong.lock().unwrap() = Instant::now();
+                // a client we gave up on stays gone, and one that lost its host to another goes
+                if !client.tx.is_closed() {
+                    if let Err(error) = Connections::add(client.clone()) {
+                        tracing::info!(client_id = %client.id, %error, "client lost its host, removing client");
+                        Connections::remove(&client);
+                        return;
+                    }
+                }
                 continue;
             }
         };
 
-        let stream = ACTIVE_STREAMS.get(&stream_id).map(|s| s.value().clone());
+        // a client without flow control can only be slowed down by waiting for its streams, as before
+        if !client.supports(Capability::FlowControl) {
+            let tx = ACTIVE_STREAMS.get(&stream_id).map(|stream| stream.tx.clone());
+            if let Some(mut tx) = tx {
+                if let Err(error) = tx.send(message).await {
+                    tracing::trace!(?error, "Failed to send to stream tx");
+                }
+            }
+            continue;
+        }
 
-        if let Some(mut stream) = stream {
-            let _ = stream.tx.send(message).await.map_err(|error| {
+        // otherwise never wait on a stream here, one slow remote connection would hold up every other stream of the client, and its pongs.
+        // always send with the stream's own sender, every clone of it gets a slot of its own past the queue size.
+        let result = match ACTIVE_STREAMS.get_mut(&stream_id) {
+            Some(mut stream) => stream.tx.try_send(message),
+            None => continue,
+        };
+
+        if let Err(error) = result {
+            if error.is_full() {
+                tracing::warn!(?stream_id, "remote stream can't keep up, resetting it");
+                let _ = client.tx.clone().try_send(ControlPacket::End(stream_id.clone()));
+            } else {
                 tracing::trace!(?error, "Failed to send to stream tx");
-            });
+            }
+
+            // the remote side writes out what it has queued already, and then closes
+            if let Some((_, mut stream)) = ACTIVE_STREAMS.remove(&stream_id) {
+                stream.tx.close_channel();
+                stream.window.close();
+            }
         }
     }
 }
@@ -273,11 +489,17 @@ async fn process_client_messages(client: ConnectedClient, mut client_conn: Split
 async fn tunnel_client(
     client: ConnectedClient,
     mut sink: SplitSink<WebSocket, Message>,
-    mut queue: UnboundedReceiver<ControlPacket>,
+    mut queue: Receiver<ControlPacket>,
 ) {
     loop {
         match queue.next().await {
             Some(packet) => {
+                // an older client would choke on a packet it doesn't know
+                if let Some(capability) = packet.capability().filter(|c| !client.supports(*c)) {
+                    tracing::debug!(packet = packet.packet_type(), ?capability, "not sending packet the client doesn't support");
+                    continue;
+                }
+
                 let result = sink.send(Message::binary(packet.serialize())).await;
                 if let Err(error) = result {
                     tracing::trace!(?error, "client disconnected: aborting.");
@@ -287,8 +509,82 @@ async fn tunnel_client(
             }
             None => {
                 tracing::debug!("ending client tunnel");
+                let _ = sink.close().await;
                 return;
             }
         };
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn headers_cannot_bypass_a_block() {
+        let blocked: IpAddr = "203.0.113.99".parse().unwrap();
+        IP_FILTER.block(ip_filter::parse_range("203.0.113.99").unwrap());
+
+        let client_ip = warp::test::request()
+            .header("X-Forwarded-For", "198.51.100.1")
+            .header("X-Real-IP", "198.51.100.2")
+            .extension(PeerAddr(SocketAddr::new(blocked, 4000)))
+            .filter(&client_ip())
+            .await
+            .unwrap();
+        assert_eq!(client_ip, blocked);
+        assert!(!IP_FILTER.allows(client_ip));
+
+        IP_FILTER.unblock(&ip_filter::parse_range("203.0.113.99").unwrap());
+    }
+
+    #[test]
+    fn believes_forwarding_headers_from_trusted_proxies_only() {
+        let proxies = [ip_filter::parse_range("10.0.0.0/8").unwrap()];
+        let proxy: IpAddr = "10.0.0.1".parse().unwrap();
+        let stranger: IpAddr = "192.0.2.1".parse().unwrap();
+        let ip = |peer, fwd, real_ip| forwarded_client_ip(Some(peer), fwd, real_ip, &proxies).unwrap().to_string();
+
+        assert_eq!(ip(stranger, Some("198.51.100.1"), Some("198.51.100.2")), "192.0.2.1");
+        // whatever the client sent is on the left, the proxies append on the right
+        assert_eq!(ip(proxy, Some("198.51.100.1, 198.51.100.7, 10.0.0.2"), None), "198.51.100.7");
+        assert_eq!(ip(proxy, None, Some("198.51.100.2")), "198.51.100.2");
+        assert_eq!(ip(proxy, None, None), "10.0.0.1");
+        assert_eq!(forwarded_client_ip(None, Some("198.51.100.1"), None, &proxies), None);
+    }
+
+    #[tokio::test]
+    async fn checks_requests_from_the_public_port_by_visitor() {
+        use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt};
+
+        let visitor: SocketAddr = "198.51.100.7:4000".parse().unwrap();
+        let loopback: SocketAddr = "127.0.0.1:50000".parse().unwrap();
+        let allowlist = IpFilter::with_ranges(vec![], vec![ip_filter::parse_range("198.51.100.0/24").unwrap()]);
+
+        // passed on by `remote_http::to_control`
+        let (mut public_port, server) = duplex(1024);
+        let mut socket = RemoteStream::new(server);
+        let header = ProxyHeader {
+            addresses: Some((visitor, "127.0.0.1:5000".parse().unwrap())),
+            tls: false,
+        };
+        public_port.write_all(&header.to_v2()).await.unwrap();
+        public_port.write_all(b"GET / HTTP/1.1\r\n").await.unwrap();
+        assert_eq!(control_peer(&mut socket, loopback, &allowlist).await, Some(visitor));
+        let mut rest = [0; 16];
+        socket.read_exact(&mut rest).await.unwrap();
+        assert_eq!(&rest, b"GET / HTTP/1.1\r\n");
+
+        // anyone else on loopback, i.e. a reverse proxy, is checked as itself
+        let (mut proxy, server) = duplex(1024);
+        let mut socket = RemoteStream::new(server);
+        proxy.write_all(b"GET / HTTP/1.1\r\n").await.unwrap();
+        assert_eq!(control_peer(&mut socket, loopback, &allowlist).await, None);
+
+        // and only loopback may say who it's for
+        let (mut stranger, server) = duplex(1024);
+        let mut socket = RemoteStream::new(server);
+        stranger.write_all(&header.to_v2()).await.unwrap();
+        assert_eq!(control_peer(&mut socket, "192.0.2.1:4000".parse().unwrap(), &allowlist).await, None);
+    }
+}
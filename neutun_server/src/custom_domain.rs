@@ -0,0 +1,223 @@
+use super::*;
+use sha2::Digest;
+use std::net::SocketAddr;
+use thiserror::Error;
+use trust_dns_resolver::config::{NameServerConfigGroup, ResolverConfig, ResolverOpts};
+use trust_dns_resolver::error::{ResolveError, ResolveErrorKind};
+use trust_dns_resolver::TokioAsyncResolver;
+
+/// The label under a custom domain that holds its verification token
+const VERIFICATION_LABEL: &str = "_neutun";
+
+lazy_static! {
+    static ref RESOLVER: TokioAsyncResolver = resolver();
+}
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("Invalid custom domain: {0}")]
+    InvalidHost(String),
+
+    #[error("{0} is on {1}, ask for a sub-domain instead")]
+    AllowedHost(String, String),
+
+    #[error("To use {host}, add a TXT record for {record} with the value {token}, and point {host} at this server with a CNAME")]
+    NotVerified {
+        host: String,
+        record: String,
+        token: String,
+    },
+
+    #[error("Failed to look up {0}: {1}")]
+    Lookup(String, ResolveError),
+}
+
+/// Lowercase a custom domain, and make sure it's a hostname that isn't on one of our allowed hosts
+pub fn parse_host(host: &str) -> Result<String, Error> {
+    parse_host_outside(host, &CONFIG.allowed_hosts)
+}
+
+fn parse_host_outside(host: &str, allowed_hosts: &[String]) -> Result<String, Error> {
+    let host = host.trim().trim_end_matches('.').to_lowercase();
+    let labels: Vec<&str> = host.split('.').collect();
+
+    let valid_label = |label: &&str| {
+        (1..=63).contains(&label.len())
+            && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+            && !label.starts_with('-')
+            && !label.ends_with('-')
+    };
+    if host.len() > 253 || labels.len() < 2 || !labels.iter().all(valid_label) {
+        return Err(Error::InvalidHost(host));
+    }
+
+    if let Some(allowed) = allowed_hosts
+        .iter()
+        .find(|allowed| host == **allowed || host.ends_with(&format!(".{}", allowed)))
+    {
+        return Err(Error::AllowedHost(host.clone(), allowed.clone()));
+    }
+
+    Ok(host)
+}
+
+/// The TXT record value that proves `owner` (see `key_store::hash_key`) controls the dns of `host`
+pub fn token(owner: &str, host: &str) -> String {
+    let digest = sha2::Sha256::digest(format!("{}:{}", owner, host).as_bytes());
+    format!("neutun-verify={}", hex::encode(&digest[..16]))
+}
+
+/// Look for the owner's token in the TXT records of the custom domain
+pub async fn verify(host: &str, owner: &str) -> Result<(), Error> {
+    verify_with(&RESOLVER, host, owner).await
+}
+
+async fn verify_with(resolver: &TokioAsyncResolver, host: &str, owner: &str) -> Result<(), Error> {
+    let record = format!("{}.{}", VERIFICATION_LABEL, host);
+    let token = token(owner, host);
+
+    // a trailing dot keeps the resolver from trying its search domains
+    let found = match resolver.txt_lookup(format!("{}.", record)).await {
+        Ok(lookup) => lookup.iter().any(|txt| {
+            // long values are split into several strings
+            let value: String = txt.txt_data().iter().map(|s| String::from_utf8_lossy(s)).collect();
+            value.trim() == token
+        }),
+        Err(error) if matches!(error.kind(), ResolveErrorKind::NoRecordsFound { .. }) => false,
+        Err(error) => return Err(Error::Lookup(record, error)),
+    };
+
+    if !found {
+        return Err(Error::NotVerified {
+            host: host.to_string(),
+            record,
+            token,
+        });
+    }
+    Ok(())
+}
+
+fn resolver() -> TokioAsyncResolver {
+    resolver_for(CONFIG.dns_server)
+}
+
+/// A resolver asking the dns server, or the system's if there isn't one
+fn resolver_for(dns_server: Option<SocketAddr>) -> TokioAsyncResolver {
+    // nothing is cached, so a client can retry as soon as it added its record
+    let mut options = ResolverOpts::default();
+    options.cache_size = 0;
+
+    match dns_server {
+        Some(server) => {
+            let name_servers = NameServerConfigGroup::from_ips_clear(&[server.ip()], server.port(), true);
+            TokioAsyncResolver::tokio(ResolverConfig::from_parts(None, vec![], name_servers), options)
+        }
+        None => match trust_dns_resolver::system_conf::read_system_conf() {
+            Ok((config, _)) => TokioAsyncResolver::tokio(config, options),
+            Err(error) => {
+                tracing::warn!(%error, "failed to read the system dns config, using google's");
+                TokioAsyncResolver::tokio(ResolverConfig::google(), options)
+            }
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::UdpSocket;
+    use trust_dns_resolver::proto::op::{Message, MessageType, ResponseCode};
+    use trust_dns_resolver::proto::rr::rdata::TXT;
+    use trust_dns_resolver::proto::rr::{RData, Record};
+
+    fn allowed() -> Vec<String> {
+        vec!["example.com".to_string()]
+    }
+
+    #[test]
+    fn parses_hosts() {
+        let parse = |host: &str| parse_host_outside(host, &allowed());
+        assert_eq!(parse(" Staging.OurCompany.com. ").unwrap(), "staging.ourcompany.com");
+        assert_eq!(parse("a-b.co").unwrap(), "a-b.co");
+        // only whole labels of an allowed host count
+        assert_eq!(parse("notexample.com").unwrap(), "notexample.com");
+
+        for host in ["localhost", "", "a..com", "-a.com", "a-.com", "a_b.com", "a.com/path", "a b.com"] {
+            assert!(matches!(parse(host), Err(Error::InvalidHost(_))), "{:?}", host);
+        }
+        assert!(matches!(parse(&format!("{}.com", "a".repeat(64))), Err(Error::InvalidHost(_))));
+        assert!(matches!(parse(&format!("{}com", "a.".repeat(127))), Err(Error::InvalidHost(_))));
+    }
+
+    #[test]
+    fn refuses_our_own_hosts() {
+        for host in ["example.com", "foo.example.com", "a.b.EXAMPLE.com"] {
+            match parse_host_outside(host, &allowed()) {
+                Err(Error::AllowedHost(_, allowed)) => assert_eq!(allowed, "example.com"),
+                other => panic!("{}: {:?}", host, other),
+            }
+        }
+    }
+
+    #[test]
+    fn tokens_depend_on_the_owner_and_host() {
+        let token = token("owner", "a.com");
+        assert_eq!(token, super::token("owner", "a.com"));
+        assert_ne!(token, super::token("other", "a.com"));
+        assert_ne!(token, super::token("owner", "b.com"));
+
+        let hex = token.strip_prefix("neutun-verify=").unwrap();
+        assert_eq!(hex.len(), 32);
+        assert!(hex.chars().all(|c| c.is_ascii_hexdigit()));
+    }
+
+    /// A dns server answering TXT queries for one name, every other name doesn't exist
+    async fn dns_stand_in(name: &'static str, strings: Vec<String>) -> SocketAddr {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0; 512];
+            while let Ok((n, from)) = socket.recv_from(&mut buf).await {
+                let query = Message::from_vec(&buf[..n]).unwrap();
+                let mut response = Message::new();
+                response
+                    .set_id(query.id())
+                    .set_message_type(MessageType::Response)
+                    .set_recursion_available(true)
+                    .add_queries(query.queries().to_vec());
+                let question = &query.queries()[0];
+                if question.name().to_ascii() == name {
+                    let txt = RData::TXT(TXT::new(strings.clone()));
+                    response.add_answer(Record::from_rdata(question.name().clone(), 60, txt));
+                } else {
+                    response.set_response_code(ResponseCode::NXDomain);
+                }
+                let _ = socket.send_to(&response.to_vec().unwrap(), from).await;
+            }
+        });
+        addr
+    }
+
+    #[tokio::test]
+    async fn verifies_the_txt_record() {
+        // long values come split into several strings
+        let token = token("owner", "shop.example.org");
+        let (head, tail) = token.split_at(20);
+        let server = dns_stand_in("_neutun.shop.example.org.", vec![head.into(), tail.into()]).await;
+        let resolver = resolver_for(Some(server));
+
+        verify_with(&resolver, "shop.example.org", "owner").await.unwrap();
+
+        match verify_with(&resolver, "shop.example.org", "other").await {
+            Err(Error::NotVerified { record, token, .. }) => {
+                assert_eq!(record, "_neutun.shop.example.org");
+                assert_eq!(token, super::token("other", "shop.example.org"));
+            }
+            other => panic!("{:?}", other),
+        }
+        assert!(matches!(
+            verify_with(&resolver, "other.example.org", "owner").await,
+            Err(Error::NotVerified { .. })
+        ));
+    }
+}
@@ -0,0 +1,233 @@
+use super::*;
+use base64::{engine::general_purpose, Engine as _};
+use hyper::header::{HeaderMap, AUTHORIZATION};
+use hyper::Method;
+use ipnet::IpNet;
+use sha2::Digest;
+use thiserror::Error;
+
+/// A tunnel's `EdgePolicy`, parsed to check its public traffic against
+#[derive(Debug, Default)]
+pub struct Policy {
+    /// digests of the `Authorization` headers that let a request through, so we never compare secrets as-is
+    credentials: Vec<Vec<u8>>,
+    basic_auth: bool,
+    bearer_tokens: bool,
+    allow_ips: Vec<IpNet>,
+    allow_methods: Vec<Method>,
+}
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("Invalid IP address or range: {0}")]
+    InvalidIpRange(String),
+
+    #[error("Invalid HTTP method: {0}")]
+    InvalidMethod(String),
+
+    #[error("Basic auth credentials must look like user:password")]
+    InvalidBasicAuth,
+
+    #[error("Basic auth, bearer tokens and allowed methods only apply to HTTP tunnels")]
+    HttpOnly,
+}
+
+/// Why a request was turned away
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Denied {
+    /// the remote ip isn't allowed
+    Forbidden,
+    /// no valid credentials
+    Unauthorized,
+    MethodNotAllowed,
+}
+
+impl Policy {
+    pub fn new(policy: &EdgePolicy, kind: TunnelKind) -> Result<Self, Error> {
+        if kind != TunnelKind::Http && policy.is_http_only() {
+            return Err(Error::HttpOnly);
+        }
+
+        let mut credentials = vec![];
+        for pair in &policy.basic_auth {
+            if !pair.contains(':') {
+                return Err(Error::InvalidBasicAuth);
+            }
+            credentials.push(digest("basic", &general_purpose::STANDARD.encode(pair)));
+        }
+        for token in &policy.bearer_tokens {
+            credentials.push(digest("bearer", token));
+        }
+
+        let allow_ips = policy
+            .allow_ips
+            .iter()
+            .map(|range| ip_filter::parse_range(range).map_err(|_| Error::InvalidIpRange(range.clone())))
+            .collect::<Result<_, _>>()?;
+
+        let allow_methods = policy
+            .allow_methods
+            .iter()
+            .map(|method| {
+                Method::from_bytes(method.to_uppercase().as_bytes())
+                    .map_err(|_| Error::InvalidMethod(method.clone()))
+            })
+            .collect::<Result<_, _>>()?;
+
+        Ok(Policy {
+            credentials,
+            basic_auth: !policy.basic_auth.is_empty(),
+            bearer_tokens: !policy.bearer_tokens.is_empty(),
+            allow_ips,
+            allow_methods,
+        })
+    }
+
+    /// May a connection from this ip reach the tunnel
+    pub fn allows_ip(&self, ip: IpAddr) -> bool {
+        let ip = ip.to_canonical();
+        self.allow_ips.is_empty() || self.allow_ips.iter().any(|range| range.contains(&ip))
+    }
+
+    /// Check a request from `ip` against every rule
+    pub fn check_request(&self, ip: IpAddr, method: &Method, headers: &HeaderMap) -> Result<(), Denied> {
+        if !self.allows_ip(ip) {
+            return Err(Denied::Forbidden);
+        }
+
+        if !self.credentials.is_empty() {
+            let presented = headers
+                .get(AUTHORIZATION)
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| value.trim().split_once(' '))
+                .map(|(scheme, credentials)| digest(&scheme.to_lowercase(), credentials.trim()));
+
+            if !presented.is_some_and(|presented| self.credentials.contains(&presented)) {
+                return Err(Denied::Unauthorized);
+            }
+        }
+
+        if !self.allow_methods.is_empty() && !self.allow_methods.contains(method) {
+            return Err(Denied::MethodNotAllowed);
+        }
+
+        Ok(())
+    }
+
+    /// Take the credentials we checked off the request, they're for us and not the local service
+    pub fn strip_credentials(&self, headers: &mut HeaderMap) {
+        if !self.credentials.is_empty() {
+            headers.remove(AUTHORIZATION);
+        }
+    }
+
+    /// The `WWW-Authenticate` challenges for an unauthorized request
+    pub fn challenges(&self) -> Vec<&'static str> {
+        let mut challenges = vec![];
+        if self.basic_auth {
+            challenges.push("Basic realm=\"neutun\", charset=\"UTF-8\"");
+        }
+        if self.bearer_tokens {
+            challenges.push("Bearer realm=\"neutun\"");
+        }
+        challenges
+    }
+
+    /// The `Allow` header for a request with a method that isn't
+    pub fn allowed_methods(&self) -> String {
+        self.allow_methods
+            .iter()
+            .map(Method::as_str)
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+}
+
+fn digest(scheme: &str, credentials: &str) -> Vec<u8> {
+    sha2::Sha256::digest(format!("{} {}", scheme, credentials).as_bytes()).to_vec()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::header::HeaderValue;
+
+    fn policy(edge: EdgePolicy) -> Policy {
+        Policy::new(&edge, TunnelKind::Http).unwrap()
+    }
+
+    fn authorized(value: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(AUTHORIZATION, HeaderValue::from_str(value).unwrap());
+        headers
+    }
+
+    fn ip(ip: &str) -> IpAddr {
+        ip.parse().unwrap()
+    }
+
+    #[test]
+    fn checks_credentials() {
+        let policy = policy(EdgePolicy {
+            basic_auth: vec!["user:pass".into()],
+            bearer_tokens: vec!["token".into()],
+            ..Default::default()
+        });
+        let check = |headers: &HeaderMap| policy.check_request(ip("1.2.3.4"), &Method::GET, headers);
+
+        // `user:pass`
+        assert_eq!(check(&authorized("Basic dXNlcjpwYXNz")), Ok(()));
+        assert_eq!(check(&authorized("basic  dXNlcjpwYXNz ")), Ok(()));
+        assert_eq!(check(&authorized("Bearer token")), Ok(()));
+
+        assert_eq!(check(&HeaderMap::new()), Err(Denied::Unauthorized));
+        assert_eq!(check(&authorized("Bearer dXNlcjpwYXNz")), Err(Denied::Unauthorized));
+        assert_eq!(check(&authorized("Basic token")), Err(Denied::Unauthorized));
+        assert_eq!(check(&authorized("Bearer tokens")), Err(Denied::Unauthorized));
+        assert_eq!(check(&authorized("token")), Err(Denied::Unauthorized));
+        assert_eq!(policy.challenges().len(), 2);
+    }
+
+    #[test]
+    fn strips_checked_credentials_only() {
+        let mut headers = authorized("Bearer token");
+        policy(EdgePolicy::default()).strip_credentials(&mut headers);
+        assert!(headers.contains_key(AUTHORIZATION));
+
+        let protected = policy(EdgePolicy {
+            bearer_tokens: vec!["token".into()],
+            ..Default::default()
+        });
+        protected.strip_credentials(&mut headers);
+        assert!(!headers.contains_key(AUTHORIZATION));
+    }
+
+    #[test]
+    fn checks_ips_and_methods() {
+        let policy = policy(EdgePolicy {
+            allow_ips: vec!["10.0.0.0/8".into(), "2001:db8::1".into()],
+            allow_methods: vec!["get".into(), "HEAD".into()],
+            ..Default::default()
+        });
+        let headers = HeaderMap::new();
+
+        assert_eq!(policy.check_request(ip("10.1.2.3"), &Method::GET, &headers), Ok(()));
+        assert_eq!(policy.check_request(ip("::ffff:10.1.2.3"), &Method::HEAD, &headers), Ok(()));
+        assert_eq!(policy.check_request(ip("2001:db8::1"), &Method::GET, &headers), Ok(()));
+        assert_eq!(policy.check_request(ip("11.0.0.1"), &Method::GET, &headers), Err(Denied::Forbidden));
+        assert_eq!(policy.check_request(ip("10.1.2.3"), &Method::POST, &headers), Err(Denied::MethodNotAllowed));
+        assert_eq!(policy.allowed_methods(), "GET, HEAD");
+    }
+
+    #[test]
+    fn rejects_bad_rules() {
+        let http = |edge: EdgePolicy| Policy::new(&edge, TunnelKind::Http).is_ok();
+        assert!(!http(EdgePolicy { basic_auth: vec!["nopassword".into()], ..Default::default() }));
+        assert!(!http(EdgePolicy { allow_ips: vec!["10.0.0.0/33".into()], ..Default::default() }));
+        assert!(!http(EdgePolicy { allow_methods: vec!["G E T".into()], ..Default::default() }));
+
+        let tcp = |edge: EdgePolicy| Policy::new(&edge, TunnelKind::Tcp).is_ok();
+        assert!(!tcp(EdgePolicy { bearer_tokens: vec!["token".into()], ..Default::default() }));
+        assert!(tcp(EdgePolicy { allow_ips: vec!["10.0.0.0/8".into()], ..Default::default() }));
+    }
+}
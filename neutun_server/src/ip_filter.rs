@@ -0,0 +1,280 @@
+use super::*;
+use ipnet::IpNet;
+use serde::Deserialize;
+use std::path::{Path, PathBuf};
+use std::sync::RwLock;
+use std::time::{Duration, SystemTime};
+use thiserror::Error;
+
+/// How often we look for changes to the ip lists file
+const RELOAD_INTERVAL: Duration = Duration::from_secs(10);
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("failed to read {0:?}: {1}")]
+    Io(PathBuf, std::io::Error),
+
+    #[error("invalid json in {0:?}: {1}")]
+    Json(PathBuf, serde_json::Error),
+
+    #[error("invalid ip address or range: {0}")]
+    InvalidRange(String),
+}
+
+/// The contents of `IP_LISTS_FILE`
+#[derive(Deserialize, Debug, Default)]
+struct IpListsFile {
+    #[serde(default)]
+    blocked: Vec<String>,
+    #[serde(default)]
+    allowed: Vec<String>,
+}
+
+#[derive(Debug, Default)]
+struct Ranges {
+    blocked: Vec<IpNet>,
+    /// when not empty, nobody else may connect
+    allowed: Vec<IpNet>,
+}
+
+/// Which remote ips may connect to our control and public listeners.
+/// Blocked ranges always win over allowed ones.
+pub struct IpFilter {
+    /// from the environment and the ip lists file, replaced whenever the file changes
+    ranges: RwLock<Ranges>,
+    /// blocked through the admin api, lost on restart
+    runtime_blocked: DashSet<IpNet>,
+}
+
+/// Parse an ip address, i.e. `203.0.113.7`, or a cidr range, i.e. `203.0.113.0/24`
+pub fn parse_range(range: &str) -> Result<IpNet, Error> {
+    let range = range.trim();
+    range
+        .parse::<IpNet>()
+        .map(|net| net.trunc())
+        .or_else(|_| range.parse::<IpAddr>().map(IpNet::from))
+        .map_err(|_| Error::InvalidRange(range.to_string()))
+}
+
+/// Whether `ip` is in one of the ranges, i.e. `CONFIG.trusted_proxies`
+pub fn contains(ranges: &[IpNet], ip: IpAddr) -> bool {
+    let ip = ip.to_canonical();
+    ranges.iter().any(|range| range.contains(&ip))
+}
+
+/// Show single addresses without their prefix
+pub fn display_range(range: &IpNet) -> String {
+    if range.prefix_len() == range.max_prefix_len() {
+        range.addr().to_string()
+    } else {
+        range.to_string()
+    }
+}
+
+impl IpFilter {
+    /// Load our ranges from the environment and the ip lists file
+    pub fn load() -> Self {
+        let filter = IpFilter {
+            ranges: RwLock::new(Ranges::default()),
+            runtime_blocked: DashSet::new(),
+        };
+
+        if let Err(error) = filter.reload() {
+            panic!("failed to load ip lists: {}", error);
+        }
+        filter
+    }
+
+    #[cfg(test)]
+    pub fn with_ranges(blocked: Vec<IpNet>, allowed: Vec<IpNet>) -> Self {
+        IpFilter {
+            ranges: RwLock::new(Ranges { blocked, allowed }),
+            runtime_blocked: DashSet::new(),
+        }
+    }
+
+    pub fn allows(&self, ip: IpAddr) -> bool {
+        let ip = ip.to_canonical();
+        if self.runtime_blocked.iter().any(|range| range.contains(&ip)) {
+            return false;
+        }
+
+        let ranges = self.ranges.read().unwrap();
+        !ranges.blocked.iter().any(|range| range.contains(&ip))
+            && (ranges.allowed.is_empty() || ranges.allowed.iter().any(|range| range.contains(&ip)))
+    }
+
+    /// Every blocked range, sorted
+    pub fn blocked(&self) -> Vec<IpNet> {
+        let mut blocked = self.ranges.read().unwrap().blocked.clone();
+        blocked.extend(self.runtime_blocked.iter().map(|range| *range));
+        blocked.sort();
+        blocked.dedup();
+        blocked
+    }
+
+    /// Every allowed range, sorted
+    pub fn allowed(&self) -> Vec<IpNet> {
+        let mut allowed = self.ranges.read().unwrap().allowed.clone();
+        allowed.sort();
+        allowed
+    }
+
+    pub fn block(&self, range: IpNet) {
+        self.runtime_blocked.insert(range);
+    }
+
+    /// Lift a block from the admin api. `None` if the range is blocked by the environment or the ip lists file.
+    pub fn unblock(&self, range: &IpNet) -> Option<bool> {
+        if self.ranges.read().unwrap().blocked.contains(range) {
+            return None;
+        }
+        Some(self.runtime_blocked.remove(range).is_some())
+    }
+
+    /// Read the ip lists file again, keeping the old ranges if it's invalid
+    fn reload(&self) -> Result<(), Error> {
+        let file = match &CONFIG.ip_lists_file {
+            Some(path) => read_file(path)?,
+            None => IpListsFile::default(),
+        };
+
+        let parse = |ranges: &[String]| -> Result<Vec<IpNet>, Error> {
+            ranges.iter().map(|range| parse_range(range)).collect()
+        };
+        let mut blocked = CONFIG.blocked_ips.clone();
+        blocked.extend(parse(&file.blocked)?);
+        let mut allowed = CONFIG.allowed_ips.clone();
+        allowed.extend(parse(&file.allowed)?);
+
+        *self.ranges.write().unwrap() = Ranges { blocked, allowed };
+        Ok(())
+    }
+}
+
+fn read_file(path: &Path) -> Result<IpListsFile, Error> {
+    match std::fs::read(path) {
+        Ok(data) => serde_json::from_slice(&data).map_err(|e| Error::Json(path.into(), e)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(IpListsFile::default()),
+        Err(e) => Err(Error::Io(path.into(), e)),
+    }
+}
+
+/// Keep reloading the ip lists file whenever it changes, and drop the clients it no longer allows
+pub fn spawn_reload(path: &'static Path) {
+    let modified_at = move || std::fs::metadata(path).and_then(|m| m.modified()).ok();
+    let mut modified: Option<SystemTime> = modified_at();
+
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(RELOAD_INTERVAL).await;
+
+            let now_modified = modified_at();
+            if now_modified == modified {
+                continue;
+            }
+            modified = now_modified;
+
+            match IP_FILTER.reload() {
+                Ok(_) => tracing::info!(?path, "reloaded ip lists"),
+                Err(error) => {
+                    tracing::error!(%error, "failed to reload ip lists");
+                    continue;
+                }
+            }
+
+            for client in CONNECTIONS.get_all_clients() {
+                if !IP_FILTER.allows(client.ip) {
+                    tracing::info!(client_id = %client.id, ip = %client.ip, "disconnecting client no longer allowed");
+                    Connections::remove(&client);
+                }
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn range(range: &str) -> IpNet {
+        parse_range(range).unwrap()
+    }
+
+    fn ip(ip: &str) -> IpAddr {
+        ip.parse().unwrap()
+    }
+
+    fn filter(blocked: &[&str], allowed: &[&str]) -> IpFilter {
+        IpFilter::with_ranges(
+            blocked.iter().map(|r| range(r)).collect(),
+            allowed.iter().map(|r| range(r)).collect(),
+        )
+    }
+
+    #[test]
+    fn parses_addresses_and_ranges() {
+        assert_eq!(range(" 203.0.113.7 "), "203.0.113.7/32".parse().unwrap());
+        assert_eq!(range("2001:db8::1"), "2001:db8::1/128".parse().unwrap());
+        assert_eq!(range("203.0.113.0/24"), "203.0.113.0/24".parse().unwrap());
+        // host bits are dropped
+        assert_eq!(range("203.0.113.77/24"), "203.0.113.0/24".parse().unwrap());
+        assert_eq!(range("2001:db8::1/32"), "2001:db8::/32".parse().unwrap());
+
+        for invalid in ["", "300.1.1.1", "10.0.0.0/33", "2001:db8::/129", "10.0.0.0/", "example.com", "10.0.0.0/8/8"] {
+            assert!(matches!(parse_range(invalid), Err(Error::InvalidRange(_))), "{:?}", invalid);
+        }
+    }
+
+    #[test]
+    fn displays_single_addresses_without_a_prefix() {
+        assert_eq!(display_range(&range("203.0.113.7")), "203.0.113.7");
+        assert_eq!(display_range(&range("203.0.113.0/24")), "203.0.113.0/24");
+        assert_eq!(display_range(&range("2001:db8::1")), "2001:db8::1");
+        assert_eq!(display_range(&range("0.0.0.0/0")), "0.0.0.0/0");
+    }
+
+    #[test]
+    fn blocked_ranges_win() {
+        let blocking = filter(&["10.1.0.0/16", "2001:db8::/32"], &[]);
+        assert!(blocking.allows(ip("10.2.0.1")));
+        assert!(!blocking.allows(ip("10.1.255.255")));
+        assert!(!blocking.allows(ip("2001:db8:1::1")));
+        // ipv4 clients on our dual stack listeners show up mapped
+        assert!(!blocking.allows(ip("::ffff:10.1.0.1")));
+
+        let allowing = filter(&["10.1.0.0/16"], &["10.0.0.0/8"]);
+        assert!(allowing.allows(ip("10.2.0.1")));
+        assert!(!allowing.allows(ip("10.1.0.1")));
+        assert!(!allowing.allows(ip("192.0.2.1")));
+    }
+
+    #[test]
+    fn blocks_at_runtime() {
+        let blocking = filter(&["10.1.0.0/16"], &[]);
+        blocking.block(range("192.0.2.0/24"));
+        assert!(!blocking.allows(ip("192.0.2.9")));
+        assert_eq!(blocking.blocked(), vec![range("10.1.0.0/16"), range("192.0.2.0/24")]);
+
+        // only runtime blocks can be lifted
+        assert_eq!(blocking.unblock(&range("10.1.0.0/16")), None);
+        assert_eq!(blocking.unblock(&range("192.0.2.0/24")), Some(true));
+        assert_eq!(blocking.unblock(&range("192.0.2.0/24")), Some(false));
+        assert!(blocking.allows(ip("192.0.2.9")));
+    }
+
+    #[test]
+    fn reads_the_ip_lists_file() {
+        let path = std::env::temp_dir().join(format!("neutun-ip-lists-{}.json", std::process::id()));
+        std::fs::write(&path, r#"{"blocked": ["10.0.0.0/8"]}"#).unwrap();
+        let file = read_file(&path).unwrap();
+        assert_eq!(file.blocked, vec!["10.0.0.0/8"]);
+        assert!(file.allowed.is_empty());
+
+        std::fs::write(&path, "not json").unwrap();
+        assert!(matches!(read_file(&path), Err(Error::Json(..))));
+
+        std::fs::remove_file(&path).unwrap();
+        assert!(read_file(&path).unwrap().blocked.is_empty());
+    }
+}
@@ -2,13 +2,14 @@ use futures::{SinkExt, StreamExt};
 use warp::ws::{Message, WebSocket, Ws};
 use warp::Filter;
 
-use dashmap::DashMap;
+use dashmap::{DashMap, DashSet};
+use std::net::IpAddr;
 use std::sync::Arc;
 pub use neutun_lib::*;
 
 use tokio::net::TcpListener;
 
-use futures::channel::mpsc::{unbounded, UnboundedReceiver, UnboundedSender};
+use futures::channel::mpsc::{channel, Receiver, Sender};
 use futures::stream::{SplitSink, SplitStream};
 use lazy_static::lazy_static;
 
@@ -22,14 +23,34 @@ pub use self::auth::simple_auth;
 pub use self::auth::client_auth;
 
 pub use self::auth::simple_auth::SimpleAuthService;
+use self::auth::key_store::KeyStore;
+use self::auth::reservations::Reservations;
 
+mod acme;
+mod admin;
 mod control_server;
+mod custom_domain;
+mod edge_policy;
+use self::edge_policy::Policy;
+mod ip_filter;
+use self::ip_filter::IpFilter;
+mod quota;
+use self::quota::{Quota, Usage};
+mod rate_limit;
+use self::rate_limit::RateLimiter;
 mod remote;
+mod remote_http;
+mod remote_stream;
+use self::remote_stream::RemoteStream;
+mod sni;
+mod tcp_tunnel;
+mod tls;
 
 mod config;
 pub use self::config::Config;
 mod network;
 
+mod metrics;
 mod observability;
 
 use tracing::level_filters::LevelFilter;
@@ -43,6 +64,11 @@ lazy_static! {
     pub static ref ACTIVE_STREAMS: ActiveStreams = Arc::new(DashMap::new());
     pub static ref AUTH_DB_SERVICE: SimpleAuthService = SimpleAuthService;
     pub static ref CONFIG: Config = Config::from_env();
+    pub static ref KEYS: KeyStore = KeyStore::load(CONFIG.keys_file.as_ref());
+    /// which ips may connect, from `BLOCKED_IPS`, `ALLOWED_IPS`, `IP_LISTS_FILE` and the admin api
+    pub static ref IP_FILTER: IpFilter = IpFilter::load();
+    pub static ref RESERVATIONS: Reservations = Reservations::load(CONFIG.reservations_file.clone());
+    pub static ref USAGE: Usage = Usage::load(CONFIG.usage_file.clone());
 }
 
 #[tokio::main]
@@ -55,6 +81,32 @@ async fn main() {
 
     tracing::info!("starting server!");
 
+    // fail fast on broken keys, reservations or usage files, rather than on the first client
+    lazy_static::initialize(&KEYS);
+    lazy_static::initialize(&RESERVATIONS);
+    lazy_static::initialize(&USAGE);
+    lazy_static::initialize(&IP_FILTER);
+
+    if let Some(path) = &CONFIG.ip_lists_file {
+        ip_filter::spawn_reload(path);
+    }
+
+    rate_limit::spawn_sweep();
+    quota::spawn_save();
+
+    if let Some(files) = &CONFIG.tls {
+        tls::spawn_reload(files);
+    }
+
+    if let Some(acme) = &CONFIG.acme {
+        acme::spawn(acme);
+    }
+
+    if CONFIG.cluster.is_some() {
+        network::spawn(([0, 0, 0, 0], CONFIG.internal_network_port));
+        info!("started network service on 0.0.0.0:{}", CONFIG.internal_network_port);
+    }
+
     control_server::spawn(([0, 0, 0, 0], CONFIG.control_port));
     info!("started neutun server on 0.0.0.0:{}", CONFIG.control_port);
 
@@ -67,17 +119,44 @@ async fn main() {
         .expect("failed to bind");
 
     loop {
-        let socket = match listener.accept().await {
-            Ok((socket, _)) => socket,
-            _ => {
-                error!("failed to accept socket");
+        let (socket, peer_addr) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(error) => {
+                error!(?error, "failed to accept socket");
+                tokio::time::sleep(tcp_tunnel::ACCEPT_ERROR_BACKOFF).await;
                 continue;
             }
         };
 
         tokio::spawn(
             async move {
-                remote::accept_connection(socket).await;
+                let public_port = socket.local_addr().map_or(CONFIG.remote_port, |addr| addr.port());
+                let mut socket = RemoteStream::new(socket);
+                // other instances of the cluster always say who they forward a connection for
+                let proxied = CONFIG.proxy_protocol.public || network::is_instance_link(&mut socket, peer_addr.ip()).await;
+                let socket_peer = peer_addr;
+                let (peer_addr, https) = if proxied {
+                    match socket.proxied_peer(peer_addr).await {
+                        Some(proxied) => proxied,
+                        None => return,
+                    }
+                } else {
+                    (peer_addr, false)
+                };
+
+                if !IP_FILTER.allows(peer_addr.ip()) {
+                    tracing::debug!(%peer_addr, "remote ip is not allowed, dropping connection");
+                    return;
+                }
+
+                let info = remote_http::ConnectionInfo::new(
+                    socket_peer,
+                    peer_addr,
+                    public_port,
+                    https,
+                    &CONFIG.trusted_proxies,
+                );
+                remote::accept_connection(socket, info).await;
             }
             .instrument(observability::remote_trace("remote_connect")),
         );
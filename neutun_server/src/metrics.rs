@@ -0,0 +1,148 @@
+use super::*;
+use prometheus::{
+    register_histogram_vec, register_int_counter_vec, register_int_gauge, Encoder, HistogramVec,
+    IntCounter, IntCounterVec, IntGauge, TextEncoder,
+};
+use crate::rate_limit::Limited;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Instant;
+
+lazy_static! {
+    static ref CONNECTED_CLIENTS: IntGauge =
+        register_int_gauge!("neutun_connected_clients", "Clients with an open tunnel")
+            .expect("metric can be registered");
+    static ref ACTIVE_STREAMS_OPEN: IntGauge =
+        register_int_gauge!("neutun_active_streams", "Streams open through tunnels")
+            .expect("metric can be registered");
+    static ref BYTES: IntCounterVec = register_int_counter_vec!(
+        "neutun_bytes_total",
+        "Bytes tunneled per tunnel host, `in` from the public to clients and `out` back",
+        &["host", "direction"]
+    )
+    .expect("metric can be registered");
+    static ref HANDSHAKE_FAILURES: IntCounterVec = register_int_counter_vec!(
+        "neutun_handshake_failures_total",
+        "Client handshakes refused, by the server hello sent back",
+        &["reason"]
+    )
+    .expect("metric can be registered");
+    static ref REFUSED_STREAMS: IntCounterVec = register_int_counter_vec!(
+        "neutun_refused_streams_total",
+        "Streams that couldn't be tunneled, `refused` by the client or with `no_client` left",
+        &["reason"]
+    )
+    .expect("metric can be registered");
+    static ref RATE_LIMITED: IntCounterVec = register_int_counter_vec!(
+        "neutun_rate_limited_total",
+        "Requests and connections turned away by a tunnel's rate limits, by the `tunnel` or `ip` limit hit",
+        &["event", "limit"]
+    )
+    .expect("metric can be registered");
+    static ref FIRST_BYTE_SECONDS: HistogramVec = register_histogram_vec!(
+        "neutun_request_first_byte_seconds",
+        "Time from a stream opening to the first response byte from the client",
+        &["kind"]
+    )
+    .expect("metric can be registered");
+    /// how many `HostBytes` count under each host
+    static ref BYTES_HOSTS: Mutex<HashMap<String, usize>> = Mutex::new(HashMap::new());
+}
+
+/// The byte counters of a tunnel host, shared by every client on it.
+/// The host's series are dropped with the last of them, so old tunnels don't pile up,
+/// but never while a client that is still connecting holds them.
+#[derive(Debug)]
+pub struct HostBytes {
+    host: String,
+    pub bytes_in: IntCounter,
+    pub bytes_out: IntCounter,
+}
+
+impl HostBytes {
+    pub fn new(host: String) -> Self {
+        let mut hosts = BYTES_HOSTS.lock().unwrap();
+        *hosts.entry(host.clone()).or_default() += 1;
+        HostBytes {
+            bytes_in: BYTES.with_label_values(&[&host, "in"]),
+            bytes_out: BYTES.with_label_values(&[&host, "out"]),
+            host,
+        }
+    }
+
+    pub fn host(&self) -> &str {
+        &self.host
+    }
+}
+
+impl Drop for HostBytes {
+    fn drop(&mut self) {
+        let mut hosts = BYTES_HOSTS.lock().unwrap();
+        let Some(count) = hosts.get_mut(&self.host) else {
+            return;
+        };
+        *count -= 1;
+        if *count == 0 {
+            hosts.remove(&self.host);
+            for direction in ["in", "out"] {
+                let _ = BYTES.remove_label_values(&[&self.host, direction]);
+            }
+        }
+    }
+}
+
+pub fn handshake_failed(hello: &ServerHello) {
+    let reason = match hello {
+        ServerHello::Success { .. } => return,
+        ServerHello::IncompatibleVersion { .. } => "incompatible_version",
+        ServerHello::SubDomainInUse => "sub_domain_in_use",
+        ServerHello::InvalidSubDomain => "invalid_sub_domain",
+        ServerHello::AuthFailed => "auth_failed",
+        ServerHello::Error(_) => "error",
+    };
+    HANDSHAKE_FAILURES.with_label_values(&[reason]).inc();
+}
+
+pub fn stream_refused(message: &StreamMessage) {
+    let reason = match message {
+        StreamMessage::Data(_) | StreamMessage::End => return,
+        StreamMessage::TunnelRefused => "refused",
+        StreamMessage::NoClientTunnel => "no_client",
+    };
+    REFUSED_STREAMS.with_label_values(&[reason]).inc();
+}
+
+/// A `request` or `connection` ran into a rate limit
+pub fn rate_limited(event: &'static str, limited: Limited) {
+    let limit = match limited {
+        Limited::Tunnel => "tunnel",
+        Limited::Ip => "ip",
+    };
+    RATE_LIMITED.with_label_values(&[event, limit]).inc();
+}
+
+pub fn first_byte(kind: TunnelKind, started: Instant) {
+    FIRST_BYTE_SECONDS
+        .with_label_values(&[kind_label(kind)])
+        .observe(started.elapsed().as_secs_f64());
+}
+
+fn kind_label(kind: TunnelKind) -> &'static str {
+    match kind {
+        TunnelKind::Http => "http",
+        TunnelKind::Tcp => "tcp",
+        TunnelKind::Tls => "tls",
+    }
+}
+
+/// Render every metric in the prometheus text format
+pub fn render() -> String {
+    CONNECTED_CLIENTS.set(CONNECTIONS.get_all_clients().len() as i64);
+    ACTIVE_STREAMS_OPEN.set(ACTIVE_STREAMS.len() as i64);
+
+    let mut buffer = vec![];
+    if let Err(error) = TextEncoder::new().encode(&prometheus::gather(), &mut buffer) {
+        tracing::error!(?error, "failed to encode metrics");
+    }
+    String::from_utf8(buffer).unwrap_or_default()
+}
@@ -1,14 +1,34 @@
-use std::net::{IpAddr, SocketAddr};
+use futures::future::select_ok;
+use lazy_static::lazy_static;
+use std::net::{IpAddr, SocketAddr, UdpSocket};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
 use thiserror::Error;
+use trust_dns_resolver::config::{ResolverConfig, ResolverOpts};
+use trust_dns_resolver::TokioAsyncResolver;
 mod server;
-#[allow(unused_imports)]
 pub use self::server::spawn;
 mod proxy;
-pub use self::proxy::proxy_stream;
+pub use self::proxy::{connect, proxy_stream};
 use crate::network::server::{HostQuery, HostQueryResponse};
-use crate::ClientId;
+use crate::{ClientId, RemoteStream, TunnelKind, CONFIG};
 use reqwest::StatusCode;
 
+lazy_static! {
+    /// resolves `CLUSTER_DNS`, caching the instances for as long as the record says
+    static ref RESOLVER: TokioAsyncResolver = TokioAsyncResolver::tokio_from_system_conf().unwrap_or_else(|error| {
+        tracing::warn!(%error, "failed to read the system dns config, using google's");
+        TokioAsyncResolver::tokio(ResolverConfig::google(), ResolverOpts::default())
+    });
+    /// sends host queries, keeping connections to the other instances open
+    static ref HTTP_CLIENT: reqwest::Client = reqwest::Client::new();
+    /// the addresses of the other instances, and when we resolved them, see `is_instance_link`
+    static ref INSTANCE_IPS: Mutex<Option<(Instant, Vec<IpAddr>)>> = Mutex::new(None);
+}
+
+/// How long `is_instance_link` trusts the addresses of the other instances it resolved
+const INSTANCE_IPS_TTL: Duration = Duration::from_secs(30);
+
 #[derive(Error, Debug)]
 pub enum Error {
     #[error("IOError: {0}")]
@@ -24,36 +44,81 @@ pub enum Error {
     DoesNotServeHost,
 }
 
+/// How the instances of a cluster find each other
+#[derive(Debug, Clone)]
+pub enum Discovery {
+    /// a fixed list of `host:port` addresses of their network services
+    Peers(Vec<String>),
+    /// every address a dns name resolves to, with their network service on our `NET_PORT`
+    Dns(String),
+}
+
 /// An instance of our server
 #[derive(Debug, Clone)]
 pub struct Instance {
-    pub ip: IpAddr,
+    /// where it answers host queries
+    pub addr: SocketAddr,
+    /// where it takes remote streams
+    pub remote_port: u16,
 }
 
 impl Instance {
     /// get all instances where our app runs
-    #[allow(dead_code)]
     async fn get_instances() -> Result<Vec<Instance>, Error> {
-        tracing::warn!("warning! gossip mode disabled!");
-        Ok(vec![])
+        let addrs: Vec<SocketAddr> = match &CONFIG.cluster {
+            None => return Ok(vec![]),
+            Some(Discovery::Peers(peers)) => {
+                let mut addrs = vec![];
+                for peer in peers {
+                    match tokio::net::lookup_host(peer).await {
+                        Ok(found) => addrs.extend(found),
+                        Err(error) => tracing::warn!(%peer, ?error, "failed to resolve peer"),
+                    }
+                }
+                addrs
+            }
+            Some(Discovery::Dns(name)) => {
+                RESOLVER
+                    .lookup_ip(name.as_str())
+                    .await?
+                    .iter()
+                    .map(|ip| SocketAddr::new(ip, CONFIG.internal_network_port))
+                    .collect()
+            }
+        };
+
+        let instances: Vec<Instance> = addrs
+            .into_iter()
+            .filter(|addr| !is_us(addr))
+            .map(|addr| Instance {
+                addr,
+                remote_port: CONFIG.remote_port,
+            })
+            .collect();
+        tracing::debug!("got {:?} other instances", instances);
+        Ok(instances)
     }
 
     /// query the instance and see if it runs our host
-    #[allow(dead_code)]
-    async fn serves_host(self, host: &str) -> Result<(Instance, ClientId), Error> {
-        let addr = SocketAddr::new(self.ip.clone(), crate::CONFIG.internal_network_port);
-        let url = format!("http://{}", addr.to_string());
-        let client = reqwest::Client::new();
-        let response = client
+    async fn serves_host(
+        mut self,
+        host: &str,
+        kind: Option<TunnelKind>,
+        path: Option<&str>,
+    ) -> Result<(Instance, ClientId), Error> {
+        let url = format!("http://{}", self.addr);
+        let response = HTTP_CLIENT
             .get(url)
             .timeout(std::time::Duration::from_secs(2))
             .query(&HostQuery {
                 host: host.to_string(),
+                kind,
+                path: path.map(String::from),
             })
             .send()
             .await
             .map_err(|e| {
-                tracing::error!(error=?e, "failed to send a host query");
+                tracing::error!(error=?e, instance=%self.addr, "failed to send a host query");
                 e
             })?;
         let status = response.status();
@@ -66,6 +131,10 @@ impl Instance {
             .unwrap_or_default();
         tracing::debug!(status=%status, found=%found_client, "got net svc response");
 
+        if let Some(remote_port) = result.remote_port {
+            self.remote_port = remote_port;
+        }
+
         match (status, result.client_id) {
             (StatusCode::OK, Some(client_id)) => Ok((self, client_id)),
             _ => Err(Error::DoesNotServeHost),
@@ -73,9 +142,121 @@ impl Instance {
     }
 }
 
-/// get the ip address we need to connect to that runs our host
+/// Our own network service, which a peer list or dns name may well include
+fn is_us(addr: &SocketAddr) -> bool {
+    addr.port() == CONFIG.internal_network_port && is_local(addr.ip())
+}
+
+/// Whether the address is one of this machine's, which we can only bind to if it is
+fn is_local(ip: IpAddr) -> bool {
+    ip.is_loopback() || ip.is_unspecified() || UdpSocket::bind(SocketAddr::new(ip, 0)).is_ok()
+}
+
+/// Whether a connection to our public port comes from another instance of the cluster,
+/// which sends a PROXY protocol header ahead of what it forwards (see `connect`)
+pub async fn is_instance_link(socket: &mut RemoteStream, ip: IpAddr) -> bool {
+    if CONFIG.cluster.is_none() {
+        return false;
+    }
+
+    let from_instance = instance_ips()
+        .await
+        .iter()
+        .any(|instance_ip| *instance_ip == ip.to_canonical());
+
+    // instances that don't send one yet, and other clients on their machines
+    from_instance && socket.starts_with_proxy_header().await.unwrap_or(false)
+}
+
+/// The addresses of the other instances, resolved again once they're older than `INSTANCE_IPS_TTL`,
+/// so checking every public connection doesn't cost a lookup
+async fn instance_ips() -> Vec<IpAddr> {
+    if let Some((resolved_at, ips)) = INSTANCE_IPS.lock().unwrap().as_ref() {
+        if resolved_at.elapsed() < INSTANCE_IPS_TTL {
+            return ips.clone();
+        }
+    }
+
+    match Instance::get_instances().await {
+        Ok(instances) => {
+            let ips: Vec<IpAddr> = instances
+                .iter()
+                .map(|instance| instance.addr.ip().to_canonical())
+                .collect();
+            *INSTANCE_IPS.lock().unwrap() = Some((Instant::now(), ips.clone()));
+            ips
+        }
+        Err(error) => {
+            tracing::warn!(?error, "failed to get the other instances");
+            vec![]
+        }
+    }
+}
+
+/// get the ip address we need to connect to that runs our host.
+/// with a `kind`, only a tunnel that would take a remote stream of that kind counts, wildcards included.
 #[tracing::instrument]
-pub async fn instance_for_host(host: &str) -> Result<(Instance, ClientId), Error> {
-    // Disabled gossip lookup as Fly.io logic is removed.
-    Err(Error::DoesNotServeHost)
+pub async fn instance_for_host(
+    host: &str,
+    kind: Option<TunnelKind>,
+) -> Result<(Instance, ClientId), Error> {
+    find_instance(host, kind, None).await
+}
+
+/// get the instance with the client to take an http request for the path on our host
+#[tracing::instrument]
+pub async fn instance_for_path(host: &str, path: &str) -> Result<(Instance, ClientId), Error> {
+    find_instance(host, Some(TunnelKind::Http), Some(path)).await
+}
+
+async fn find_instance(
+    host: &str,
+    kind: Option<TunnelKind>,
+    path: Option<&str>,
+) -> Result<(Instance, ClientId), Error> {
+    let queries: Vec<_> = Instance::get_instances()
+        .await?
+        .into_iter()
+        .map(|instance| Box::pin(instance.serves_host(host, kind, path)))
+        .collect();
+
+    if queries.is_empty() {
+        return Err(Error::DoesNotServeHost);
+    }
+
+    // instances we can't reach were logged already, to us they don't serve the host
+    let (found, _) = select_ok(queries)
+        .await
+        .map_err(|_| Error::DoesNotServeHost)?;
+    tracing::info!(instance=%found.0.addr, "found host at instance");
+    Ok(found)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn our_own_addresses_are_local() {
+        assert!(is_local("127.0.0.1".parse().unwrap()));
+        assert!(is_local("127.0.0.2".parse().unwrap()));
+        assert!(is_local("0.0.0.0".parse().unwrap()));
+
+        // whatever address we'd reach the outside world from is one of ours, if there is one
+        if let Some(ip) = UdpSocket::bind("0.0.0.0:0")
+            .and_then(|socket| socket.connect("192.0.2.1:9").map(|_| socket))
+            .and_then(|socket| socket.local_addr())
+            .ok()
+            .map(|addr| addr.ip())
+        {
+            assert!(is_local(ip), "{}", ip);
+        }
+    }
+
+    #[test]
+    fn other_addresses_are_not() {
+        // documentation ranges, nobody has them
+        assert!(!is_local("192.0.2.1".parse().unwrap()));
+        assert!(!is_local("2001:db8::1".parse().unwrap()));
+    }
 }
@@ -1,4 +1,6 @@
 use crate::network::Instance;
+use crate::RemoteStream;
+use neutun_lib::ProxyHeader;
 use std::net::SocketAddr;
 use tokio::io::AsyncWriteExt;
 use tokio::net::TcpStream;
@@ -6,9 +8,25 @@ use tokio::net::TcpStream;
 const HTTP_ERROR_PROXYING_TUNNEL_RESPONSE: &'static [u8] =
     b"HTTP/1.1 500\r\nContent-Length: 28\r\n\r\nError: Error proxying tunnel";
 
-pub async fn proxy_stream(instance: Instance, mut stream: TcpStream) {
-    let addr = SocketAddr::new(instance.ip, crate::CONFIG.remote_port);
-    let mut instance = match TcpStream::connect(addr).await {
+/// Connect to an instance's public port.
+/// It takes a PROXY protocol header from us first, naming `client` and whether it connected to us over tls,
+/// so the request is treated just like one the instance got itself.
+pub async fn connect(instance: &Instance, client: SocketAddr, tls: bool) -> std::io::Result<TcpStream> {
+    let addr = SocketAddr::new(instance.addr.ip(), instance.remote_port);
+    let mut stream = TcpStream::connect(addr).await?;
+
+    let header = ProxyHeader {
+        addresses: Some((client, addr)),
+        tls,
+    };
+    stream.write_all(&header.to_v2()).await?;
+
+    Ok(stream)
+}
+
+pub async fn proxy_stream(instance: Instance, mut stream: RemoteStream, client: SocketAddr) {
+    // the stream is still encrypted, the instance terminates tls itself
+    let mut instance = match connect(&instance, client, false).await {
         Ok(stream) => stream,
         Err(error) => {
             tracing::error!(?error, "Error connecting to instance");
@@ -18,7 +36,7 @@ pub async fn proxy_stream(instance: Instance, mut stream: TcpStream) {
     };
 
     let (mut i_read, mut i_write) = instance.split();
-    let (mut r_read, mut r_write) = stream.split();
+    let (mut r_read, mut r_write) = tokio::io::split(stream);
 
     let _ = futures::future::join(
         tokio::io::copy(&mut r_read, &mut i_write),
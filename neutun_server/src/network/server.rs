@@ -1,10 +1,9 @@
 use super::*;
 use crate::connected_clients::Connections;
-use crate::ClientId;
+use crate::{ClientId, TunnelKind, CONFIG};
 use serde::{Deserialize, Serialize};
 use warp::Filter;
 
-#[allow(dead_code)]
 pub fn spawn<A: Into<SocketAddr>>(addr: A) {
     let health_check = warp::get().and(warp::path("health_check")).map(|| {
         tracing::debug!("Net svc health check triggered");
@@ -18,23 +17,50 @@ pub fn spawn<A: Into<SocketAddr>>(addr: A) {
 
     let routes = query_svc.or(health_check);
 
-    // spawn our websocket control server
+    // spawn our network service for the other instances
     tokio::spawn(warp::serve(routes).run(addr.into()));
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct HostQuery {
     pub host: String,
+    /// the kind of remote stream to route, or any tunnel on exactly `host` if unset
+    #[serde(default)]
+    pub kind: Option<TunnelKind>,
+    /// the path of an http request, to route by the clients' path prefixes
+    #[serde(default)]
+    pub path: Option<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct HostQueryResponse {
     pub client_id: Option<ClientId>,
+    /// where we take remote streams
+    #[serde(default)]
+    pub remote_port: Option<u16>,
 }
 
 fn handle_query(query: HostQuery) -> HostQueryResponse {
     tracing::debug!(host=%query.host, "got query");
+    let client_id = match query.kind {
+        None => Connections::client_for_host(&query.host),
+        Some(kind) => {
+            let client = match &query.path {
+                Some(path) => Connections::peek_by_path(&query.host, path),
+                None => Connections::peek_by_host(&query.host),
+            };
+            client
+                .filter(|client| client.kind == kind)
+                .or_else(|| {
+                    let (_, domain) = crate::remote::validate_host_prefix(&query.host)?;
+                    Connections::find_wildcard(&domain, kind)
+                })
+                .map(|client| client.id)
+        }
+    };
+
     HostQueryResponse {
-        client_id: Connections::client_for_host(&query.host),
+        client_id,
+        remote_port: Some(CONFIG.remote_port),
     }
 }
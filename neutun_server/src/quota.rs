@@ -0,0 +1,327 @@
+use super::*;
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use thiserror::Error;
+
+/// How often we write the usage file, so a crash loses at most this much of it
+const SAVE_INTERVAL: Duration = Duration::from_secs(30);
+/// How often we remind a client that its key's throughput cap is slowing it down
+const THROTTLED_NOTICE_INTERVAL: Duration = Duration::from_secs(60);
+
+lazy_static! {
+    /// the throttle each key's tunnels share, by key name
+    static ref THROTTLES: DashMap<String, Arc<Throttle>> = DashMap::new();
+}
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("IOError: {0}")]
+    Io(#[from] std::io::Error),
+
+    #[error("invalid json: {0}")]
+    Json(#[from] serde_json::Error),
+}
+
+/// The bytes a key transferred in a month
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct KeyUsage {
+    /// i.e. `2024-05`
+    pub month: String,
+    pub bytes: u64,
+}
+
+/// Bytes transferred by every api key this month, by key name, persisted to a json file
+pub struct Usage {
+    path: PathBuf,
+    by_key: DashMap<String, KeyUsage>,
+    /// changed since we last saved
+    dirty: AtomicBool,
+    /// serializes writes to the file
+    save_lock: Mutex<()>,
+}
+
+impl Usage {
+    /// Load the usage file, starting empty if it doesn't exist yet
+    pub fn load(path: PathBuf) -> Self {
+        let by_key: BTreeMap<String, KeyUsage> = match std::fs::read(&path) {
+            Ok(data) => serde_json::from_slice(&data)
+                .unwrap_or_else(|e| panic!("invalid usage file {:?}: {}", path, e)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(e) => panic!("failed to read usage file {:?}: {}", path, e),
+        };
+
+        Usage {
+            path,
+            by_key: by_key.into_iter().collect(),
+            dirty: AtomicBool::new(false),
+            save_lock: Mutex::new(()),
+        }
+    }
+
+    /// The bytes a key transferred this month
+    pub fn get(&self, key_name: &str) -> u64 {
+        let month = current_month();
+        self.by_key
+            .get(key_name)
+            .filter(|usage| usage.month == month)
+            .map_or(0, |usage| usage.bytes)
+    }
+
+    /// Count bytes against a key unless they'd take it past `limit`, returning its total for this month
+    pub fn add_within(&self, key_name: &str, bytes: u64, limit: Option<u64>) -> Option<u64> {
+        let month = current_month();
+        let mut usage = self.by_key.entry(key_name.to_string()).or_default();
+        if usage.month != month {
+            *usage = KeyUsage { month, bytes: 0 };
+        }
+        if limit.is_some_and(|limit| usage.bytes + bytes > limit) {
+            return None;
+        }
+
+        usage.bytes += bytes;
+        self.dirty.store(true, Ordering::Relaxed);
+        Some(usage.bytes)
+    }
+
+    /// Start a key's month over
+    pub fn reset(&self, key_name: &str) -> Result<(), Error> {
+        self.by_key.remove(key_name);
+        self.save()
+    }
+
+    pub fn list(&self) -> BTreeMap<String, KeyUsage> {
+        self.by_key
+            .iter()
+            .map(|u| (u.key().clone(), u.value().clone()))
+            .collect()
+    }
+
+    fn save(&self) -> Result<(), Error> {
+        let _guard = self.save_lock.lock().unwrap_or_else(|e| e.into_inner());
+        self.dirty.store(false, Ordering::Relaxed);
+        let by_key = self.list();
+
+        // write a temporary file first, so a crash never leaves a truncated file behind
+        let tmp = self.path.with_extension("tmp");
+        std::fs::write(&tmp, serde_json::to_vec_pretty(&by_key)?)?;
+        std::fs::rename(&tmp, &self.path)?;
+        Ok(())
+    }
+}
+
+fn current_month() -> String {
+    Utc::now().format("%Y-%m").to_string()
+}
+
+/// Write the usage file whenever it changed
+pub fn spawn_save() {
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(SAVE_INTERVAL).await;
+            if !USAGE.dirty.load(Ordering::Relaxed) {
+                continue;
+            }
+
+            if let Err(error) = USAGE.save() {
+                tracing::error!(%error, "failed to save usage file");
+            }
+        }
+    });
+}
+
+/// Shapes traffic to `rate` bytes a second, letting through bursts of a second's worth
+#[derive(Debug)]
+struct Throttle {
+    rate: u64,
+    /// the bytes we may send right away, negative once we're in debt, and when we last counted them
+    tokens: Mutex<(f64, Instant)>,
+}
+
+impl Throttle {
+    fn new(rate: u64) -> Self {
+        Throttle {
+            rate,
+            tokens: Mutex::new((rate as f64, Instant::now())),
+        }
+    }
+
+    /// Take `bytes`, returning how long to wait before sending them
+    fn take(&self, bytes: usize) -> Duration {
+        let rate = self.rate as f64;
+        let mut tokens = self.tokens.lock().unwrap();
+        let now = Instant::now();
+        tokens.0 = (tokens.0 + now.duration_since(tokens.1).as_secs_f64() * rate).min(rate);
+        tokens.1 = now;
+        tokens.0 -= bytes as f64;
+
+        if tokens.0 >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-tokens.0 / rate)
+        }
+    }
+}
+
+/// Returned once a key used up its transfer quota for the month
+#[derive(Debug)]
+pub struct QuotaExceeded;
+
+/// The throughput cap and transfer quota of a client's key
+#[derive(Debug, Default)]
+pub struct Quota {
+    key_name: Option<String>,
+    monthly_bytes: Option<u64>,
+    throttle: Option<Arc<Throttle>>,
+    last_throttled_notice: Mutex<Option<Instant>>,
+    exceeded_notice_sent: AtomicBool,
+}
+
+impl Quota {
+    /// The limits of a key from the key store, the master key and anonymous clients have none
+    pub fn new(key_name: Option<&str>) -> Self {
+        let (name, key) = match key_name.and_then(|name| Some((name, KEYS.get(name)?))) {
+            Some(named) => named,
+            None => return Quota::default(),
+        };
+
+        // clients of the same key share a throttle, a new one replaces it if the key's cap changed
+        let throttle = key.max_bytes_per_sec.map(|rate| {
+            THROTTLES
+                .entry(name.to_string())
+                .and_modify(|throttle| {
+                    if throttle.rate != rate {
+                        *throttle = Arc::new(Throttle::new(rate));
+                    }
+                })
+                .or_insert_with(|| Arc::new(Throttle::new(rate)))
+                .clone()
+        });
+
+        Quota {
+            key_name: Some(name.to_string()),
+            monthly_bytes: key.monthly_quota_bytes,
+            throttle,
+            ..Default::default()
+        }
+    }
+
+    /// The key used up its transfer quota for the month
+    pub fn is_exceeded(&self) -> bool {
+        match (&self.key_name, self.monthly_bytes) {
+            (Some(name), Some(quota)) => USAGE.get(name) >= quota,
+            _ => false,
+        }
+    }
+}
+
+/// Count bytes through a client's tunnel against its key, returning how long its stream should hold back to fit the key's throughput cap.
+/// Bytes past the key's quota aren't counted, the stream ends instead. The client hears about both.
+pub fn consume(client: &ConnectedClient, bytes: usize) -> Result<Duration, QuotaExceeded> {
+    let quota = &client.quota;
+    let name = match &quota.key_name {
+        Some(name) => name,
+        None => return Ok(Duration::ZERO),
+    };
+
+    if USAGE.add_within(name, bytes as u64, quota.monthly_bytes).is_none() {
+        let quota_bytes = quota.monthly_bytes.unwrap_or_default();
+        if !quota.exceeded_notice_sent.swap(true, Ordering::Relaxed) {
+            tracing::info!(key = %name, client_id = %client.id, quota_bytes, "transfer quota exceeded");
+            notify(client, Notice::QuotaExceeded { quota_bytes });
+        }
+        return Err(QuotaExceeded);
+    }
+
+    let throttle = match &quota.throttle {
+        Some(throttle) => throttle,
+        None => return Ok(Duration::ZERO),
+    };
+    let wait = throttle.take(bytes);
+    if wait.is_zero() {
+        return Ok(wait);
+    }
+
+    let notify_now = {
+        let mut last = quota.last_throttled_notice.lock().unwrap();
+        let due = last.is_none_or(|last| last.elapsed() >= THROTTLED_NOTICE_INTERVAL);
+        if due {
+            *last = Some(Instant::now());
+        }
+        due
+    };
+    if notify_now {
+        tracing::debug!(key = %name, client_id = %client.id, "throttling tunnel");
+        notify(client, Notice::Throttled { bytes_per_sec: throttle.rate });
+    }
+
+    Ok(wait)
+}
+
+/// Queue a notice without waiting on the client, it's only informational
+fn notify(client: &ConnectedClient, notice: Notice) {
+    if !client.supports(Capability::Notices) {
+        return;
+    }
+
+    let _ = client
+        .tx
+        .clone()
+        .try_send(ControlPacket::Notice(notice))
+        .map_err(|error| tracing::debug!(?error, "failed to send notice"));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn quota_is_checked_before_counting() {
+        let path = std::env::temp_dir().join(format!("neutun-usage-{}.json", std::process::id()));
+        let usage = Usage::load(path);
+
+        assert_eq!(usage.add_within("key", 60, Some(100)), Some(60));
+        // would go past the quota, so it isn't counted
+        assert_eq!(usage.add_within("key", 50, Some(100)), None);
+        assert_eq!(usage.get("key"), 60);
+        assert_eq!(usage.add_within("key", 40, Some(100)), Some(100));
+        assert_eq!(usage.add_within("key", 1, Some(100)), None);
+        assert_eq!(usage.get("key"), 100);
+
+        assert_eq!(usage.add_within("other", 1000, None), Some(1000));
+        assert_eq!(usage.get("unknown"), 0);
+    }
+
+    #[test]
+    fn usage_starts_over_every_month() {
+        let path = std::env::temp_dir().join(format!("neutun-usage-month-{}.json", std::process::id()));
+        let usage = Usage::load(path);
+        usage.by_key.insert(
+            "key".into(),
+            KeyUsage {
+                month: "2000-01".into(),
+                bytes: 100,
+            },
+        );
+
+        assert_eq!(usage.get("key"), 0);
+        assert_eq!(usage.add_within("key", 10, Some(100)), Some(10));
+    }
+
+    #[test]
+    fn throttle_lets_a_burst_through_then_spaces_out_the_rest() {
+        let throttle = Throttle::new(1000);
+        assert_eq!(throttle.take(600), Duration::ZERO);
+        assert_eq!(throttle.take(400), Duration::ZERO);
+
+        // a second's worth is gone, more has to wait for its share of the rate
+        let wait = throttle.take(500);
+        assert!(wait > Duration::from_millis(450) && wait <= Duration::from_millis(500), "{:?}", wait);
+        let wait = throttle.take(500);
+        assert!(wait > Duration::from_millis(950) && wait <= Duration::from_secs(1), "{:?}", wait);
+    }
+}
@@ -0,0 +1,263 @@
+use super::*;
+use std::net::Ipv6Addr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// How often we forget remote ips that stopped using a tunnel
+const SWEEP_INTERVAL: Duration = Duration::from_secs(30);
+
+/// A token bucket refilling at `rate` tokens a second, holding up to a second's worth
+#[derive(Debug)]
+struct Bucket {
+    rate: f64,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn new(rate: u32) -> Self {
+        Bucket {
+            rate: rate as f64,
+            tokens: rate as f64,
+            updated: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.rate);
+        self.updated = now;
+    }
+
+    fn has_token(&mut self) -> bool {
+        self.refill();
+        self.tokens >= 1.0
+    }
+
+    /// Only after `has_token` said there is one
+    fn take(&mut self) {
+        self.tokens -= 1.0;
+    }
+
+    /// Nothing was taken from the bucket for long enough that it could be started over
+    fn is_full(&mut self) -> bool {
+        self.refill();
+        self.tokens >= self.rate
+    }
+}
+
+/// The buckets of a single remote ip
+#[derive(Debug)]
+struct IpBuckets {
+    requests: Option<Bucket>,
+    connections: Option<Bucket>,
+}
+
+/// Who a per-ip bucket is for: the ip itself, or for ipv6 its /64,
+/// which is usually a single visitor's and would otherwise give them endless fresh buckets
+fn bucket_key(ip: IpAddr) -> IpAddr {
+    match ip.to_canonical() {
+        IpAddr::V6(ip) => IpAddr::V6(Ipv6Addr::from(u128::from(ip) & !(u64::MAX as u128))),
+        ip => ip,
+    }
+}
+
+/// Which rate limit a request or connection ran into
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Limited {
+    Tunnel,
+    Ip,
+}
+
+/// A tunnel's rate limits, with the buckets for the whole tunnel and for every remote ip using it
+#[derive(Debug)]
+pub struct RateLimiter {
+    limits: RateLimits,
+    requests: Option<Mutex<Bucket>>,
+    connections: Option<Mutex<Bucket>>,
+    per_ip: DashMap<IpAddr, IpBuckets>,
+}
+
+impl RateLimiter {
+    /// Limit a tunnel by the tighter of the server's and the client's limits
+    pub fn new(client: &RateLimits) -> Self {
+        let limits = CONFIG.rate_limits.tighten(client);
+        RateLimiter {
+            limits,
+            requests: limits.requests.map(|rate| Mutex::new(Bucket::new(rate))),
+            connections: limits.connections.map(|rate| Mutex::new(Bucket::new(rate))),
+            per_ip: DashMap::new(),
+        }
+    }
+
+    pub fn limits(&self) -> &RateLimits {
+        &self.limits
+    }
+
+    /// Take a token for an http request from `ip`
+    pub fn check_request(&self, ip: IpAddr) -> Result<(), Limited> {
+        self.check(ip, &self.requests, |buckets| buckets.requests.as_mut())
+    }
+
+    /// Take a token for a new public connection from `ip`
+    pub fn check_connection(&self, ip: IpAddr) -> Result<(), Limited> {
+        self.check(ip, &self.connections, |buckets| buckets.connections.as_mut())
+    }
+
+    /// The remote ip's bucket goes first, so a single busy ip doesn't use up the whole tunnel's.
+    /// Tokens are only taken once both buckets have one, so a refusal costs neither.
+    fn check(
+        &self,
+        ip: IpAddr,
+        tunnel: &Option<Mutex<Bucket>>,
+        bucket: impl FnOnce(&mut IpBuckets) -> Option<&mut Bucket>,
+    ) -> Result<(), Limited> {
+        let per_ip = self.limits.requests_per_ip.is_some() || self.limits.connections_per_ip.is_some();
+        let mut ip_buckets = per_ip.then(|| {
+            self.per_ip
+                .entry(bucket_key(ip))
+                .or_insert_with(|| IpBuckets {
+                    requests: self.limits.requests_per_ip.map(Bucket::new),
+                    connections: self.limits.connections_per_ip.map(Bucket::new),
+                })
+        });
+        let mut ip_bucket = ip_buckets.as_deref_mut().and_then(bucket);
+        if !ip_bucket.as_deref_mut().is_none_or(Bucket::has_token) {
+            return Err(Limited::Ip);
+        }
+
+        let mut tunnel = tunnel.as_ref().map(|bucket| bucket.lock().unwrap());
+        if !tunnel.as_deref_mut().is_none_or(Bucket::has_token) {
+            return Err(Limited::Tunnel);
+        }
+
+        ip_bucket.map(Bucket::take);
+        tunnel.as_deref_mut().map(Bucket::take);
+        Ok(())
+    }
+
+    /// Forget the remote ips whose buckets have filled up again
+    fn sweep(&self) {
+        self.per_ip.retain(|_, buckets| {
+            let requests_full = buckets.requests.as_mut().is_none_or(Bucket::is_full);
+            let connections_full = buckets.connections.as_mut().is_none_or(Bucket::is_full);
+            !(requests_full && connections_full)
+        });
+    }
+}
+
+/// Keep the per-ip buckets of every tunnel from growing without bound
+pub fn spawn_sweep() {
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(SWEEP_INTERVAL).await;
+            for client in CONNECTIONS.get_all_clients() {
+                client.rate_limiter.sweep();
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limiter(limits: RateLimits) -> RateLimiter {
+        RateLimiter::new(&limits)
+    }
+
+    /// Pretend `bucket` was last refilled `ago`
+    fn rewind(bucket: &mut Bucket, ago: Duration) {
+        bucket.updated -= ago;
+    }
+
+    #[test]
+    fn buckets_allow_a_burst_and_then_refill() {
+        let mut bucket = Bucket::new(5);
+        for _ in 0..5 {
+            assert!(bucket.has_token());
+            bucket.take();
+        }
+        assert!(!bucket.has_token());
+
+        rewind(&mut bucket, Duration::from_millis(400));
+        assert!(bucket.has_token());
+        assert!(bucket.tokens >= 2.0 && bucket.tokens < 3.0);
+
+        // never more than a second's worth
+        rewind(&mut bucket, Duration::from_secs(60));
+        assert!(bucket.is_full());
+        assert_eq!(bucket.tokens, 5.0);
+    }
+
+    #[test]
+    fn a_busy_ip_is_refused_before_it_costs_the_tunnel() {
+        let limiter = limiter(RateLimits {
+            requests: Some(3),
+            requests_per_ip: Some(1),
+            ..Default::default()
+        });
+        let ip = |n: u8| IpAddr::from([192, 0, 2, n]);
+
+        assert_eq!(limiter.check_request(ip(1)), Ok(()));
+        assert_eq!(limiter.check_request(ip(1)), Err(Limited::Ip));
+        // the refused request left the tunnel with two
+        assert_eq!(limiter.check_request(ip(2)), Ok(()));
+        assert_eq!(limiter.check_request(ip(3)), Ok(()));
+        assert_eq!(limiter.check_request(ip(4)), Err(Limited::Tunnel));
+
+        // and a request the tunnel refused left the ip its token
+        let mut buckets = limiter.per_ip.get_mut(&ip(4)).unwrap();
+        assert!(buckets.requests.as_mut().unwrap().has_token());
+    }
+
+    #[test]
+    fn connections_and_requests_are_counted_apart() {
+        let limiter = limiter(RateLimits {
+            connections_per_ip: Some(1),
+            ..Default::default()
+        });
+        let ip = IpAddr::from([192, 0, 2, 1]);
+
+        assert_eq!(limiter.check_connection(ip), Ok(()));
+        assert_eq!(limiter.check_connection(ip), Err(Limited::Ip));
+        assert_eq!(limiter.check_request(ip), Ok(()));
+        assert_eq!(limiter.check_request(ip), Ok(()));
+    }
+
+    #[test]
+    fn an_ipv6_visitor_gets_one_bucket_for_its_network() {
+        let limiter = limiter(RateLimits {
+            requests_per_ip: Some(1),
+            ..Default::default()
+        });
+        let ip = |ip: &str| ip.parse::<IpAddr>().unwrap();
+
+        assert_eq!(limiter.check_request(ip("2001:db8:1:2::1")), Ok(()));
+        assert_eq!(limiter.check_request(ip("2001:db8:1:2:ffff::7")), Err(Limited::Ip));
+        assert_eq!(limiter.check_request(ip("2001:db8:1:3::1")), Ok(()));
+        // ipv4 clients on our dual stack listeners still count by address
+        assert_eq!(limiter.check_request(ip("::ffff:192.0.2.1")), Ok(()));
+        assert_eq!(limiter.check_request(ip("192.0.2.2")), Ok(()));
+        assert_eq!(limiter.check_request(ip("192.0.2.1")), Err(Limited::Ip));
+        assert_eq!(limiter.per_ip.len(), 4);
+    }
+
+    #[test]
+    fn sweep_forgets_ips_once_their_buckets_fill_up() {
+        let limiter = limiter(RateLimits {
+            requests_per_ip: Some(2),
+            ..Default::default()
+        });
+        let (idle, busy) = (IpAddr::from([192, 0, 2, 1]), IpAddr::from([192, 0, 2, 2]));
+        limiter.check_request(idle).unwrap();
+        limiter.check_request(busy).unwrap();
+
+        rewind(limiter.per_ip.get_mut(&idle).unwrap().requests.as_mut().unwrap(), Duration::from_secs(1));
+        limiter.sweep();
+
+        assert!(!limiter.per_ip.contains_key(&idle));
+        assert!(limiter.per_ip.contains_key(&busy));
+    }
+}
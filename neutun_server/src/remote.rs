@@ -1,106 +1,99 @@
 use super::*;
+use std::net::SocketAddr;
+use std::time::Instant;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::io::{ReadHalf, WriteHalf};
-use tokio::net::TcpStream;
+use tokio::time::Duration;
 use tracing::debug;
 use tracing::{error, Instrument};
 
-async fn direct_to_control(mut incoming: TcpStream) {
-    let mut control_socket =
-        match TcpStream::connect(format!("localhost:{}", CONFIG.control_port)).await {
-            Ok(s) => s,
-            Err(error) => {
-                tracing::warn!(?error, "failed to connect to local control server");
+#[tracing::instrument(skip(socket, info), fields(peer_addr = %info.peer_addr))]
+pub async fn accept_connection(socket: RemoteStream, mut info: remote_http::ConnectionInfo) {
+    let peer_addr = info.peer_addr;
+    // plain http is routed request by request, only tls has to be routed by its sni up front
+    let peeked = match peek_connection(socket).await {
+        Some(Peeked::Http(socket)) => {
+            remote_http::serve(socket, info).await;
+            return;
+        }
+        Some(Peeked::Tls(peeked)) => peeked,
+        None => return,
+    };
+
+    // terminate tls ourselves, unless a passthrough tunnel wants the encrypted stream.
+    // encrypted streams can only go to tls passthrough tunnels, routed by their sni.
+    let StreamWithPeekedHost { socket, host } = peeked;
+    let client = host.as_deref().and_then(find_passthrough_client);
+    let (client, host) = match (client, host) {
+        (Some(client), Some(host)) => (client, host),
+        (_, host) => {
+            // a passthrough tunnel on another instance needs the stream still encrypted
+            let instance = match host.as_deref() {
+                Some(host) => find_passthrough_instance(host).await,
+                None => None,
+            };
+            if let Some(instance) = instance {
+                network::proxy_stream(instance, socket, peer_addr).await;
                 return;
             }
-        };
 
-    let (mut control_r, mut control_w) = control_socket.split();
-    let (mut incoming_r, mut incoming_w) = incoming.split();
-
-    let join_1 = tokio::io::copy(&mut control_r, &mut incoming_w);
-    let join_2 = tokio::io::copy(&mut incoming_r, &mut control_w);
+            // without sni we serve our default certificate, and route by the requests' host
+            if let Some(acceptor) = tls::acceptor() {
+                if let Some(socket) = tls::handshake(acceptor, socket).await {
+                    info.https = true;
+                    remote_http::serve(socket, info).await;
+                }
+                return;
+            }
 
-    match futures::future::join(join_1, join_2).await {
-        (Ok(_), Ok(_)) => {}
-        (Err(error), _) | (_, Err(error)) => {
-            tracing::error!(?error, "directing stream to control failed");
+            // tls clients can't read a plaintext error response, so we just hang up on them
+            error!(?host, "no tunnel found");
+            return;
         }
-    }
-}
-
-#[tracing::instrument(skip(socket))]
-pub async fn accept_connection(socket: TcpStream) {
-    // peek the host of the http request
-    // if health check, then handle it and return
-    let StreamWithPeekedHost {
-        mut socket,
-        host,
-        forwarded_for,
-    } = match peek_http_request_host(socket).await {
-        Some(s) => s,
-        None => return,
     };
 
-    tracing::info!(%host, %forwarded_for, "new remote connection");
+    tracing::info!(%host, "new remote tls connection");
 
-    // parse the host string and find our client
-    let host_no_port = host.split(":").next().unwrap_or(&host).to_string();
-
-    if CONFIG.allowed_hosts.contains(&host_no_port) {
-        // error!("serving hello world");
-        let _ = socket.write_all(HTTP_HELLO_WORLD_RESPONSE).await;
+    if !client.policy.allows_ip(peer_addr.ip()) {
+        tracing::info!(%host, %peer_addr, "connection denied by edge policy");
         return;
     }
 
-    let (sub_domain, domain) = match validate_host_prefix(&host_no_port) {
-        Some(pair) => pair,
-        None => {
-            error!("invalid host specified");
-            let _ = socket.write_all(HTTP_INVALID_HOST_RESPONSE).await;
-            return;
-        }
-    };
+    if let Err(limited) = client.rate_limiter.check_connection(peer_addr.ip()) {
+        tracing::info!(%host, %peer_addr, ?limited, "connection rate limited");
+        metrics::rate_limited("connection", limited);
+        return;
+    }
 
-    // Special case -- we redirect this tcp connection to the control server
-    if sub_domain == "wormhole" {
-        direct_to_control(socket).await;
+    if client.quota.is_exceeded() {
+        tracing::info!(%host, %peer_addr, "transfer quota exceeded, dropping connection");
         return;
     }
 
-    let full_host = format!("{}.{}", sub_domain, domain);
-
-    // find the client listening for this host
-    let client = match Connections::find_by_host(&full_host) {
-        Some(client) => client.clone(),
-        None => {
-            // try to find a wildcard client for this domain
-            if let Some(client) = Connections::find_wildcard(&domain) {
-                 client
-            } else {
-                // check other instances that may be serving this host
-                match network::instance_for_host(&full_host).await {
-                    Ok((instance, _)) => {
-                        network::proxy_stream(instance, socket).await;
-                        return;
-                    }
-                    Err(network::Error::DoesNotServeHost) => {
-                        error!(%host, "no tunnel found");
-                        let _ = socket.write_all(HTTP_NOT_FOUND_RESPONSE).await;
-                        return;
-                    }
-                    Err(error) => {
-                        error!(%host, ?error, "failed to find instance");
-                        let _ = socket.write_all(HTTP_ERROR_LOCATING_HOST_RESPONSE).await;
-                        return;
-                    }
-                }
-            }
-        }
+    let metadata = StreamMetadata {
+        peer_addr: Some(canonical_addr(peer_addr)),
+        host,
+        tls: true,
+        public_port: Some(info.public_port),
     };
+    stream_to_client(client, socket, metadata);
+}
+
+/// Our listeners are dual stack, so ipv4 clients show up as ipv4 mapped ipv6 addresses
+pub fn canonical_addr(addr: SocketAddr) -> SocketAddr {
+    SocketAddr::new(addr.ip().to_canonical(), addr.port())
+}
+
+/// Tunnel a public connection through to a client
+pub fn stream_to_client(
+    client: ConnectedClient,
+    socket: RemoteStream,
+    metadata: StreamMetadata,
+) -> StreamId {
+    let host = metadata.host.clone();
 
-    // allocate a new stream for this request
-    let (active_stream, queue_rx) = ActiveStream::new(client.clone());
+    // allocate a new stream for this connection
+    let (active_stream, queue_rx) = ActiveStream::new(client.clone(), metadata);
     let stream_id = active_stream.id.clone();
 
     tracing::debug!(
@@ -123,16 +116,19 @@ pub async fn accept_connection(socket: TcpStream) {
 
     // read from client, write to socket
     let span = observability::remote_trace("neutun_stream");
+    let id = stream_id.clone();
     tokio::spawn(
         async move {
-            neutun_stream(host, stream_id, sink, queue_rx).await;
+            neutun_stream(host, client, id, sink, queue_rx).await;
         }
         .instrument(span),
     );
+
+    stream_id
 }
 
 // Returns (subdomain, domain)
-fn validate_host_prefix(host: &str) -> Option<(String, String)> {
+pub fn validate_host_prefix(host: &str) -> Option<(String, String)> {
     // host is already host_no_port from caller
 
     for allowed in &CONFIG.allowed_hosts {
@@ -151,111 +147,142 @@ fn validate_host_prefix(host: &str) -> Option<(String, String)> {
     None
 }
 
+/// The host a tunnel registers for a public host, and the allowed host its wildcard tunnels would be on.
+/// Hosts that aren't on one of our allowed hosts can only be custom domains, which have no wildcards.
+pub fn tunnel_host(host: &str) -> (String, Option<String>) {
+    match validate_host_prefix(host) {
+        Some((sub_domain, domain)) => (format!("{}.{}", sub_domain, domain), Some(domain)),
+        None => (host.to_lowercase(), None),
+    }
+}
+
+/// Find the tunnel that wants encrypted tls connections to this host
+fn find_passthrough_client(host: &str) -> Option<ConnectedClient> {
+    let (full_host, domain) = tunnel_host(host);
+    Connections::find_by_host(&full_host)
+        .filter(|client| client.kind == TunnelKind::Tls)
+        .or_else(|| Connections::find_wildcard(&domain?, TunnelKind::Tls))
+}
+
+/// The instance holding a tls passthrough tunnel for the host, when it isn't us.
+/// Only asks the other instances when no tunnel here would take the decrypted requests.
+async fn find_passthrough_instance(host: &str) -> Option<network::Instance> {
+    let (full_host, domain) = tunnel_host(host);
+    if Connections::serves_host(&full_host, domain.as_deref()) {
+        return None;
+    }
+
+    network::instance_for_host(&full_host, Some(TunnelKind::Tls))
+        .await
+        .ok()
+        .map(|(instance, _)| instance)
+}
 
 /// Response Constants
-const HTTP_HELLO_WORLD_RESPONSE: &'static [u8] =
-    b"HTTP/1.1 200 OK\r\nContent-Length: 12\r\n\r\nHello World!";
-const HTTP_INVALID_HOST_RESPONSE: &'static [u8] =
-    b"HTTP/1.1 400\r\nContent-Length: 23\r\n\r\nError: Invalid Hostname";
 const HTTP_NOT_FOUND_RESPONSE: &'static [u8] =
     b"HTTP/1.1 404\r\nContent-Length: 23\r\n\r\nError: Tunnel Not Found";
-const HTTP_ERROR_LOCATING_HOST_RESPONSE: &'static [u8] =
-    b"HTTP/1.1 500\r\nContent-Length: 27\r\n\r\nError: Error finding tunnel";
 const HTTP_TUNNEL_REFUSED_RESPONSE: &'static [u8] =
     b"HTTP/1.1 500\r\nContent-Length: 32\r\n\r\nTunnel says: connection refused.";
-const HTTP_OK_RESPONSE: &'static [u8] = b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok";
-const HEALTH_CHECK_PATH: &'static [u8] = b"/0xDEADBEEF_HEALTH_CHECK";
 
+/// how long we wait for a client to say anything, and then to finish its tls ClientHello
+const PEEK_TIMEOUT: Duration = Duration::from_secs(5);
+/// a ClientHello fits in a single record of at most 16kb
+const MAX_RECORD_PEEK: usize = 16 * 1024 + 5;
+
+/// A tls connection, still encrypted, and the host from its sni
 struct StreamWithPeekedHost {
-    socket: TcpStream,
-    host: String,
-    forwarded_for: String,
+    socket: RemoteStream,
+    /// none if the client sent no sni, only a tunnel we terminate tls for can take it then
+    host: Option<String>,
 }
-/// Filter incoming remote streams
+
+/// What a public connection turned out to be
+enum Peeked {
+    /// anything but tls, for our http server to make sense of
+    Http(RemoteStream),
+    Tls(StreamWithPeekedHost),
+}
+
+/// Tell tls from plain http by the first bytes of a connection
 #[tracing::instrument(skip(socket))]
-async fn peek_http_request_host(mut socket: TcpStream) -> Option<StreamWithPeekedHost> {
-    /// Note we return out if the host header is not found
-    /// within the first 4kb of the request.
-    const MAX_HEADER_PEAK: usize = 4096;
-    let mut buf = vec![0; MAX_HEADER_PEAK]; //1kb
-
-    tracing::debug!("checking stream headers");
-
-    let n = match socket.peek(&mut buf).await {
-        Ok(n) => n,
-        Err(e) => {
-            error!("failed to read from tcp socket to determine host: {:?}", e);
+async fn peek_connection(mut socket: RemoteStream) -> Option<Peeked> {
+    let n = match tokio::time::timeout(PEEK_TIMEOUT, socket.peek_more(MAX_RECORD_PEEK)).await {
+        Ok(Ok(peeked)) => peeked.len(),
+        Ok(Err(e)) => {
+            error!("failed to read from tcp socket: {:?}", e);
+            return None;
+        }
+        Err(_) => {
+            tracing::debug!(
+                "timed out waiting for the client to say anything, dropping connection."
+            );
             return None;
         }
     };
 
-    // make sure we're not peeking the same header bytes
     if n == 0 {
-        tracing::debug!("unable to peek header bytes");
+        tracing::debug!("connection closed before sending anything");
         return None;
     }
 
-    tracing::debug!("peeked {} stream bytes ", n);
-
-    let mut headers = [httparse::EMPTY_HEADER; 64]; // 30 seems like a generous # of headers
-    let mut req = httparse::Request::new(&mut headers);
-
-    if let Err(e) = req.parse(&buf[..n]) {
-        error!("failed to parse incoming http bytes: {:?}", e);
-        return None;
+    if !sni::is_tls(socket.peeked()) {
+        return Some(Peeked::Http(socket));
     }
+    peek_tls_server_name(socket).await.map(Peeked::Tls)
+}
 
-    // Handle the health check route
-    if req.path.map(|s| s.as_bytes()) == Some(HEALTH_CHECK_PATH) {
-        let _ = socket.write_all(HTTP_OK_RESPONSE).await.map_err(|e| {
-            error!("failed to write health_check: {:?}", e);
-        });
+/// Peek the SNI host of an incoming TLS handshake, without terminating it
+async fn peek_tls_server_name(mut socket: RemoteStream) -> Option<StreamWithPeekedHost> {
+    // the ClientHello may arrive over several packets, wait until we have all of it
+    let peek_record = async {
+        loop {
+            let buf = socket.peeked();
+            let complete = match sni::first_record_len(buf) {
+                Some(len) => buf.len() >= len.min(MAX_RECORD_PEEK),
+                None => false,
+            };
+            if complete || buf.len() >= MAX_RECORD_PEEK {
+                return Ok(buf.to_vec());
+            }
 
-        return None;
-    }
+            let peeked = buf.len();
+            if socket.peek_more(MAX_RECORD_PEEK - peeked).await?.len() == peeked {
+                return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof));
+            }
+        }
+    };
 
-    // get the ip addr in the header
-    let forwarded_for = if let Some(Ok(forwarded_for)) = req
-        .headers
-        .iter()
-        .filter(|h| h.name.to_lowercase() == "x-forwarded-for".to_string())
-        .map(|h| std::str::from_utf8(h.value))
-        .next()
-    {
-        forwarded_for.to_string()
-    } else {
-        String::default()
+    let buf = match tokio::time::timeout(PEEK_TIMEOUT, peek_record).await {
+        Ok(Ok(buf)) => buf,
+        Ok(Err(e)) => {
+            error!("failed to read from tcp socket to determine sni: {:?}", e);
+            return None;
+        }
+        Err(_) => {
+            tracing::info!("timed out waiting for tls client hello, dropping connection.");
+            return None;
+        }
     };
 
-    // look for a host header
-    if let Some(Ok(host)) = req
-        .headers
-        .iter()
-        .filter(|h| h.name.to_lowercase() == "host".to_string())
-        .map(|h| std::str::from_utf8(h.value))
-        .next()
-    {
-        tracing::info!(host=%host, path=%req.path.unwrap_or_default(), "peek request");
-
-        return Some(StreamWithPeekedHost {
-            socket,
-            host: host.to_string(),
-            forwarded_for,
-        });
+    let host = sni::server_name(&buf);
+    match &host {
+        Some(host) => tracing::info!(host=%host, "peek tls client hello"),
+        None => tracing::info!("found no sni host"),
     }
-
-    tracing::info!("found no host header, dropping connection.");
-    None
+    Some(StreamWithPeekedHost { socket, host })
 }
 
 /// Process Messages from the control path in & out of the remote stream
 #[tracing::instrument(skip(tunnel_stream, tcp_stream))]
-async fn process_tcp_stream(mut tunnel_stream: ActiveStream, mut tcp_stream: ReadHalf<TcpStream>) {
+async fn process_tcp_stream(
+    mut tunnel_stream: ActiveStream,
+    mut tcp_stream: ReadHalf<RemoteStream>,
+) {
     // send initial control stream init to client
     control_server::send_client_stream_init(tunnel_stream.clone()).await;
 
     // now read from stream and forward to clients
-    let mut buf = [0; 1024];
+    let mut buf = [0; STREAM_CHUNK_SIZE];
 
     loop {
         // client is no longer connected
@@ -290,8 +317,34 @@ async fn process_tcp_stream(mut tunnel_stream: ActiveStream, mut tcp_stream: Rea
 
         debug!("read {} bytes", n);
 
+        // wait until the key's throughput cap lets more through, or give up once its quota is used up
+        match quota::consume(&tunnel_stream.client, n) {
+            Ok(wait) => tokio::time::sleep(wait).await,
+            Err(_) => {
+                debug!("transfer quota exceeded, ending stream");
+                let _ = tunnel_stream
+                    .client
+                    .tx
+                    .send(ControlPacket::End(tunnel_stream.id.clone()))
+                    .await;
+                return;
+            }
+        }
+
+        // wait until the client is ready for more
+        if !tunnel_stream.window.reserve(n).await {
+            debug!("stream window closed");
+            let _ = tunnel_stream
+                .client
+                .tx
+                .send(ControlPacket::End(tunnel_stream.id.clone()))
+                .await;
+            return;
+        }
+
         let data = &buf[..n];
         let packet = ControlPacket::Data(tunnel_stream.id.clone(), data.to_vec());
+        tunnel_stream.client.stats.add_in(n);
 
         match tunnel_stream.client.tx.send(packet.clone()).await {
             Ok(_) => debug!(client_id = %tunnel_stream.client.id, "sent data packet to client"),
@@ -303,27 +356,49 @@ async fn process_tcp_stream(mut tunnel_stream: ActiveStream, mut tcp_stream: Rea
     }
 }
 
-#[tracing::instrument(skip(sink, stream_id, queue))]
+#[tracing::instrument(skip(client, sink, stream_id, queue))]
 async fn neutun_stream(
     subdomain: String,
+    mut client: ConnectedClient,
     stream_id: StreamId,
-    mut sink: WriteHalf<TcpStream>,
-    mut queue: UnboundedReceiver<StreamMessage>,
+    mut sink: WriteHalf<RemoteStream>,
+    mut queue: Receiver<StreamMessage>,
 ) {
+    let mut window = ReceiveWindow::new(client.supports(Capability::FlowControl));
+    let started = Instant::now();
+    let mut responded = false;
+
     loop {
         let result = queue.next().await;
 
         let result = if let Some(message) = result {
             match message {
-                StreamMessage::Data(data) => Some(data),
+                StreamMessage::Data(data) => {
+                    if !responded {
+                        responded = true;
+                        metrics::first_byte(client.kind, started);
+                    }
+                    Some(data)
+                }
+                // tls passthrough and tcp streams are just closed, an http response would land in the middle of their protocol
                 StreamMessage::TunnelRefused => {
                     tracing::debug!(?stream_id, "tunnel refused");
-                    let _ = sink.write_all(HTTP_TUNNEL_REFUSED_RESPONSE).await;
+                    metrics::stream_refused(&message);
+                    if client.kind == TunnelKind::Http {
+                        let _ = sink.write_all(HTTP_TUNNEL_REFUSED_RESPONSE).await;
+                    }
                     None
                 }
                 StreamMessage::NoClientTunnel => {
                     tracing::info!(%subdomain, ?stream_id, "client tunnel not found");
-                    let _ = sink.write_all(HTTP_NOT_FOUND_RESPONSE).await;
+                    metrics::stream_refused(&message);
+                    if client.kind == TunnelKind::Http {
+                        let _ = sink.write_all(HTTP_NOT_FOUND_RESPONSE).await;
+                    }
+                    None
+                }
+                StreamMessage::End => {
+                    tracing::debug!(?stream_id, "client ended stream");
                     None
                 }
             }
@@ -331,7 +406,15 @@ async fn neutun_stream(
             None
         };
 
-        let data = match result {
+        // the key's quota ends the stream
+        let result = match result {
+            Some(data) => quota::consume(&client, data.len())
+                .ok()
+                .map(|wait| (data, wait)),
+            None => None,
+        };
+
+        let (data, wait) = match result {
             Some(data) => data,
             None => {
                 tracing::debug!("done tunneling to sink");
@@ -339,7 +422,9 @@ async fn neutun_stream(
                     error!("error shutting down tcp stream");
                 });
 
-                ACTIVE_STREAMS.remove(&stream_id);
+                if let Some((_, stream)) = ACTIVE_STREAMS.remove(&stream_id) {
+                    stream.window.close();
+                }
                 return;
             }
         };
@@ -348,7 +433,110 @@ async fn neutun_stream(
 
         if let Some(error) = result.err() {
             tracing::warn!(?error, "stream closed, disconnecting");
+            // nothing else removes the stream of a client without flow control
+            if let Some((_, stream)) = ACTIVE_STREAMS.remove(&stream_id) {
+                stream.window.close();
+            }
             return;
         }
+
+        // the key's throughput cap holds back the client's credit, or, without flow control,
+        // the client's reader, which waits for room in this stream's queue
+        tokio::time::sleep(wait).await;
+
+        // let the client know it can send more
+        if let Some(credit) = window.consume(data.len()) {
+            let _ = client
+                .tx
+                .send(ControlPacket::WindowUpdate(stream_id.clone(), credit))
+                .await
+                .map_err(|error| {
+                    tracing::debug!(?error, "failed to send window update");
+                });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sni::tests::client_hello;
+    use tokio::io::{duplex, AsyncWriteExt};
+
+    /// Peek a connection that sends `chunks`, a moment apart, and then waits
+    async fn peek(chunks: Vec<Vec<u8>>) -> Option<Peeked> {
+        let (mut client, server) = duplex(64 * 1024);
+        tokio::spawn(async move {
+            for chunk in chunks {
+                client.write_all(&chunk).await.unwrap();
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+            // hold the connection open like a client waiting for the server's reply
+            tokio::time::sleep(Duration::from_secs(10)).await;
+        });
+        peek_connection(RemoteStream::new(server)).await
+    }
+
+    #[tokio::test]
+    async fn peeks_the_server_name_of_a_split_client_hello() {
+        let hello = client_hello("app.example.com");
+        let (head, tail) = hello.split_at(20);
+        let peeked = match peek(vec![head.to_vec(), tail.to_vec()]).await {
+            Some(Peeked::Tls(peeked)) => peeked,
+            _ => panic!("not peeked as tls"),
+        };
+        assert_eq!(peeked.host.as_deref(), Some("app.example.com"));
+        // whoever reads the stream next still gets the whole hello
+        assert_eq!(peeked.socket.peeked(), &hello[..]);
+    }
+
+    #[tokio::test]
+    async fn hands_on_plain_http_however_it_arrives() {
+        // the host header comes in a later packet, or not at all
+        let chunks = vec![
+            b"GET / HTTP/1.1\r\n".to_vec(),
+            b"Host: app.example.com\r\n\r\n".to_vec(),
+        ];
+        for chunks in [
+            chunks,
+            vec![b"GET / HTTP/1.0\r\n\r\n".to_vec()],
+            vec![b"PRI * HTTP/2.0\r\n".to_vec()],
+        ] {
+            let first = chunks[0].clone();
+            match peek(chunks).await {
+                Some(Peeked::Http(socket)) => assert_eq!(socket.peeked(), &first[..]),
+                _ => panic!("not handed on as http"),
+            }
+        }
+    }
+
+    #[test]
+    fn unmaps_ipv4_peers_of_dual_stack_listeners() {
+        let mapped: SocketAddr = "[::ffff:192.0.2.1]:5000".parse().unwrap();
+        assert_eq!(canonical_addr(mapped), "192.0.2.1:5000".parse().unwrap());
+        let v6: SocketAddr = "[2001:db8::1]:5000".parse().unwrap();
+        assert_eq!(canonical_addr(v6), v6);
+    }
+
+    #[tokio::test]
+    async fn keeps_a_client_hello_without_server_name() {
+        // for our own certificate, no passthrough tunnel could take it
+        let hello = client_hello("127.0.0.1");
+        match peek(vec![hello.clone()]).await {
+            Some(Peeked::Tls(peeked)) => {
+                assert_eq!(peeked.host, None);
+                assert_eq!(peeked.socket.peeked(), &hello[..]);
+            }
+            _ => panic!("not peeked as tls"),
+        }
+    }
+
+    #[tokio::test]
+    async fn drops_a_client_hello_cut_short() {
+        let (mut client, server) = duplex(64 * 1024);
+        let hello = client_hello("app.example.com");
+        client.write_all(&hello[..hello.len() / 2]).await.unwrap();
+        drop(client);
+        assert!(peek_connection(RemoteStream::new(server)).await.is_none());
     }
 }
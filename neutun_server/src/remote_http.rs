@@ -0,0 +1,594 @@
+use super::*;
+use http_body_util::combinators::BoxBody;
+use http_body_util::{BodyExt, Full};
+use hyper::body::{Bytes, Frame, Incoming, SizeHint};
+use edge_policy::Denied;
+use hyper::header::{
+    HeaderMap, HeaderName, HeaderValue, ALLOW, CONTENT_TYPE, FORWARDED, HOST, RETRY_AFTER,
+    WWW_AUTHENTICATE,
+};
+use hyper::service::service_fn;
+use hyper::{Request, Response, StatusCode, Version};
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use hyper_util::server::conn::auto;
+use remote_stream::AsyncStream;
+use std::net::{IpAddr, SocketAddr};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpStream;
+use tracing::error;
+
+type Body = BoxBody<Bytes, hyper::Error>;
+
+/// How many bytes may buffer between a request and the tunnel stream carrying it
+const STREAM_BUFFER_SIZE: usize = 64 * 1024;
+const HEALTH_CHECK_PATH: &str = "/0xDEADBEEF_HEALTH_CHECK";
+
+const X_FORWARDED_FOR: HeaderName = HeaderName::from_static("x-forwarded-for");
+const X_FORWARDED_PROTO: HeaderName = HeaderName::from_static("x-forwarded-proto");
+const X_FORWARDED_HOST: HeaderName = HeaderName::from_static("x-forwarded-host");
+
+/// Where a public connection comes from, for the forwarding headers and stream metadata of its requests
+#[derive(Debug, Clone, Copy)]
+pub struct ConnectionInfo {
+    pub peer_addr: SocketAddr,
+    /// the port of ours it connected to
+    pub public_port: u16,
+    /// we terminated tls for the connection
+    pub https: bool,
+    /// it came straight from one of our trusted proxies, so the forwarding headers on it are theirs.
+    /// A PROXY protocol load balancer or another instance only passes on the visitor's own bytes, headers and all.
+    pub behind_proxy: bool,
+}
+
+impl ConnectionInfo {
+    /// A connection accepted from `socket_peer`, for `peer_addr` if a PROXY protocol header named someone else
+    pub fn new(
+        socket_peer: SocketAddr,
+        peer_addr: SocketAddr,
+        public_port: u16,
+        https: bool,
+        trusted_proxies: &[ipnet::IpNet],
+    ) -> Self {
+        ConnectionInfo {
+            peer_addr,
+            public_port,
+            https,
+            behind_proxy: ip_filter::contains(trusted_proxies, socket_peer.ip()),
+        }
+    }
+}
+
+/// The tunnels a public connection sent requests to, each counts it once against its connection rate limits
+type SeenTunnels = Arc<DashSet<ClientId>>;
+
+/// Serve a public http/1 or http/2 connection, routing every request on it by its own host.
+/// Each request gets a stream of its own, so keep-alive connections can mix hosts.
+pub async fn serve(socket: RemoteStream, info: ConnectionInfo) {
+    let seen = SeenTunnels::default();
+    let service = service_fn(move |request| {
+        let seen = seen.clone();
+        async move { Ok::<_, hyper::Error>(route(request, info, seen).await) }
+    });
+
+    let _ = auto::Builder::new(TokioExecutor::new())
+        .serve_connection_with_upgrades(TokioIo::new(socket), service)
+        .await
+        .map_err(|error| tracing::debug!(?error, "public http connection error"));
+}
+
+#[tracing::instrument(skip(request), fields(path = %request.uri().path()))]
+async fn route(request: Request<Incoming>, info: ConnectionInfo, seen: SeenTunnels) -> Response<Body> {
+    // Handle the health check route
+    if request.uri().path() == HEALTH_CHECK_PATH {
+        return text(StatusCode::OK, "ok");
+    }
+
+    // Answer acme http-01 challenges for certificates we're issuing
+    if let Some(key_authorization) = acme::http_challenge(request.uri().path()) {
+        let mut response = text(StatusCode::OK, key_authorization);
+        response
+            .headers_mut()
+            .insert(CONTENT_TYPE, "text/plain".parse().expect("valid header"));
+        return response;
+    }
+
+    // http/2 requests name their host in the :authority, http/1 ones in a header
+    let host = request
+        .uri()
+        .authority()
+        .map(|authority| authority.to_string())
+        .or_else(|| {
+            let host = request.headers().get(HOST)?.to_str().ok()?;
+            Some(host.to_string())
+        });
+
+    let host = match host {
+        Some(host) => host,
+        None => {
+            tracing::info!("found no host header");
+            return text(StatusCode::BAD_REQUEST, "Error: Invalid Hostname");
+        }
+    };
+
+    // parse the host string and find our client
+    let host_no_port = host.split(":").next().unwrap_or(&host).to_string();
+
+    if CONFIG.allowed_hosts.contains(&host_no_port) {
+        return text(StatusCode::OK, "Hello World!");
+    }
+
+    // a client connecting to our control server through the public port (see `neutun config ctrl-host`)
+    if remote::validate_host_prefix(&host_no_port).is_some_and(|(sub_domain, _)| sub_domain == "wormhole") {
+        return to_control(request, &host, info).await;
+    }
+
+    // hosts that aren't on one of our allowed hosts may still be a client's custom domain
+    let (full_host, domain) = remote::tunnel_host(&host_no_port);
+    let path = request.uri().path().to_string();
+    let client = Connections::find_by_path(&full_host, &path)
+        .filter(|client| client.kind == TunnelKind::Http)
+        .or_else(|| Connections::find_wildcard(&domain?, TunnelKind::Http));
+
+    if let Some(client) = client {
+        return tunnel(client, host, request, info, &seen).await;
+    }
+
+    // other instances serve both protocols, keep the one the request came in with
+    let http2 = request.version() == Version::HTTP_2;
+
+    // check other instances that may be serving this host
+    match network::instance_for_path(&full_host, &path).await {
+        Ok((instance, _)) => {
+            match network::connect(&instance, info.peer_addr, info.https).await {
+                Ok(socket) => exchange(socket, &host, request, http2, None).await,
+                Err(error) => {
+                    error!(?error, "Error connecting to instance");
+                    text(StatusCode::INTERNAL_SERVER_ERROR, "Error: Error proxying tunnel")
+                }
+            }
+        }
+        Err(network::Error::DoesNotServeHost) => {
+            error!(%host, "no tunnel found");
+            text(StatusCode::NOT_FOUND, "Error: Tunnel Not Found")
+        }
+        Err(error) => {
+            error!(%host, ?error, "failed to find instance");
+            text(StatusCode::INTERNAL_SERVER_ERROR, "Error: Error finding tunnel")
+        }
+    }
+}
+
+/// Pass a request on to our control server
+async fn to_control(request: Request<Incoming>, host: &str, info: ConnectionInfo) -> Response<Body> {
+    let mut control = match TcpStream::connect(("localhost", CONFIG.control_port)).await {
+        Ok(control) => control,
+        Err(error) => {
+            tracing::warn!(?error, "failed to connect to local control server");
+            return text(StatusCode::BAD_GATEWAY, "Error: Error connecting to the control server");
+        }
+    };
+
+    // the control server would otherwise see us, on loopback, instead of the visitor its ip filter is for
+    let header = ProxyHeader {
+        addresses: control.peer_addr().ok().map(|control| (info.peer_addr, control)),
+        tls: info.https,
+    };
+    if let Err(error) = control.write_all(&header.to_v2()).await {
+        tracing::warn!(?error, "failed to send proxy header to control server");
+        return text(StatusCode::BAD_GATEWAY, "Error: Error connecting to the control server");
+    }
+
+    let http2 = request.version() == Version::HTTP_2;
+    exchange(control, host, request, http2, None).await
+}
+
+/// Send a request through a new stream to the client
+async fn tunnel(
+    client: ConnectedClient,
+    host: String,
+    mut request: Request<Incoming>,
+    info: ConnectionInfo,
+    seen: &SeenTunnels,
+) -> Response<Body> {
+    // turn the request away before anything of it reaches the client
+    if let Err(denied) = client
+        .policy
+        .check_request(info.peer_addr.ip(), request.method(), request.headers())
+    {
+        tracing::info!(%host, peer_addr = %info.peer_addr, ?denied, "request denied by edge policy");
+        return denied_response(&client.policy, denied);
+    }
+    client.policy.strip_credentials(request.headers_mut());
+
+    let ip = info.peer_addr.ip();
+    let limited = if seen.insert(client.id.clone()) {
+        client
+            .rate_limiter
+            .check_connection(ip)
+            .map_err(|limited| ("connection", limited))
+    } else {
+        Ok(())
+    };
+    let limited = limited.and_then(|_| {
+        client
+            .rate_limiter
+            .check_request(ip)
+            .map_err(|limited| ("request", limited))
+    });
+    if let Err((event, limited)) = limited {
+        tracing::info!(%host, peer_addr = %info.peer_addr, event, ?limited, "request rate limited");
+        metrics::rate_limited(event, limited);
+        return too_many_requests();
+    }
+
+    if client.quota.is_exceeded() {
+        tracing::info!(%host, "transfer quota exceeded");
+        return text(StatusCode::TOO_MANY_REQUESTS, "Error: Tunnel Transfer Quota Exceeded");
+    }
+
+    if client.forwarded_headers {
+        add_forwarded_headers(request.headers_mut(), &host, info);
+    }
+
+    let http2 = client.http2;
+    let (ours, theirs) = tokio::io::duplex(STREAM_BUFFER_SIZE);
+    let metadata = StreamMetadata {
+        peer_addr: Some(remote::canonical_addr(info.peer_addr)),
+        host: host.clone(),
+        tls: info.https,
+        public_port: Some(info.public_port),
+    };
+    let stream_id = remote::stream_to_client(client, RemoteStream::new(theirs), metadata);
+    exchange(ours, &host, request, http2, Some(Arc::new(StreamGuard(stream_id)))).await
+}
+
+/// Ends a request stream once nothing uses it anymore
+struct StreamGuard(StreamId);
+
+impl Drop for StreamGuard {
+    fn drop(&mut self) {
+        if let Some((_, stream)) = ACTIVE_STREAMS.remove(&self.0) {
+            stream.window.close();
+        }
+    }
+}
+
+/// Play a single request over `io` with http/1.1 or http/2, and return its response.
+/// The stream stays open until the response body is through, or for as long as an upgraded connection lasts.
+async fn exchange<S: AsyncStream + 'static>(
+    io: S,
+    host: &str,
+    mut request: Request<Incoming>,
+    http2: bool,
+    guard: Option<Arc<StreamGuard>>,
+) -> Response<Body> {
+    let public_upgrade = hyper::upgrade::on(&mut request);
+    prepare(&mut request, host, http2);
+
+    let result = if http2 {
+        send_http2(io, request).await
+    } else {
+        send_http1(io, request).await
+    };
+
+    let mut response = match result {
+        Ok(response) => response,
+        Err(error) => {
+            tracing::warn!(?error, "request stream closed before a response");
+            return text(StatusCode::BAD_GATEWAY, "Error: Error proxying tunnel");
+        }
+    };
+
+    // websockets and friends take over both connections once the switch is through
+    if response.status() == StatusCode::SWITCHING_PROTOCOLS {
+        let tunnel_upgrade = hyper::upgrade::on(&mut response);
+        let guard = guard.clone();
+        tokio::spawn(async move {
+            match futures::future::try_join(public_upgrade, tunnel_upgrade).await {
+                Ok((public, tunnel)) => {
+                    let _ = tokio::io::copy_bidirectional(
+                        &mut TokioIo::new(public),
+                        &mut TokioIo::new(tunnel),
+                    )
+                    .await;
+                }
+                Err(error) => tracing::debug!(?error, "failed to upgrade request stream"),
+            }
+            drop(guard);
+        });
+    }
+
+    response.map(|body| GuardedBody { body, _guard: guard }.boxed())
+}
+
+async fn send_http1<S: AsyncStream + 'static>(
+    io: S,
+    request: Request<Incoming>,
+) -> hyper::Result<Response<Incoming>> {
+    let (mut sender, conn) = hyper::client::conn::http1::handshake(TokioIo::new(io)).await?;
+    tokio::spawn(async move {
+        let _ = conn
+            .with_upgrades()
+            .await
+            .map_err(|error| tracing::debug!(?error, "request stream error"));
+    });
+
+    sender.send_request(request).await
+}
+
+async fn send_http2<S: AsyncStream + 'static>(
+    io: S,
+    request: Request<Incoming>,
+) -> hyper::Result<Response<Incoming>> {
+    let (mut sender, conn) =
+        hyper::client::conn::http2::handshake(TokioExecutor::new(), TokioIo::new(io)).await?;
+    tokio::spawn(async move {
+        let _ = conn
+            .await
+            .map_err(|error| tracing::debug!(?error, "request stream error"));
+    });
+
+    sender.send_request(request).await
+}
+
+/// A response body that keeps its stream open until the body is dropped.
+/// The http/2 connection future finishes once the request is sent, long before its response does.
+struct GuardedBody {
+    body: Incoming,
+    _guard: Option<Arc<StreamGuard>>,
+}
+
+impl hyper::body::Body for GuardedBody {
+    type Data = Bytes;
+    type Error = hyper::Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Bytes>, hyper::Error>>> {
+        Pin::new(&mut self.body).poll_frame(cx)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.body.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.body.size_hint()
+    }
+}
+
+/// Translate a request between http versions, for the protocol we forward it with.
+/// http/2 names the host in an absolute uri, http/1 in the host header.
+fn prepare(request: &mut Request<Incoming>, host: &str, http2: bool) {
+    let path = request
+        .uri()
+        .path_and_query()
+        .map(|path| path.as_str())
+        .unwrap_or("/")
+        .to_string();
+
+    if http2 {
+        let scheme = request.uri().scheme_str().unwrap_or("http");
+        if let Ok(uri) = format!("{}://{}{}", scheme, host, path).parse() {
+            *request.uri_mut() = uri;
+        }
+        *request.version_mut() = Version::HTTP_2;
+        return;
+    }
+
+    if let Ok(uri) = path.parse() {
+        *request.uri_mut() = uri;
+    }
+    if request.version() == Version::HTTP_2 {
+        *request.version_mut() = Version::HTTP_11;
+    }
+    if !request.headers().contains_key(HOST) {
+        if let Ok(value) = HeaderValue::from_str(host) {
+            request.headers_mut().insert(HOST, value);
+        }
+    }
+}
+
+/// Tell the local service where a request came from.
+/// Behind a trusted hop the client address is appended to what it said in `X-Forwarded-For` and `Forwarded`,
+/// and the proto and host are only set if missing. Otherwise we are the edge, and whatever the visitor sent is replaced.
+fn add_forwarded_headers(headers: &mut HeaderMap, host: &str, info: ConnectionInfo) {
+    let ip = info.peer_addr.ip().to_canonical();
+    let proto = if info.https { "https" } else { "http" };
+
+    if !info.behind_proxy {
+        for name in [X_FORWARDED_FOR, FORWARDED, X_FORWARDED_PROTO, X_FORWARDED_HOST] {
+            headers.remove(name);
+        }
+    }
+
+    append_header(headers, X_FORWARDED_FOR, ip.to_string());
+    append_header(headers, FORWARDED, forwarded_element(ip, host, proto));
+
+    if !headers.contains_key(X_FORWARDED_PROTO) {
+        headers.insert(X_FORWARDED_PROTO, HeaderValue::from_static(proto));
+    }
+    if !headers.contains_key(X_FORWARDED_HOST) {
+        if let Ok(value) = HeaderValue::from_str(host) {
+            headers.insert(X_FORWARDED_HOST, value);
+        }
+    }
+}
+
+/// A `Forwarded` element (RFC 7239) for a request from `ip`
+fn forwarded_element(ip: IpAddr, host: &str, proto: &str) -> String {
+    // ipv6 nodes are bracketed, which only a quoted-string can carry
+    let node = match ip {
+        IpAddr::V4(ip) => ip.to_string(),
+        IpAddr::V6(ip) => format!("[{}]", ip),
+    };
+    format!(
+        "for={};host={};proto={}",
+        forwarded_value(&node),
+        forwarded_value(host),
+        forwarded_value(proto)
+    )
+}
+
+/// A `Forwarded` parameter value: a token if it can be one, a quoted-string otherwise
+fn forwarded_value(value: &str) -> String {
+    let is_tchar = |c: char| c.is_ascii_alphanumeric() || "!#$%&'*+-.^_`|~".contains(c);
+    if !value.is_empty() && value.chars().all(is_tchar) {
+        return value.to_string();
+    }
+
+    let mut quoted = String::with_capacity(value.len() + 2);
+    quoted.push('"');
+    for c in value.chars() {
+        if c == '"' || c == '\\' {
+            quoted.push('\\');
+        }
+        quoted.push(c);
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// Add to a comma separated header, folding any values already there into one
+fn append_header(headers: &mut HeaderMap, name: HeaderName, value: String) {
+    let mut values: Vec<&str> = headers
+        .get_all(&name)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .collect();
+    values.push(&value);
+
+    if let Ok(value) = HeaderValue::from_str(&values.join(", ")) {
+        headers.insert(name, value);
+    }
+}
+
+/// The response for a request the tunnel's edge policy turned away
+fn denied_response(policy: &Policy, denied: Denied) -> Response<Body> {
+    match denied {
+        Denied::Forbidden => text(StatusCode::FORBIDDEN, "Error: Forbidden"),
+        Denied::Unauthorized => {
+            let mut response = text(StatusCode::UNAUTHORIZED, "Error: Unauthorized");
+            for challenge in policy.challenges() {
+                response
+                    .headers_mut()
+                    .append(WWW_AUTHENTICATE, HeaderValue::from_static(challenge));
+            }
+            response
+        }
+        Denied::MethodNotAllowed => {
+            let mut response = text(StatusCode::METHOD_NOT_ALLOWED, "Error: Method Not Allowed");
+            if let Ok(allow) = HeaderValue::from_str(&policy.allowed_methods()) {
+                response.headers_mut().insert(ALLOW, allow);
+            }
+            response
+        }
+    }
+}
+
+/// The response for a request over the tunnel's rate limits
+fn too_many_requests() -> Response<Body> {
+    let mut response = text(StatusCode::TOO_MANY_REQUESTS, "Error: Too Many Requests");
+    response
+        .headers_mut()
+        .insert(RETRY_AFTER, HeaderValue::from_static("1"));
+    response
+}
+
+fn text(status: StatusCode, body: impl Into<Bytes>) -> Response<Body> {
+    let body = Full::new(body.into()).map_err(|never| match never {}).boxed();
+    let mut response = Response::new(body);
+    *response.status_mut() = status;
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn quotes_forwarded_values_only_when_needed() {
+        assert_eq!(forwarded_value("http"), "http");
+        assert_eq!(forwarded_value("app.example.com"), "app.example.com");
+        assert_eq!(forwarded_value("app.example.com:8080"), "\"app.example.com:8080\"");
+        assert_eq!(forwarded_value("[2001:db8::1]"), "\"[2001:db8::1]\"");
+        assert_eq!(forwarded_value(""), "\"\"");
+        assert_eq!(forwarded_value("a\"b\\c d"), "\"a\\\"b\\\\c d\"");
+    }
+
+    #[test]
+    fn builds_forwarded_elements() {
+        assert_eq!(
+            forwarded_element("203.0.113.7".parse().unwrap(), "app.example.com", "https"),
+            "for=203.0.113.7;host=app.example.com;proto=https"
+        );
+        assert_eq!(
+            forwarded_element("2001:db8::1".parse().unwrap(), "app.example.com:8080", "http"),
+            "for=\"[2001:db8::1]\";host=\"app.example.com:8080\";proto=http"
+        );
+    }
+
+    /// Headers a visitor, or a proxy in front of us, already sent
+    fn spoofed() -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.append(X_FORWARDED_FOR, HeaderValue::from_static("10.0.0.1"));
+        headers.append(X_FORWARDED_FOR, HeaderValue::from_static("10.0.0.2"));
+        headers.insert(FORWARDED, HeaderValue::from_static("for=10.0.0.1"));
+        headers.insert(X_FORWARDED_PROTO, HeaderValue::from_static("https"));
+        headers.insert(X_FORWARDED_HOST, HeaderValue::from_static("evil.example.com"));
+        headers
+    }
+
+    fn info(behind_proxy: bool) -> ConnectionInfo {
+        ConnectionInfo {
+            peer_addr: "[::ffff:203.0.113.7]:4000".parse().unwrap(),
+            https: false,
+            public_port: 8080,
+            behind_proxy,
+        }
+    }
+
+    #[test]
+    fn replaces_what_a_visitor_says() {
+        let mut headers = spoofed();
+        add_forwarded_headers(&mut headers, "app.example.com", info(false));
+        assert_eq!(headers[X_FORWARDED_FOR], "203.0.113.7");
+        assert_eq!(headers.get_all(X_FORWARDED_FOR).iter().count(), 1);
+        assert_eq!(headers[FORWARDED], "for=203.0.113.7;host=app.example.com;proto=http");
+        assert_eq!(headers[X_FORWARDED_PROTO], "http");
+        assert_eq!(headers[X_FORWARDED_HOST], "app.example.com");
+    }
+
+    #[test]
+    fn replaces_what_a_visitor_says_through_a_load_balancer() {
+        let balancer: SocketAddr = "10.1.0.5:40000".parse().unwrap();
+        let visitor: SocketAddr = "203.0.113.7:4000".parse().unwrap();
+
+        let info = ConnectionInfo::new(balancer, visitor, 443, true, &[]);
+        let mut headers = spoofed();
+        add_forwarded_headers(&mut headers, "app.example.com", info);
+        assert_eq!(headers[X_FORWARDED_HOST], "app.example.com");
+        assert_eq!(headers[X_FORWARDED_PROTO], "https");
+        assert_eq!(headers[X_FORWARDED_FOR], "203.0.113.7");
+
+        // only a proxy we were told about writes headers of its own
+        let info = ConnectionInfo::new(balancer, visitor, 443, true, &["10.1.0.0/16".parse().unwrap()]);
+        let mut headers = spoofed();
+        add_forwarded_headers(&mut headers, "app.example.com", info);
+        assert_eq!(headers[X_FORWARDED_HOST], "evil.example.com");
+    }
+
+    #[test]
+    fn appends_to_what_a_trusted_hop_says() {
+        let mut headers = spoofed();
+        add_forwarded_headers(&mut headers, "app.example.com", info(true));
+        assert_eq!(headers[X_FORWARDED_FOR], "10.0.0.1, 10.0.0.2, 203.0.113.7");
+        assert_eq!(headers[FORWARDED], "for=10.0.0.1, for=203.0.113.7;host=app.example.com;proto=http");
+        // a proxy in front of us knows better
+        assert_eq!(headers[X_FORWARDED_PROTO], "https");
+        assert_eq!(headers[X_FORWARDED_HOST], "evil.example.com");
+    }
+}
@@ -0,0 +1,220 @@
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use neutun_lib::{ParsedProxyHeader, ProxyHeader};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, ReadBuf};
+
+/// enough of a PROXY protocol header to tell it from anything else
+const V2_SIGNATURE_LEN: usize = 12;
+/// how much we read at a time looking for the end of a PROXY protocol header
+const PROXY_HEADER_CHUNK: usize = 256;
+/// how long a load balancer or another instance gets to send its PROXY protocol header
+const PROXY_HEADER_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Any byte stream we can tunnel
+pub trait AsyncStream: AsyncRead + AsyncWrite + Unpin + Send {}
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> AsyncStream for T {}
+
+/// A public connection: either a plain tcp socket or a tls session we've terminated.
+/// Bytes can be peeked to route the connection before handing it off,
+/// and are replayed to whoever reads the stream afterwards.
+pub struct RemoteStream {
+    inner: Box<dyn AsyncStream>,
+    peeked: Vec<u8>,
+}
+
+impl RemoteStream {
+    pub fn new<S: AsyncStream + 'static>(stream: S) -> Self {
+        RemoteStream {
+            inner: Box::new(stream),
+            peeked: Vec::new(),
+        }
+    }
+
+    /// The bytes peeked so far
+    pub fn peeked(&self) -> &[u8] {
+        &self.peeked
+    }
+
+    /// Read up to `max` more bytes without consuming them.
+    /// Returns everything peeked so far, which only stops growing at the end of the stream.
+    pub async fn peek_more(&mut self, max: usize) -> io::Result<&[u8]> {
+        let mut buf = vec![0; max];
+        let n = self.inner.read(&mut buf).await?;
+        self.peeked.extend_from_slice(&buf[..n]);
+        Ok(&self.peeked)
+    }
+
+    /// The client a load balancer accepted this connection from, read from its PROXY protocol header.
+    /// Falls back on `peer_addr` when the load balancer doesn't know, i.e. for its own health checks.
+    pub async fn proxied_peer_addr(&mut self, peer_addr: SocketAddr) -> Option<SocketAddr> {
+        self.proxied_peer(peer_addr).await.map(|(peer_addr, _)| peer_addr)
+    }
+
+    /// Like `proxied_peer_addr`, along with whether the client connected over tls
+    pub async fn proxied_peer(&mut self, peer_addr: SocketAddr) -> Option<(SocketAddr, bool)> {
+        match self.read_proxy_header().await {
+            Ok(header) => Some((header.source().unwrap_or(peer_addr), header.tls)),
+            Err(error) => {
+                tracing::warn!(%peer_addr, %error, "failed to read proxy protocol header");
+                None
+            }
+        }
+    }
+
+    /// Whether the stream starts with a PROXY protocol header, as far as the first bytes tell.
+    /// Waits for enough of them to decide, a lone `P` could still be anything.
+    pub async fn starts_with_proxy_header(&mut self) -> io::Result<bool> {
+        match tokio::time::timeout(PROXY_HEADER_TIMEOUT, self.peek_proxy_signature()).await {
+            Ok(result) => result,
+            Err(_) => Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "timed out waiting for proxy protocol header",
+            )),
+        }
+    }
+
+    async fn peek_proxy_signature(&mut self) -> io::Result<bool> {
+        // every header is at least as long as the v2 signature, and starts with it or the v1 prefix
+        while self.peeked.len() < V2_SIGNATURE_LEN && ProxyHeader::parse(&self.peeked).is_ok() {
+            let peeked = self.peeked.len();
+            if self.peek_more(V2_SIGNATURE_LEN - peeked).await?.len() == peeked {
+                return Ok(false);
+            }
+        }
+        Ok(ProxyHeader::parse(&self.peeked).is_ok())
+    }
+
+    /// Read the PROXY protocol header a load balancer sends ahead of the connection.
+    /// Whatever we read past the header stays peeked for whoever reads the stream next.
+    pub async fn read_proxy_header(&mut self) -> io::Result<ProxyHeader> {
+        match tokio::time::timeout(PROXY_HEADER_TIMEOUT, self.peek_proxy_header()).await {
+            Ok(result) => result,
+            Err(_) => Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "timed out waiting for proxy protocol header",
+            )),
+        }
+    }
+
+    async fn peek_proxy_header(&mut self) -> io::Result<ProxyHeader> {
+        loop {
+            match ProxyHeader::parse(&self.peeked) {
+                Ok(ParsedProxyHeader::Complete(header, len)) => {
+                    self.peeked.drain(..len);
+                    return Ok(header);
+                }
+                Ok(ParsedProxyHeader::Incomplete(needed)) => {
+                    let peeked = self.peeked.len();
+                    if self.peek_more(needed.max(PROXY_HEADER_CHUNK)).await?.len() == peeked {
+                        return Err(io::ErrorKind::UnexpectedEof.into());
+                    }
+                }
+                Err(error) => return Err(io::Error::new(io::ErrorKind::InvalidData, error)),
+            }
+        }
+    }
+}
+
+impl AsyncRead for RemoteStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        if self.peeked.is_empty() {
+            return Pin::new(&mut self.inner).poll_read(cx, buf);
+        }
+
+        let n = self.peeked.len().min(buf.remaining());
+        buf.put_slice(&self.peeked[..n]);
+        self.peeked.drain(..n);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for RemoteStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{duplex, AsyncWriteExt};
+
+    #[tokio::test]
+    async fn reads_a_split_header_and_keeps_what_follows() {
+        let (mut client, server) = duplex(1024);
+        let mut socket = RemoteStream::new(server);
+        tokio::spawn(async move {
+            client.write_all(b"PROXY TCP4 192.0.2.1 ").await.unwrap();
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            client.write_all(b"192.0.2.2 5000 443\r\nGET / HTTP/1.1\r\n").await.unwrap();
+            tokio::time::sleep(Duration::from_secs(10)).await;
+        });
+
+        let header = socket.read_proxy_header().await.unwrap();
+        assert_eq!(header.source(), Some("192.0.2.1:5000".parse().unwrap()));
+
+        let mut rest = [0; 16];
+        socket.read_exact(&mut rest).await.unwrap();
+        assert_eq!(&rest, b"GET / HTTP/1.1\r\n");
+    }
+
+    #[tokio::test]
+    async fn waits_for_enough_bytes_to_tell_a_header() {
+        let (mut client, server) = duplex(1024);
+        let mut socket = RemoteStream::new(server);
+        tokio::spawn(async move {
+            client.write_all(b"P").await.unwrap();
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            client.write_all(b"OST / HTTP/1.1\r\n").await.unwrap();
+            tokio::time::sleep(Duration::from_secs(10)).await;
+        });
+        assert!(!socket.starts_with_proxy_header().await.unwrap());
+        assert!(socket.peeked().starts_with(b"POST / HTTP"));
+
+        let (mut client, server) = duplex(1024);
+        client.write_all(b"PROXY UNKNOWN\r\n").await.unwrap();
+        assert!(RemoteStream::new(server).starts_with_proxy_header().await.unwrap());
+
+        // a connection that ends before it could be one isn't one
+        let (mut client, server) = duplex(1024);
+        client.write_all(b"PRO").await.unwrap();
+        drop(client);
+        assert!(!RemoteStream::new(server).starts_with_proxy_header().await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn fails_on_a_header_cut_short() {
+        let (mut client, server) = duplex(1024);
+        client.write_all(b"PROXY TCP4 192.0.2.1").await.unwrap();
+        drop(client);
+        let error = RemoteStream::new(server).read_proxy_header().await.unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[tokio::test]
+    async fn fails_on_anything_else() {
+        let (mut client, server) = duplex(1024);
+        client.write_all(b"GET / HTTP/1.1\r\n").await.unwrap();
+        let error = RemoteStream::new(server).read_proxy_header().await.unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+}
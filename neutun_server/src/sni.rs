@@ -0,0 +1,185 @@
+/// TLS record type of handshake messages
+const HANDSHAKE_RECORD: u8 = 0x16;
+/// handshake message type of a ClientHello
+const CLIENT_HELLO: u8 = 0x01;
+/// extension type of the server name indication
+const SERVER_NAME_EXTENSION: u16 = 0x0000;
+/// server name type of a dns host name
+const HOST_NAME: u8 = 0x00;
+
+/// Does this look like the start of a TLS handshake?
+/// Plain HTTP always starts with an ASCII method instead.
+pub fn is_tls(buf: &[u8]) -> bool {
+    buf.len() >= 2 && buf[0] == HANDSHAKE_RECORD && buf[1] == 0x03
+}
+
+/// How many bytes we need to have the entire first record
+pub fn first_record_len(buf: &[u8]) -> Option<usize> {
+    if buf.len() < 5 {
+        return None;
+    }
+    Some(5 + u16::from_be_bytes([buf[3], buf[4]]) as usize)
+}
+
+/// Find the SNI host name in the ClientHello of the first TLS record
+pub fn server_name(buf: &[u8]) -> Option<String> {
+    let mut reader = Reader(buf);
+
+    // record header: type, version, length
+    if reader.u8()? != HANDSHAKE_RECORD {
+        return None;
+    }
+    reader.skip(2)?;
+    let len = reader.u16()? as usize;
+    let mut record = Reader(reader.bytes(len)?);
+
+    // handshake header: type, length
+    if record.u8()? != CLIENT_HELLO {
+        return None;
+    }
+    let len = record.u24()?;
+    let mut hello = Reader(record.bytes(len)?);
+
+    // version, random, session id, cipher suites, compression methods
+    hello.skip(2 + 32)?;
+    let len = hello.u8()? as usize;
+    hello.skip(len)?;
+    let len = hello.u16()? as usize;
+    hello.skip(len)?;
+    let len = hello.u8()? as usize;
+    hello.skip(len)?;
+
+    let len = hello.u16()? as usize;
+    let mut extensions = Reader(hello.bytes(len)?);
+    while !extensions.0.is_empty() {
+        let typ = extensions.u16()?;
+        let len = extensions.u16()? as usize;
+        let mut extension = Reader(extensions.bytes(len)?);
+        if typ != SERVER_NAME_EXTENSION {
+            continue;
+        }
+
+        let len = extension.u16()? as usize;
+        let mut names = Reader(extension.bytes(len)?);
+        while !names.0.is_empty() {
+            let typ = names.u8()?;
+            let len = names.u16()? as usize;
+            let name = names.bytes(len)?;
+            if typ == HOST_NAME {
+                return std::str::from_utf8(name).ok().map(|s| s.to_lowercase());
+            }
+        }
+    }
+
+    None
+}
+
+/// Reads big endian values off the front of a buffer
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, n: usize) -> Option<&'a [u8]> {
+        if self.0.len() < n {
+            return None;
+        }
+        let (head, tail) = self.0.split_at(n);
+        self.0 = tail;
+        Some(head)
+    }
+
+    fn skip(&mut self, n: usize) -> Option<()> {
+        self.bytes(n).map(|_| ())
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        self.bytes(1).map(|b| b[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        self.bytes(2).map(|b| u16::from_be_bytes([b[0], b[1]]))
+    }
+
+    fn u24(&mut self) -> Option<usize> {
+        self.bytes(3)
+            .map(|b| u32::from_be_bytes([0, b[0], b[1], b[2]]) as usize)
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use std::convert::TryFrom;
+    use std::sync::Arc;
+    use tokio_rustls::rustls::crypto::ring::default_provider;
+    use tokio_rustls::rustls::pki_types::ServerName;
+    use tokio_rustls::rustls::{ClientConfig, ClientConnection, RootCertStore};
+
+    /// The first flight of a real tls client connecting to `host`
+    pub fn client_hello(host: &str) -> Vec<u8> {
+        let config = ClientConfig::builder_with_provider(Arc::new(default_provider()))
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_root_certificates(RootCertStore::empty())
+            .with_no_client_auth();
+        let name = ServerName::try_from(host.to_string()).unwrap();
+        let mut connection = ClientConnection::new(Arc::new(config), name).unwrap();
+        let mut buf = vec![];
+        connection.write_tls(&mut buf).unwrap();
+        buf
+    }
+
+    #[test]
+    fn finds_the_server_name() {
+        let hello = client_hello("App.Example.com");
+        assert!(is_tls(&hello));
+        assert_eq!(first_record_len(&hello), Some(hello.len()));
+        assert_eq!(server_name(&hello).as_deref(), Some("app.example.com"));
+    }
+
+    #[test]
+    fn plain_http_is_not_tls() {
+        let request = b"GET / HTTP/1.1\r\nHost: app.example.com\r\n\r\n";
+        assert!(!is_tls(request));
+        assert_eq!(server_name(request), None);
+    }
+
+    #[test]
+    fn no_server_name_for_an_ip() {
+        assert_eq!(server_name(&client_hello("127.0.0.1")), None);
+    }
+
+    #[test]
+    fn truncated_hello() {
+        let hello = client_hello("app.example.com");
+        assert_eq!(first_record_len(&hello[..4]), None);
+        for len in 0..hello.len() {
+            assert_eq!(server_name(&hello[..len]), None, "{} bytes", len);
+        }
+    }
+
+    #[test]
+    fn malformed_hello() {
+        let hello = client_hello("app.example.com");
+
+        // not a handshake record
+        let mut alert = hello.clone();
+        alert[0] = 0x15;
+        assert_eq!(server_name(&alert), None);
+
+        // a record longer than what arrived
+        let mut long = hello.clone();
+        long[3..5].copy_from_slice(&u16::MAX.to_be_bytes());
+        assert_eq!(server_name(&long), None);
+
+        // not a client hello
+        let mut server_hello = hello.clone();
+        server_hello[5] = 0x02;
+        assert_eq!(server_name(&server_hello), None);
+
+        // garbage never panics
+        for seed in 0..64u8 {
+            let garbage: Vec<u8> = (0..hello.len()).map(|i| (i as u8).wrapping_mul(31) ^ seed).collect();
+            let _ = server_name(&[&hello[..9], &garbage[9..]].concat());
+        }
+    }
+}
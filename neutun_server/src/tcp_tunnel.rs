@@ -0,0 +1,190 @@
+use super::*;
+use rand::RngExt;
+use std::ops::RangeInclusive;
+use tokio::task::JoinHandle;
+
+/// How long an accept loop waits after a failed accept (i.e. out of file descriptors) before it tries again
+pub const ACCEPT_ERROR_BACKOFF: std::time::Duration = std::time::Duration::from_millis(100);
+
+lazy_static! {
+    /// the accept loops of tcp tunnels and their ports, by the client that owns them
+    static ref LISTENERS: DashMap<ClientId, (u16, JoinHandle<()>)> = DashMap::new();
+    /// released accept loops that may not have dropped their listener yet, by port
+    static ref CLOSING: DashMap<u16, JoinHandle<()>> = DashMap::new();
+}
+
+/// Bind a public listener for a new tcp tunnel.
+/// We try the requested port first, then a random free one from `TCP_PORTS`.
+pub async fn bind(requested_port: Option<u16>) -> Option<TcpListener> {
+    bind_in(CONFIG.tcp_ports.clone()?, requested_port).await
+}
+
+async fn bind_in(ports: RangeInclusive<u16>, requested_port: Option<u16>) -> Option<TcpListener> {
+    if let Some(port) = requested_port.filter(|p| ports.contains(p)) {
+        // a reconnecting client gets its port back once its old listener is gone
+        closed(port).await;
+        if let Ok(listener) = TcpListener::bind(format!("[::]:{}", port)).await {
+            return Some(listener);
+        }
+        tracing::debug!(port, "requested tcp port is taken");
+    }
+
+    let count = (*ports.end() - *ports.start()) as u32 + 1;
+    let offset = rand::rng().random_range(0..count);
+    for i in 0..count {
+        let port = *ports.start() + ((offset + i) % count) as u16;
+        if let Ok(listener) = TcpListener::bind(format!("[::]:{}", port)).await {
+            return Some(listener);
+        }
+    }
+
+    tracing::warn!("no free tcp tunnel ports left");
+    None
+}
+
+/// Accept connections on a tcp tunnel's public port for as long as the client is connected
+pub fn spawn(client: ConnectedClient, listener: TcpListener) {
+    let client_id = client.id.clone();
+    let port = listener.local_addr().map(|addr| addr.port()).unwrap_or_default();
+
+    let accept_loop = tokio::spawn(
+        async move {
+            loop {
+                let (socket, peer_addr) = match listener.accept().await {
+                    Ok(accepted) => accepted,
+                    Err(error) => {
+                        error!(?error, "failed to accept tcp tunnel socket");
+                        tokio::time::sleep(ACCEPT_ERROR_BACKOFF).await;
+                        continue;
+                    }
+                };
+
+                if Connections::get(&client.id).is_none() {
+                    tracing::debug!(client_id = %client.id, "client gone, closing tcp tunnel");
+                    return;
+                }
+
+                if !IP_FILTER.allows(peer_addr.ip()) {
+                    tracing::debug!(%peer_addr, "remote ip is not allowed, dropping connection");
+                    continue;
+                }
+
+                if !client.policy.allows_ip(peer_addr.ip()) {
+                    tracing::info!(%peer_addr, host = %client.full_host(), "tcp tunnel connection denied by edge policy");
+                    continue;
+                }
+
+                if let Err(limited) = client.rate_limiter.check_connection(peer_addr.ip()) {
+                    tracing::info!(%peer_addr, host = %client.full_host(), ?limited, "tcp tunnel connection rate limited");
+                    metrics::rate_limited("connection", limited);
+                    continue;
+                }
+
+                if client.quota.is_exceeded() {
+                    tracing::info!(%peer_addr, host = %client.full_host(), "transfer quota exceeded, dropping connection");
+                    continue;
+                }
+
+                tracing::info!(%peer_addr, host = %client.full_host(), "new tcp tunnel connection");
+                let metadata = StreamMetadata {
+                    peer_addr: Some(remote::canonical_addr(peer_addr)),
+                    host: client.full_host(),
+                    tls: false,
+                    public_port: socket.local_addr().ok().map(|addr| addr.port()),
+                };
+                remote::stream_to_client(client.clone(), RemoteStream::new(socket), metadata);
+            }
+        }
+        .instrument(observability::remote_trace("tcp_tunnel")),
+    );
+
+    LISTENERS.insert(client_id.clone(), (port, accept_loop));
+
+    // the client may have left before we got here
+    if Connections::get(&client_id).is_none() {
+        release(&client_id);
+    }
+}
+
+/// Stop accepting connections for this client and free its port.
+/// The port is only free once the aborted accept loop has dropped its listener, see `closed`.
+pub fn release(client_id: &ClientId) {
+    if let Some((_, (port, accept_loop))) = LISTENERS.remove(client_id) {
+        tracing::debug!(%client_id, port, "releasing tcp tunnel port");
+        accept_loop.abort();
+        CLOSING.insert(port, accept_loop);
+    }
+}
+
+/// Wait until a listener released on `port` is closed
+async fn closed(port: u16) {
+    if let Some((_, accept_loop)) = CLOSING.remove(&port) {
+        let _ = accept_loop.await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpStream;
+
+    /// A tcp tunnel client we know about, as if it just connected
+    fn connected() -> (ConnectedClient, Receiver<ControlPacket>) {
+        let (client, rx) = ConnectedClient::for_tests(&format!("tcp-{}", ClientId::generate()));
+        let client = ConnectedClient {
+            kind: TunnelKind::Tcp,
+            ..client
+        };
+        Connections::add(client.clone()).unwrap();
+        (client, rx)
+    }
+
+    fn port(listener: &TcpListener) -> u16 {
+        listener.local_addr().unwrap().port()
+    }
+
+    #[tokio::test]
+    async fn binds_the_requested_port_or_another_free_one() {
+        let ports = 42100..=42104;
+        let first = bind_in(ports.clone(), Some(42102)).await.unwrap();
+        assert_eq!(port(&first), 42102);
+
+        // taken, or not ours to give out
+        for requested in [Some(42102), Some(80), None] {
+            let other = bind_in(ports.clone(), requested).await.unwrap();
+            assert!(ports.contains(&port(&other)) && port(&other) != 42102);
+        }
+
+        let all: Vec<TcpListener> = futures::future::join_all((0..4).map(|_| bind_in(ports.clone(), None)))
+            .await
+            .into_iter()
+            .flatten()
+            .collect();
+        assert_eq!(all.len(), 4);
+        assert!(bind_in(ports, None).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn frees_the_port_once_the_client_is_gone() {
+        let (client, _rx) = connected();
+        let listener = bind_in(42110..=42114, Some(42111)).await.unwrap();
+        spawn(client.clone(), listener);
+        assert!(TcpStream::connect("127.0.0.1:42111").await.is_ok());
+
+        Connections::remove(&client);
+        closed(42111).await;
+        assert!(TcpStream::connect("127.0.0.1:42111").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn a_reconnecting_client_gets_its_port_back() {
+        let (client, _rx) = connected();
+        let listener = bind_in(42120..=42124, Some(42122)).await.unwrap();
+        spawn(client.clone(), listener);
+
+        // the new connection asks for the port before the old listener has had a chance to close
+        Connections::remove(&client);
+        let listener = bind_in(42120..=42124, Some(42122)).await.unwrap();
+        assert_eq!(port(&listener), 42122);
+    }
+}
@@ -0,0 +1,195 @@
+use crate::remote_stream::RemoteStream;
+use crate::{sni, CONFIG};
+use dashmap::DashMap;
+use lazy_static::lazy_static;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+use thiserror::Error;
+use tokio_rustls::rustls::crypto::ring::{default_provider, sign::any_supported_type};
+use tokio_rustls::rustls::pki_types::pem::PemObject;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use tokio_rustls::rustls::server::{ClientHello, ResolvesServerCert};
+use tokio_rustls::rustls::sign::CertifiedKey;
+use tokio_rustls::rustls::ServerConfig;
+use tokio_rustls::TlsAcceptor;
+
+/// enough bytes to tell a tls handshake apart from plaintext
+const MIN_PEEK: usize = 5;
+/// how long a client gets to start and then finish its tls handshake
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+/// how often we check the certificate files for changes
+const RELOAD_INTERVAL: Duration = Duration::from_secs(10);
+
+lazy_static! {
+    static ref CERTIFICATE: Arc<CertResolver> = Arc::new(CertResolver::default());
+    static ref ACCEPTOR: Option<TlsAcceptor> = (CONFIG.tls.is_some() || CONFIG.acme.is_some()).then(|| {
+        let mut config = ServerConfig::builder_with_provider(Arc::new(default_provider()))
+            .with_safe_default_protocol_versions()
+            .expect("tls protocol versions")
+            .with_no_client_auth()
+            .with_cert_resolver(CERTIFICATE.clone());
+        config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+        TlsAcceptor::from(Arc::new(config))
+    });
+}
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("IOError reading {0:?}: {1}")]
+    Io(PathBuf, std::io::Error),
+
+    #[error("Invalid PEM in {0:?}: {1}")]
+    Pem(PathBuf, tokio_rustls::rustls::pki_types::pem::Error),
+
+    #[error("No certificates in {0:?}")]
+    NoCertificates(PathBuf),
+
+    #[error("Invalid private key: {0}")]
+    Key(#[from] tokio_rustls::rustls::Error),
+}
+
+/// The certificate and key files we serve TLS with
+pub struct TlsFiles {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+}
+
+/// Picks the certificate for the sni host of each handshake
+#[derive(Debug, Default)]
+struct CertResolver {
+    /// the certificate from `TLS_CERT_FILE`, served when nothing more specific matches
+    default: RwLock<Option<Arc<CertifiedKey>>>,
+    /// certificates we issued ourselves, by each name they cover (i.e. `*.foo.bar`)
+    by_name: DashMap<String, Arc<CertifiedKey>>,
+}
+
+impl ResolvesServerCert for CertResolver {
+    fn resolve(&self, client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        if let Some(host) = client_hello.server_name() {
+            let host = host.to_lowercase();
+            if let Some(certified_key) = self.by_name.get(&host) {
+                return Some(certified_key.clone());
+            }
+
+            if let Some((_, parent)) = host.split_once('.') {
+                if let Some(certified_key) = self.by_name.get(&format!("*.{}", parent)) {
+                    return Some(certified_key.clone());
+                }
+            }
+        }
+
+        self.default.read().ok()?.clone()
+    }
+}
+
+/// Serve a certificate for these names from now on
+pub fn add_certificate(names: &[String], certified_key: CertifiedKey) {
+    let certified_key = Arc::new(certified_key);
+    for name in names {
+        CERTIFICATE.by_name.insert(name.to_lowercase(), certified_key.clone());
+    }
+}
+
+/// The acceptor for terminating TLS, if the server is configured with certificates
+pub fn acceptor() -> Option<&'static TlsAcceptor> {
+    ACCEPTOR.as_ref()
+}
+
+/// Terminate tls if the connection speaks it and we have a certificate,
+/// otherwise hand back the plaintext connection
+pub async fn accept(mut socket: RemoteStream) -> Option<RemoteStream> {
+    let acceptor = match acceptor() {
+        Some(acceptor) => acceptor,
+        None => return Some(socket),
+    };
+
+    match tokio::time::timeout(HANDSHAKE_TIMEOUT, socket.peek_more(MIN_PEEK)).await {
+        Ok(Ok(buf)) if sni::is_tls(buf) => {}
+        Ok(Ok(_)) => return Some(socket),
+        Ok(Err(error)) => {
+            tracing::debug!(?error, "failed to peek connection");
+            return None;
+        }
+        Err(_) => {
+            tracing::debug!("timed out waiting for the client to speak");
+            return None;
+        }
+    }
+
+    handshake(acceptor, socket).await
+}
+
+/// Terminate tls on a connection we know speaks it, giving up on clients too slow to finish the handshake
+pub async fn handshake(acceptor: &TlsAcceptor, socket: RemoteStream) -> Option<RemoteStream> {
+    match tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(socket)).await {
+        Ok(Ok(stream)) => Some(RemoteStream::new(stream)),
+        Ok(Err(error)) => {
+            tracing::debug!(?error, "tls handshake failed");
+            None
+        }
+        Err(_) => {
+            tracing::debug!("tls handshake timed out");
+            None
+        }
+    }
+}
+
+/// Load our certificate and keep reloading it whenever the files change
+pub fn spawn_reload(files: &'static TlsFiles) {
+    let mut modified = modified_at(files);
+    if let Err(error) = reload(files) {
+        panic!("failed to load tls certificate: {}", error);
+    }
+    tracing::info!(cert = ?files.cert, "loaded tls certificate");
+
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(RELOAD_INTERVAL).await;
+
+            let now_modified = modified_at(files);
+            if now_modified == modified {
+                continue;
+            }
+            modified = now_modified;
+
+            // keep serving the old certificate until the new one is valid
+            match reload(files) {
+                Ok(_) => tracing::info!(cert = ?files.cert, "reloaded tls certificate"),
+                Err(error) => tracing::error!(%error, "failed to reload tls certificate"),
+            }
+        }
+    });
+}
+
+fn reload(files: &TlsFiles) -> Result<(), Error> {
+    let certified_key = load(&files.cert, &files.key)?;
+    if let Ok(mut current) = CERTIFICATE.default.write() {
+        current.replace(Arc::new(certified_key));
+    }
+    Ok(())
+}
+
+pub fn load(cert: &Path, key: &Path) -> Result<CertifiedKey, Error> {
+    let cert_pem = std::fs::read(cert).map_err(|e| Error::Io(cert.into(), e))?;
+    let key_pem = std::fs::read(key).map_err(|e| Error::Io(key.into(), e))?;
+
+    let chain = CertificateDer::pem_slice_iter(&cert_pem)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| Error::Pem(cert.into(), e))?;
+    if chain.is_empty() {
+        return Err(Error::NoCertificates(cert.into()));
+    }
+
+    let key = PrivateKeyDer::from_pem_slice(&key_pem).map_err(|e| Error::Pem(key.into(), e))?;
+
+    // the files may be caught halfway through being replaced
+    let certified_key = CertifiedKey::new(chain, any_supported_type(&key)?);
+    certified_key.keys_match()?;
+    Ok(certified_key)
+}
+
+fn modified_at(files: &TlsFiles) -> (Option<SystemTime>, Option<SystemTime>) {
+    let modified = |path: &Path| std::fs::metadata(path).and_then(|m| m.modified()).ok();
+    (modified(&files.cert), modified(&files.key))
+}